@@ -2,5 +2,7 @@ set -e
 
 cargo clippy --manifest-path uefi-bootloader/Cargo.toml --target x86_64-unknown-uefi
 cargo clippy --manifest-path uefi-bootloader/Cargo.toml --target aarch64-unknown-uefi
+# There is no built-in riscv64 UEFI target, so core is built for a custom one.
+cargo clippy --manifest-path uefi-bootloader/Cargo.toml --target uefi-bootloader/riscv64-unknown-uefi.json -Zjson-target-spec -Zbuild-std=core,compiler_builtins -Zbuild-std-features=compiler-builtins-mem
 # unsupported
 cargo clippy --manifest-path uefi-bootloader/Cargo.toml --target i686-unknown-uefi
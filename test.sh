@@ -0,0 +1,5 @@
+set -e
+
+# UEFI targets can't run tests, so the bootloader's unit tests are built for the
+# host instead.
+cargo test --manifest-path uefi-bootloader/Cargo.toml --target x86_64-unknown-linux-gnu
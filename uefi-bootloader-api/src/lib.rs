@@ -5,15 +5,212 @@
 
 use core::{ops, slice, str};
 
+/// The UEFI memory type of the memory backing the kernel's segments.
+///
+/// A kernel that reads the UEFI memory map can locate all memory occupied by
+/// its image by this type alone. The value lies in the range reserved for OS
+/// loaders (`0x8000_0000..=0xffff_ffff`).
+pub const KERNEL_IMAGE_MEMORY_TYPE: u32 = 0x8000_0001;
+
+/// The UEFI memory type of the memory backing
+/// [`kernel_config`][BootInformation::kernel_config].
+pub const KERNEL_CONFIG_MEMORY_TYPE: u32 = 0x8000_0002;
+
+/// The UEFI memory type of the memory backing
+/// [`boot_logo`][BootInformation::boot_logo].
+pub const BOOT_LOGO_MEMORY_TYPE: u32 = 0x8000_0003;
+
+/// The UEFI memory type of the page table frames allocated before exiting boot
+/// services.
+///
+/// These regions are reported as [`MemoryRegionKind::Bootloader`], like the
+/// page table frames allocated afterwards, as they back the page table the
+/// kernel is entered with.
+pub const PAGE_TABLE_MEMORY_TYPE: u32 = 0x8000_0004;
+
+/// The word the lowest page of the stack is filled with if the bootloader was
+/// configured to, so that the kernel can detect stack overflows by checking
+/// whether the pattern was overwritten.
+pub const STACK_GUARD_PATTERN: u64 = 0x5354_4b5f_4755_4152;
+
+/// Information passed to the kernel by the bootloader.
+///
+/// The boot information and all slices it references are located in memory
+/// allocated after exiting boot services, reported as
+/// [`Bootloader`][MemoryRegionKind::Bootloader] memory, and mapped in the
+/// kernel's page table. Other addresses (e.g. [`FrameBuffer::start`] and
+/// [`rsdp_address`][Self::rsdp_address]) are physical addresses that aren't
+/// necessarily mapped.
 #[derive(Debug)]
 #[repr(C)]
 pub struct BootInformation {
     pub size: usize,
     pub frame_buffer: Option<FrameBuffer>,
+    /// The address of the preferred RSDP, i.e. the ACPI 2.0 RSDP if present,
+    /// falling back to the ACPI 1.0 RSDP.
     pub rsdp_address: Option<usize>,
+    /// The addresses of all RSDPs reported by the firmware.
+    pub rsdp_addresses: RsdpAddresses,
+    /// The address of the flattened device tree, if reported by the firmware.
+    pub device_tree_address: Option<usize>,
+    /// Which hardware descriptions the bootloader was configured to pass.
+    ///
+    /// The RSDP fields are `None` if only the device tree is passed, and
+    /// [`device_tree_address`][Self::device_tree_address] is `None` if only
+    /// ACPI is passed. [`configuration_tables`][Self::configuration_tables]
+    /// always holds all tables.
+    pub hardware_description: HardwareDescription,
+    /// The number of enabled processors, if reported by the firmware.
+    pub cpu_count: Option<usize>,
+    pub firmware: Firmware,
+    /// The number of levels in the kernel's page tables, e.g. 4 on x86_64.
+    pub page_table_levels: u8,
+    /// The size of the pages in the kernel's page tables, in bytes.
+    pub page_size: usize,
+    /// The bitwise OR of the sizes of the large pages used in the kernel's
+    /// page tables, in bytes.
+    ///
+    /// The bootloader only maps pages of [`page_size`][Self::page_size], so
+    /// this is currently always zero.
+    pub large_page_sizes: usize,
+    /// The physical memory regions, sorted by start address.
     pub memory_regions: MemoryRegions,
+    /// The address after the highest byte of [`Usable`][MemoryRegionKind::Usable]
+    /// memory in [`memory_regions`][Self::memory_regions], or zero if there is
+    /// none.
+    pub max_usable_physical_address: usize,
+    /// The address after the highest byte of any region in
+    /// [`memory_regions`][Self::memory_regions], including reserved and MMIO
+    /// regions.
+    pub max_physical_address: usize,
     pub modules: Modules,
+    /// The physical address module offsets are relative to, i.e. the start of
+    /// the memory of type `0x8000_0000`.
+    ///
+    /// It is aligned to the largest module alignment.
+    pub modules_address: usize,
     pub elf_sections: ElfSections,
+    /// Where the bootloader placed each loaded segment of the kernel.
+    pub kernel_segments: KernelSegments,
+    /// The CRC-32 (IEEE 802.3) of the kernel file, if enabled by the
+    /// `kernel_crc32` config key.
+    pub kernel_crc32: Option<u32>,
+    /// The virtual address of the kernel's entry point, as in its ELF header.
+    pub entry_point: usize,
+    /// The physical address of the kernel's entry point, if it lies in a
+    /// loaded segment.
+    ///
+    /// If the `identity_map_kernel` config key is set, the kernel's segments
+    /// are also identity-mapped and the kernel is entered at this address, so
+    /// that it can switch to its virtual addresses itself.
+    pub physical_entry_point: Option<usize>,
+    /// The bootloader's log messages encoded as UTF-8, one per line.
+    ///
+    /// The oldest messages may have been discarded.
+    pub boot_log: Bytes,
+    /// The contents of `\boot\kernel.map` on the boot volume, if present.
+    ///
+    /// The bootloader doesn't parse the file; its format is up to the kernel.
+    pub symbol_map: Option<Bytes>,
+    /// The kernel command line given in the bootloader's config, if any.
+    ///
+    /// The bytes are passed exactly as configured, and aren't null-terminated.
+    pub command_line: Option<Bytes>,
+    /// The contents of `\boot\kernel.cfg` on the boot volume, if present.
+    ///
+    /// Unlike the rest of the boot info, the config is mapped read-only at its
+    /// own virtual address. The bootloader doesn't parse the file; its format is
+    /// up to the kernel.
+    pub kernel_config: Option<Bytes>,
+    /// The stack the kernel is entered with.
+    pub stack: Stack,
+    /// The firmware boot logo described by the ACPI BGRT, if present.
+    pub boot_logo: Option<BootLogo>,
+    /// The zero-filled regions requested with the `anonymous_region` config
+    /// key.
+    pub anonymous_regions: AnonymousRegions,
+    /// The paging features enabled when the kernel is entered.
+    pub paging_features: PagingFeatures,
+    /// All entries of the UEFI configuration table, e.g. the ACPI, SMBIOS and
+    /// device tree tables.
+    pub configuration_tables: ConfigurationTables,
+    /// The UEFI memory map the memory regions were derived from, if the
+    /// bootloader was configured to pass it.
+    pub raw_memory_map: Option<RawMemoryMap>,
+    /// Whether Secure Boot was enforced by the firmware, i.e. the `SecureBoot`
+    /// UEFI variable was set and the platform wasn't in setup mode.
+    pub secure_boot_enabled: bool,
+    /// The handles needed to keep using UEFI boot services, if the bootloader
+    /// was configured not to exit them.
+    pub boot_services: Option<BootServices>,
+}
+
+/// The virtual address range of the kernel's initial stack.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Stack {
+    /// The start of the guard region below the stack.
+    ///
+    /// The guard region extends up to [`bottom`][Self::bottom] and isn't
+    /// mapped, so that overflowing the stack faults. Nothing else is mapped
+    /// there either.
+    pub guard_start: usize,
+    /// The lowest address of the stack.
+    ///
+    /// The stack is zeroed, except for its lowest page, which is filled with
+    /// [`STACK_GUARD_PATTERN`] if the bootloader was configured to.
+    pub bottom: usize,
+    /// The address after the highest address of the stack, i.e. the initial
+    /// stack pointer.
+    pub top: usize,
+}
+
+/// The paging-related CPU features the kernel is entered with.
+///
+/// The bootloader doesn't change these, so they are those the firmware
+/// enabled. Only x86_64 reports them; on other architectures, they are all
+/// `false`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct PagingFeatures {
+    /// Whether supervisor writes to read-only pages fault (`CR0.WP`).
+    pub write_protect: bool,
+    /// Whether global pages are enabled (`CR4.PGE`).
+    pub global_pages: bool,
+    /// Whether physical address extension is enabled (`CR4.PAE`).
+    pub physical_address_extension: bool,
+    /// Whether the no-execute bit is enabled (`EFER.NXE`).
+    pub no_execute: bool,
+    /// Whether five-level paging is enabled (`CR4.LA57`).
+    pub five_level_paging: bool,
+    /// Whether supervisor-mode execution prevention is enabled (`CR4.SMEP`).
+    pub supervisor_mode_execution_prevention: bool,
+    /// Whether supervisor-mode access prevention is enabled (`CR4.SMAP`).
+    pub supervisor_mode_access_prevention: bool,
+}
+
+/// The firmware boot logo, as described by the ACPI BGRT.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct BootLogo {
+    /// The physical address of the logo, encoded as a BMP file.
+    ///
+    /// The bootloader copies the logo out of boot services memory, so that it
+    /// isn't reported as usable. The copy isn't mapped in the kernel's address
+    /// space.
+    pub address: usize,
+    /// The size of the BMP file in bytes.
+    pub size: usize,
+    /// The horizontal offset of the logo's top left corner on the screen, in
+    /// pixels.
+    pub x: u32,
+    /// The vertical offset of the logo's top left corner on the screen, in
+    /// pixels.
+    pub y: u32,
+    /// The width of the logo in pixels.
+    pub width: u32,
+    /// The height of the logo in pixels.
+    pub height: u32,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -21,6 +218,9 @@ pub struct BootInformation {
 pub struct FrameBuffer {
     /// The framebuffer's physical address.
     pub start: usize,
+    /// The address the framebuffer is mapped at in the kernel's address
+    /// space.
+    pub virtual_start: usize,
     pub info: FrameBufferInfo,
 }
 
@@ -32,7 +232,25 @@ pub struct FrameBufferInfo {
     pub height: usize,
     pub pixel_format: PixelFormat,
     pub bytes_per_pixel: usize,
+    /// The number of pixels per row, including padding.
     pub stride: usize,
+    /// The number of bytes per row, including padding.
+    ///
+    /// This is usually `stride * bytes_per_pixel`, but may be larger if the
+    /// firmware pads rows beyond their stride.
+    pub pitch_bytes: usize,
+}
+
+/// The hardware descriptions passed to the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub enum HardwareDescription {
+    /// Both the ACPI tables and the device tree, whichever are present.
+    All,
+    /// Only the ACPI tables.
+    Acpi,
+    /// Only the device tree.
+    DeviceTree,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -95,6 +313,8 @@ pub struct MemoryRegion {
     ///
     /// Only [`Usable`][MemoryRegionKind::Usable] regions can be freely used.
     pub kind: MemoryRegionKind,
+    /// The capabilities of the memory region reported by the firmware.
+    pub attributes: MemoryAttributes,
 }
 
 impl MemoryRegion {
@@ -105,10 +325,88 @@ impl MemoryRegion {
             start: 0,
             len: 0,
             kind: MemoryRegionKind::Bootloader,
+            attributes: MemoryAttributes::empty(),
         }
     }
 }
 
+/// The capabilities of a memory region, mirroring the UEFI memory attributes.
+///
+/// The cacheability bits list the cache modes the region can be mapped with,
+/// and the protection bits list the protections the hardware supports for it.
+/// Neither describes how the region is currently mapped.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Hash)]
+#[repr(transparent)]
+pub struct MemoryAttributes(u64);
+
+impl MemoryAttributes {
+    /// The region can be mapped uncacheable.
+    pub const UNCACHEABLE: Self = Self(0x1);
+    /// The region can be mapped write-combining.
+    pub const WRITE_COMBINE: Self = Self(0x2);
+    /// The region can be mapped write-through.
+    pub const WRITE_THROUGH: Self = Self(0x4);
+    /// The region can be mapped write-back.
+    pub const WRITE_BACK: Self = Self(0x8);
+    /// The region can be mapped uncacheable, exported, and supports the
+    /// "fetch and add" semaphore mechanism.
+    pub const UNCACHEABLE_EXPORTED: Self = Self(0x10);
+    /// The region can be write-protected.
+    pub const WRITE_PROTECT: Self = Self(0x1000);
+    /// The region can be read-protected.
+    pub const READ_PROTECT: Self = Self(0x2000);
+    /// The region can be execute-protected.
+    pub const EXECUTE_PROTECT: Self = Self(0x4000);
+    /// The region can be made read-only.
+    pub const READ_ONLY: Self = Self(0x2_0000);
+
+    /// All the attributes that are reported.
+    pub const ALL: Self = Self(
+        Self::UNCACHEABLE.0
+            | Self::WRITE_COMBINE.0
+            | Self::WRITE_THROUGH.0
+            | Self::WRITE_BACK.0
+            | Self::UNCACHEABLE_EXPORTED.0
+            | Self::WRITE_PROTECT.0
+            | Self::READ_PROTECT.0
+            | Self::EXECUTE_PROTECT.0
+            | Self::READ_ONLY.0,
+    );
+
+    /// Returns an empty set of attributes.
+    #[must_use]
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Creates a set of attributes from UEFI memory attribute bits, discarding
+    /// those that aren't reported.
+    #[must_use]
+    pub const fn from_bits_truncate(bits: u64) -> Self {
+        Self(bits & Self::ALL.0)
+    }
+
+    /// Returns the raw UEFI memory attribute bits.
+    #[must_use]
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    /// Returns whether all attributes in `other` are set.
+    #[must_use]
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl ops::BitOr for MemoryAttributes {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
 /// Represents the different types of memory.
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 #[non_exhaustive]
@@ -119,8 +417,28 @@ pub enum MemoryRegionKind {
     /// Memory mappings created by the bootloader, including the page table and
     /// boot info mappings.
     ///
-    /// This memory should _not_ be used by the kernel.
+    /// This memory should _not_ be used by the kernel, at least until it has
+    /// switched to its own page table.
     Bootloader,
+    /// The framebuffer.
+    ///
+    /// This memory should _not_ be used by the kernel, even if the firmware
+    /// reported it as conventional memory.
+    Framebuffer,
+    /// Persistent memory, e.g. NVDIMMs, whose contents survive a reboot.
+    ///
+    /// This memory should _not_ be used as regular memory by the kernel.
+    Persistent,
+    /// Memory intended for specific uses, e.g. high-bandwidth memory.
+    ///
+    /// This memory should _not_ be used as regular memory by the kernel, unless
+    /// it knows the intended use.
+    ReservedForSpecificPurpose,
+    /// Memory the firmware reported as usable, but with attributes the
+    /// bootloader doesn't understand.
+    ///
+    /// This memory should _not_ be used by the kernel.
+    Reserved,
     /// An unknown memory region reported by the UEFI firmware.
     ///
     /// Contains the UEFI memory type tag.
@@ -177,7 +495,9 @@ pub struct Module {
     pub name: [u8; 64],
     /// The offset in bytes from the start of the modules.
     ///
-    /// The offset is guaranteed to be page aligned.
+    /// The offset is guaranteed to be aligned to the module's alignment, which
+    /// is set with an `align=` hint in the config and is at least the page
+    /// size.
     pub offset: usize,
     /// The length of the module in bytes.
     pub len: usize,
@@ -196,6 +516,55 @@ impl Module {
     }
 }
 
+/// The handles needed to keep using UEFI boot services after the bootloader
+/// entered the kernel without exiting them.
+///
+/// All memory described by the firmware's memory map when the kernel was
+/// entered is identity-mapped, as boot services must be called with identity
+/// mapping. [`BootInformation::memory_regions`] is only a snapshot, in which
+/// all memory the firmware may still hand out, including conventional memory
+/// and the memory the bootloader allocated, is reported as
+/// [`UnknownUefi`](MemoryRegionKind::UnknownUefi) rather than usable. The
+/// firmware keeps allocating memory until boot services are exited, so the
+/// kernel must fetch the memory map, and with it the map key, itself with
+/// `GetMemoryMap` right before calling `ExitBootServices`, and must only use
+/// memory it allocated from the firmware until then.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct BootServices {
+    /// The physical address of the UEFI system table.
+    pub system_table: usize,
+    /// The bootloader's image handle, which must be passed to
+    /// `ExitBootServices`.
+    pub image_handle: usize,
+}
+
+/// Information about the UEFI firmware.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Firmware {
+    /// The firmware vendor encoded as a null-terminated UTF-8 string.
+    ///
+    /// Longer vendor strings are truncated to 63 bytes.
+    #[doc(hidden)]
+    pub vendor: [u8; 64],
+    /// The vendor-specific firmware revision.
+    pub revision: u32,
+}
+
+impl Firmware {
+    /// The firmware vendor, e.g. `EDK II`.
+    #[must_use]
+    pub fn vendor(&self) -> &str {
+        let end = self
+            .vendor
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(self.vendor.len());
+        str::from_utf8(&self.vendor[..end]).expect("invalid bytes in firmware vendor")
+    }
+}
+
 /// FFI-safe slice of [`ElfSection`] structs, semantically equivalent to
 /// `&'static mut [ElfSection]`.
 #[derive(Debug)]
@@ -237,6 +606,184 @@ impl From<ElfSections> for &'static mut [ElfSection] {
     }
 }
 
+/// FFI-safe slice of [`KernelSegment`] structs, semantically equivalent to
+/// `&'static mut [KernelSegment]`.
+#[derive(Debug)]
+#[repr(C)]
+pub struct KernelSegments {
+    pub(crate) ptr: *mut KernelSegment,
+    pub(crate) len: usize,
+}
+
+impl ops::Deref for KernelSegments {
+    type Target = [KernelSegment];
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: Pointer and length were calculated from a valid slice.
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl ops::DerefMut for KernelSegments {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: Pointer and length were calculated from a valid slice.
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl From<&'static mut [KernelSegment]> for KernelSegments {
+    fn from(segments: &'static mut [KernelSegment]) -> Self {
+        Self {
+            ptr: segments.as_mut_ptr(),
+            len: segments.len(),
+        }
+    }
+}
+
+impl From<KernelSegments> for &'static mut [KernelSegment] {
+    fn from(segments: KernelSegments) -> Self {
+        // SAFETY: Pointer and length were calculated from a valid slice.
+        unsafe { slice::from_raw_parts_mut(segments.ptr, segments.len) }
+    }
+}
+
+/// FFI-safe slice of [`AnonymousRegion`] structs, semantically equivalent to
+/// `&'static mut [AnonymousRegion]`.
+#[derive(Debug)]
+#[repr(C)]
+pub struct AnonymousRegions {
+    pub(crate) ptr: *mut AnonymousRegion,
+    pub(crate) len: usize,
+}
+
+impl ops::Deref for AnonymousRegions {
+    type Target = [AnonymousRegion];
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: Pointer and length were calculated from a valid slice.
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl ops::DerefMut for AnonymousRegions {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: Pointer and length were calculated from a valid slice.
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl From<&'static mut [AnonymousRegion]> for AnonymousRegions {
+    fn from(regions: &'static mut [AnonymousRegion]) -> Self {
+        Self {
+            ptr: regions.as_mut_ptr(),
+            len: regions.len(),
+        }
+    }
+}
+
+impl From<AnonymousRegions> for &'static mut [AnonymousRegion] {
+    fn from(regions: AnonymousRegions) -> Self {
+        // SAFETY: Pointer and length were calculated from a valid slice.
+        unsafe { slice::from_raw_parts_mut(regions.ptr, regions.len) }
+    }
+}
+
+/// A virtual memory region that was mapped writable and no-execute, and
+/// zeroed.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct AnonymousRegion {
+    /// The page-aligned virtual address of the region.
+    pub start: usize,
+    /// The length of the region in bytes, rounded up to a multiple of the page
+    /// size.
+    pub len: usize,
+}
+
+/// FFI-safe slice of [`ConfigurationTable`] structs, semantically equivalent to
+/// `&'static mut [ConfigurationTable]`.
+#[derive(Debug)]
+#[repr(C)]
+pub struct ConfigurationTables {
+    pub(crate) ptr: *mut ConfigurationTable,
+    pub(crate) len: usize,
+}
+
+impl ops::Deref for ConfigurationTables {
+    type Target = [ConfigurationTable];
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: Pointer and length were calculated from a valid slice.
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl ops::DerefMut for ConfigurationTables {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: Pointer and length were calculated from a valid slice.
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl From<&'static mut [ConfigurationTable]> for ConfigurationTables {
+    fn from(tables: &'static mut [ConfigurationTable]) -> Self {
+        Self {
+            ptr: tables.as_mut_ptr(),
+            len: tables.len(),
+        }
+    }
+}
+
+impl From<ConfigurationTables> for &'static mut [ConfigurationTable] {
+    fn from(tables: ConfigurationTables) -> Self {
+        // SAFETY: Pointer and length were calculated from a valid slice.
+        unsafe { slice::from_raw_parts_mut(tables.ptr, tables.len) }
+    }
+}
+
+/// An entry of the UEFI configuration table.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ConfigurationTable {
+    /// The GUID identifying the table, in its UEFI in-memory layout.
+    pub guid: [u8; 16],
+    /// The physical address of the table.
+    pub address: usize,
+}
+
+/// The UEFI memory map, with descriptors in their UEFI in-memory layout.
+///
+/// This is the memory map fetched when exiting boot services, or once the
+/// bootloader stopped allocating from the firmware if boot services weren't
+/// exited. Frames the bootloader allocated from conventional memory
+/// afterwards, e.g. for the kernel's stack and the boot info, are still
+/// conventional memory in it, whereas [`BootInformation::memory_regions`]
+/// reports them as used.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct RawMemoryMap {
+    /// The descriptors, each [`descriptor_size`](Self::descriptor_size) bytes
+    /// long.
+    pub descriptors: Bytes,
+    /// The size of each descriptor in bytes, which may be larger than the
+    /// descriptor structure defined by the UEFI specification.
+    pub descriptor_size: usize,
+    /// The version of the descriptor structure.
+    pub descriptor_version: u32,
+}
+
+impl RawMemoryMap {
+    /// Returns the number of descriptors.
+    pub fn len(&self) -> usize {
+        self.descriptors.len() / self.descriptor_size
+    }
+
+    /// Returns whether there are no descriptors.
+    pub fn is_empty(&self) -> bool {
+        self.descriptors.is_empty()
+    }
+}
+
 /// An ELF section.
 #[derive(Debug, Clone, Copy)]
 #[repr(C)]
@@ -250,6 +797,9 @@ pub struct ElfSection {
     pub size: usize,
     /// The section flags.
     pub flags: u64,
+    /// The section type, e.g. `SHT_NOBITS` (8) for sections like `.bss` that
+    /// occupy memory but have no contents in the file.
+    pub ty: u32,
 }
 
 impl ElfSection {
@@ -264,3 +814,86 @@ impl ElfSection {
         str::from_utf8(&self.name[..end]).expect("invalid bytes in section name")
     }
 }
+
+/// A loaded segment of the kernel, and the memory backing it.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct KernelSegment {
+    /// The virtual address of the segment, i.e. its `p_vaddr`.
+    pub virtual_start: usize,
+    /// The physical address the segment was loaded at. It has the same offset
+    /// into its frame as the virtual address has into its page.
+    pub physical_start: usize,
+    /// The size of the segment in memory, i.e. its `p_memsz`.
+    pub size: usize,
+    /// The number of bytes at the start of the segment that were read from the
+    /// kernel file, i.e. its `p_filesz`. The rest of the segment is zeroed.
+    pub file_size: usize,
+    /// The ELF segment flags (`PF_X`, `PF_W` and `PF_R`) the segment was
+    /// mapped with.
+    pub flags: u32,
+}
+
+/// The physical addresses of the RSDPs found in the UEFI configuration table.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct RsdpAddresses {
+    /// The ACPI 1.0 RSDP, which points to the RSDT.
+    pub acpi1: Option<usize>,
+    /// The ACPI 2.0 RSDP, which points to the XSDT.
+    pub acpi2: Option<usize>,
+}
+
+impl RsdpAddresses {
+    /// Returns the ACPI 2.0 RSDP if present, falling back to the ACPI 1.0 RSDP.
+    #[must_use]
+    pub fn preferred(&self) -> Option<usize> {
+        self.acpi2.or(self.acpi1)
+    }
+}
+
+/// FFI-safe byte slice, semantically equivalent to `&'static [u8]`.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Bytes {
+    pub(crate) ptr: *const u8,
+    pub(crate) len: usize,
+}
+
+impl ops::Deref for Bytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: Pointer and length were calculated from a valid slice.
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Bytes {
+    /// Creates a byte slice from a pointer and a length.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be valid for reads of `len` bytes in the kernel's address
+    /// space for the rest of its lifetime.
+    #[must_use]
+    pub const unsafe fn from_raw_parts(ptr: *const u8, len: usize) -> Self {
+        Self { ptr, len }
+    }
+}
+
+impl From<&'static [u8]> for Bytes {
+    fn from(bytes: &'static [u8]) -> Self {
+        Self {
+            ptr: bytes.as_ptr(),
+            len: bytes.len(),
+        }
+    }
+}
+
+impl From<Bytes> for &'static [u8] {
+    fn from(bytes: Bytes) -> Self {
+        // SAFETY: Pointer and length were calculated from a valid slice.
+        unsafe { slice::from_raw_parts(bytes.ptr, bytes.len) }
+    }
+}
@@ -0,0 +1,116 @@
+//! Locating ACPI tables through the RSDP.
+//!
+//! Tables are read in place, as physical memory is identity-mapped before the
+//! kernel's page table is loaded.
+
+use crate::{memory::BOOT_LOGO_MEMORY, BootContext};
+use core::ptr;
+use log::{info, warn};
+use uefi_bootloader_api::{BootLogo, RsdpAddresses};
+
+/// The size of the header shared by all system description tables.
+const SDT_HEADER_SIZE: usize = 36;
+
+/// The largest boot logo that is copied, to reject garbage.
+const MAX_BOOT_LOGO_SIZE: usize = 64 * 1024 * 1024;
+
+/// Reads a `T` from the given physical address.
+///
+/// # Safety
+///
+/// The address must point to readable memory.
+unsafe fn read<T: Copy>(address: usize) -> T {
+    // SAFETY: Guaranteed by caller.
+    unsafe { ptr::read_unaligned(address as *const T) }
+}
+
+/// Returns the physical address of the first table with the given signature
+/// in the XSDT, or the RSDT if there is no XSDT.
+fn find_table(rsdp_addresses: RsdpAddresses, signature: [u8; 4]) -> Option<usize> {
+    let rsdp = rsdp_addresses.preferred()?;
+    // SAFETY: The firmware reported the RSDP at this address.
+    let (revision, rsdt, xsdt) = unsafe {
+        (
+            read::<u8>(rsdp + 15),
+            read::<u32>(rsdp + 16) as usize,
+            read::<u64>(rsdp + 24) as usize,
+        )
+    };
+
+    let (root, entry_size) = if revision >= 2 && xsdt != 0 {
+        (xsdt, 8)
+    } else {
+        (rsdt, 4)
+    };
+    // SAFETY: The RSDP points to the root table.
+    let root_len = unsafe { read::<u32>(root + 4) } as usize;
+
+    (root + SDT_HEADER_SIZE..root + root_len)
+        .step_by(entry_size)
+        .map(|entry| {
+            // SAFETY: The entry lies within the root table.
+            unsafe {
+                if entry_size == 8 {
+                    read::<u64>(entry) as usize
+                } else {
+                    read::<u32>(entry) as usize
+                }
+            }
+        })
+        // SAFETY: The root table points to valid tables.
+        .find(|table| *table != 0 && unsafe { read::<[u8; 4]>(*table) } == signature)
+}
+
+impl BootContext {
+    /// Copies the firmware boot logo described by the BGRT, if there is one,
+    /// into memory that is preserved for the kernel.
+    pub(crate) fn load_boot_logo(&self, rsdp_addresses: RsdpAddresses) -> Option<BootLogo> {
+        const IMAGE_TYPE_BITMAP: u8 = 0;
+
+        let bgrt = find_table(rsdp_addresses, *b"BGRT")?;
+        // SAFETY: The BGRT is a valid table.
+        let (image_type, image, x, y) = unsafe {
+            (
+                read::<u8>(bgrt + 39),
+                read::<u64>(bgrt + 40) as usize,
+                read::<u32>(bgrt + 48),
+                read::<u32>(bgrt + 52),
+            )
+        };
+        if image_type != IMAGE_TYPE_BITMAP || image == 0 {
+            warn!("ignoring BGRT with unsupported image type {image_type} at {image:#x}");
+            return None;
+        }
+
+        // SAFETY: The BGRT points to a bitmap, which starts with a 14 byte file
+        // header followed by the info header.
+        let (magic, size, width, height) = unsafe {
+            (
+                read::<[u8; 2]>(image),
+                read::<u32>(image + 2) as usize,
+                read::<i32>(image + 18),
+                read::<i32>(image + 22),
+            )
+        };
+        if magic != *b"BM" || size > MAX_BOOT_LOGO_SIZE {
+            warn!("ignoring invalid BGRT image at {image:#x}");
+            return None;
+        }
+
+        let copy = self.allocate_byte_slice(size, BOOT_LOGO_MEMORY);
+        // SAFETY: The bitmap is `size` bytes long, and doesn't overlap the memory
+        // we just allocated.
+        unsafe { ptr::copy_nonoverlapping(image as *const u8, copy.as_mut_ptr(), size) };
+
+        let logo = BootLogo {
+            address: copy.as_ptr() as usize,
+            size,
+            x,
+            y,
+            width: width.unsigned_abs(),
+            height: height.unsigned_abs(),
+        };
+        info!("found boot logo: {logo:x?}");
+        Some(logo)
+    }
+}
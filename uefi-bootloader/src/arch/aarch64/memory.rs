@@ -1,5 +1,8 @@
 use crate::{
-    memory::{Frame, FrameAllocator, Page, PhysicalAddress, VirtualAddress, PAGE_SIZE},
+    memory::{
+        CountingFrameAllocator, Frame, FrameAllocator, Page, PhysicalAddress, VirtualAddress,
+        GUARD_SIZE, PAGE_SIZE,
+    },
     RuntimeContext,
 };
 use bit_field::BitField;
@@ -44,6 +47,11 @@ pub(crate) const fn canonicalize_physical_address(phys_addr: usize) -> usize {
     phys_addr & 0x0000_FFFF_FFFF_FFFF
 }
 
+/// The number of levels in the kernel's page tables.
+pub(crate) const fn page_table_levels() -> u8 {
+    4
+}
+
 pub(crate) fn set_up_arch_specific_mappings(_: &mut RuntimeContext) {}
 
 #[derive(Clone, Copy, Debug)]
@@ -151,7 +159,7 @@ impl PageAllocator {
 
     pub(crate) fn get_free_address(&mut self, len: usize) -> VirtualAddress {
         const LEVEL_0_SIZE: usize = 4096 * 512 * 512 * 512;
-        let num_level_0_entries = (len + (LEVEL_0_SIZE - 1)) / LEVEL_0_SIZE;
+        let num_level_0_entries = (len + GUARD_SIZE + (LEVEL_0_SIZE - 1)) / LEVEL_0_SIZE;
 
         let level_0_index = self.get_free_entries(num_level_0_entries as u64);
         let mut address = 0;
@@ -161,11 +169,20 @@ impl PageAllocator {
     }
 
     pub(crate) fn mark_segment_as_used(&mut self, segment: &ProgramHeader) {
-        let start = VirtualAddress::new_canonical(segment.p_vaddr as usize);
-        let end_inclusive = (start + segment.p_memsz as usize) - 1;
+        self.mark_range_as_used(
+            VirtualAddress::new_canonical(segment.p_vaddr as usize),
+            segment.p_memsz as usize,
+        );
+    }
+
+    /// Prevents the P0 entries covering the given range from being returned
+    /// by [`get_free_address`](Self::get_free_address).
+    pub(crate) fn mark_range_as_used(&mut self, start: VirtualAddress, len: usize) {
+        let end_inclusive = (start + len) - 1;
 
         let start_page = Page::containing_address(start);
-        let end_page_inclusive = Page::containing_address(end_inclusive);
+        // The guard after the range is reserved too.
+        let end_page_inclusive = Page::containing_address(end_inclusive) + GUARD_SIZE / PAGE_SIZE;
 
         for p0_index in start_page.p0_index()..=end_page_inclusive.p0_index() {
             self.level_0_entries[p0_index] = true;
@@ -175,6 +192,8 @@ impl PageAllocator {
 
 pub(crate) struct Mapper {
     level_zero_page_table: &'static mut PageTable,
+    /// The number of frames allocated for page tables.
+    page_table_frames: usize,
 }
 
 impl Mapper {
@@ -190,6 +209,7 @@ impl Mapper {
         unsafe { ptr::write_bytes(address, 0, 1) };
         Self {
             level_zero_page_table: unsafe { &mut *address },
+            page_table_frames: 1,
         }
     }
 
@@ -201,6 +221,7 @@ impl Mapper {
             as *mut PageTable;
         Self {
             level_zero_page_table: unsafe { &mut *address },
+            page_table_frames: 0,
         }
     }
 
@@ -210,6 +231,30 @@ impl Mapper {
         ))
     }
 
+    /// Returns the number of frames allocated for page tables.
+    pub(crate) fn page_table_frames(&self) -> usize {
+        self.page_table_frames
+    }
+
+    pub(crate) fn translate(&self, address: VirtualAddress) -> Option<PhysicalAddress> {
+        let page = Page::containing_address(address);
+
+        // SAFETY: The page tables were created by the mapper.
+        let level_3 = unsafe {
+            self.level_zero_page_table
+                .next_table(page.p0_index())?
+                .next_table(page.p1_index())?
+                .next_table(page.p2_index())?
+        };
+        let entry = &level_3[page.p3_index()];
+
+        if entry.is_unused() {
+            None
+        } else {
+            Some(entry.output_address() + address.page_offset())
+        }
+    }
+
     pub(crate) fn map<T>(
         &mut self,
         page: Page,
@@ -224,6 +269,10 @@ impl Mapper {
             .page_descriptor(true)
             .writable(true)
             .no_execute(true);
+        let frame_allocator = &mut CountingFrameAllocator {
+            inner: frame_allocator,
+            count: &mut self.page_table_frames,
+        };
 
         let level_1 = unsafe {
             self.level_zero_page_table.create_next_table(
@@ -271,6 +320,21 @@ impl PageTable {
         }
         unsafe { entry.as_page_table() }
     }
+
+    /// Returns the table referenced by the entry at `index`, if it is used.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure used entries reference valid page tables.
+    unsafe fn next_table(&self, index: usize) -> Option<&PageTable> {
+        let entry = &self[index];
+        if entry.is_unused() {
+            None
+        } else {
+            // SAFETY: Guaranteed by caller.
+            Some(unsafe { entry.as_page_table() })
+        }
+    }
 }
 
 impl Index<usize> for PageTable {
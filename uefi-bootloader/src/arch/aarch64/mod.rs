@@ -1,30 +1,67 @@
 // FIXME: This doesn't work.
 
-use crate::KernelContext;
+use crate::{config::Interrupts, KernelContext};
 use core::arch::asm;
 use cortex_a::{
     asm::barrier,
     registers::{MAIR_EL1, SCTLR_EL1, TCR_EL1},
 };
 use tock_registers::interfaces::{ReadWriteable, Writeable};
+use uefi_bootloader_api::PagingFeatures;
 
 pub(crate) mod memory;
 
-pub(crate) fn pre_context_switch_actions() {
+/// The ELF machine type of kernels for this architecture.
+pub(crate) const KERNEL_MACHINE: u16 = goblin::elf64::header::EM_AARCH64;
+
+// There are no legacy PICs to mask.
+pub(crate) fn pre_context_switch_actions(config: Interrupts) {
+    if config.disable {
+        // SAFETY: Masking interrupts doesn't affect memory safety.
+        unsafe { asm!("msr daifset, #0xf", options(nomem, nostack)) };
+    }
     enable_mmu();
     configure_translation_registers();
 }
 
+/// Busy-waits for `ms` milliseconds using the generic timer's physical count.
+pub(crate) fn busy_wait(ms: usize) {
+    let read = || {
+        let count: u64;
+        // SAFETY: Reading the physical count doesn't have side effects. The `isb`
+        // keeps it from being read early.
+        unsafe { asm!("isb", "mrs {}, cntpct_el0", out(reg) count, options(nomem, nostack)) };
+        count
+    };
+    let frequency: u64;
+    // SAFETY: Reading the timer frequency doesn't have side effects.
+    unsafe { asm!("mrs {}, cntfrq_el0", out(reg) frequency, options(nomem, nostack)) };
+
+    let ticks = frequency * ms as u64 / 1000;
+    let start = read();
+    while read().wrapping_sub(start) < ticks {
+        core::hint::spin_loop();
+    }
+}
+
 // The function needs to take ownership of the context so that it remains valid
 // when we switch page tables.
 #[allow(clippy::needless_pass_by_value)]
 pub(crate) unsafe fn jump_to_kernel(context: KernelContext) -> ! {
+    // The stack pointer must always be 16-byte aligned.
+    debug_assert!(
+        context.stack_top.value() % 16 == 0,
+        "kernel stack top isn't 16-byte aligned"
+    );
+
     // SAFETY: The caller guarantees that the context switch function is
     // identity-mapped, the stack pointer is mapped in the new page table, and the
     // kernel entry point is correct.
     unsafe {
         // TODO: Set stack pointer, and jump to entry point.
         core::arch::asm!(
+            // Make the page table stores visible to the table walker.
+            "dsb ishst",
             "msr ttbr0_el1, {}",
             "tlbi alle1",
             "dsb ish",
@@ -38,6 +75,19 @@ pub(crate) unsafe fn jump_to_kernel(context: KernelContext) -> ! {
     };
 }
 
+// The reported features are specific to x86_64.
+pub(crate) fn paging_features() -> PagingFeatures {
+    PagingFeatures {
+        write_protect: false,
+        global_pages: false,
+        physical_address_extension: false,
+        no_execute: false,
+        five_level_paging: false,
+        supervisor_mode_execution_prevention: false,
+        supervisor_mode_access_prevention: false,
+    }
+}
+
 pub(crate) fn halt() -> ! {
     loop {
         // SAFETY: This instruction will stop the CPU.
@@ -5,6 +5,9 @@ cfg_if::cfg_if! {
     } else if #[cfg(target_arch = "aarch64")] {
         mod aarch64;
         pub(crate) use self::aarch64::*;
+    } else if #[cfg(target_arch = "riscv64")] {
+        mod riscv64;
+        pub(crate) use self::riscv64::*;
     } else {
         mod unsupported;
         pub(crate) use self::unsupported::*;
@@ -0,0 +1,97 @@
+//! Finding what the kernel needs to know about the firmware before boot
+//! services are exited: the id of the hart the bootloader runs on, which the
+//! kernel is passed in `a0`, and the frequency of the `time` counter.
+//!
+//! The hart id is read from `RISCV_EFI_BOOT_PROTOCOL` if the firmware
+//! implements it, and otherwise from the `/chosen/boot-hartid` property of the
+//! device tree the firmware installed as a configuration table. The frequency
+//! is read from the `/cpus/timebase-frequency` property.
+
+use crate::fdt;
+use log::{info, warn};
+use spin::Once;
+use uefi::{
+    proto::Protocol,
+    table::{Boot, SystemTable},
+    Guid, Identify, Status,
+};
+
+/// The GUID of the RISC-V boot protocol.
+const RISCV_EFI_BOOT_GUID: Guid =
+    Guid::from_values(0xccd1_5fec, 0x6f73, 0x4eec, 0x8395, 0x3e69_e4b9_40bf);
+
+/// The id of the hart the bootloader runs on.
+static BOOT_HART_ID: Once<usize> = Once::new();
+
+/// The frequency of the `time` counter, in Hz, if the device tree has it.
+static TIMEBASE_FREQUENCY: Once<Option<u64>> = Once::new();
+
+#[repr(C)]
+struct RiscvEfiBoot {
+    revision: u64,
+    get_boot_hart_id:
+        unsafe extern "efiapi" fn(this: *const RiscvEfiBoot, boot_hart_id: *mut usize) -> Status,
+}
+
+// SAFETY: The GUID is the one of the protocol the struct describes.
+unsafe impl Identify for RiscvEfiBoot {
+    const GUID: Guid = RISCV_EFI_BOOT_GUID;
+}
+
+impl Protocol for RiscvEfiBoot {}
+
+/// Reads the boot hart id and the timebase frequency, so that they are
+/// available after exiting boot services.
+///
+/// This must be called while boot services are available.
+pub(crate) fn read_firmware_info(
+    system_table: &SystemTable<Boot>,
+    device_tree_address: Option<usize>,
+) {
+    // SAFETY: The firmware installed a valid device tree at the address.
+    let tree = device_tree_address.and_then(|address| unsafe { fdt::from_address(address) });
+
+    let hart_id = hart_id_from_protocol(system_table)
+        .or_else(|| {
+            let value = fdt::property(tree?, b"chosen", b"boot-hartid")?;
+            usize::try_from(fdt::read_cell(value)?).ok()
+        })
+        .expect("failed to find the boot hart id in the RISC-V boot protocol or the device tree");
+    info!("boot hart id: {hart_id}");
+    BOOT_HART_ID.call_once(|| hart_id);
+
+    let frequency = tree
+        .and_then(|tree| fdt::property(tree, b"cpus", b"timebase-frequency"))
+        .and_then(fdt::read_cell)
+        .filter(|&frequency| frequency != 0);
+    match frequency {
+        Some(frequency) => info!("timebase frequency: {frequency} Hz"),
+        None => warn!("no timebase frequency in the device tree, waiting won't be possible"),
+    }
+    TIMEBASE_FREQUENCY.call_once(|| frequency);
+}
+
+/// Returns the id read by [`read_firmware_info`].
+pub(super) fn boot_hart_id() -> usize {
+    *BOOT_HART_ID.get().expect("boot hart id wasn't read")
+}
+
+/// Returns the frequency read by [`read_firmware_info`], if there was one.
+pub(super) fn timebase_frequency() -> Option<u64> {
+    TIMEBASE_FREQUENCY.get().copied().flatten()
+}
+
+fn hart_id_from_protocol(system_table: &SystemTable<Boot>) -> Option<usize> {
+    let boot_services = system_table.boot_services();
+    let handle = boot_services
+        .get_handle_for_protocol::<RiscvEfiBoot>()
+        .ok()?;
+    let protocol = boot_services
+        .open_protocol_exclusive::<RiscvEfiBoot>(handle)
+        .ok()?;
+
+    let mut hart_id = 0;
+    // SAFETY: The protocol is open, and the id is written to a valid pointer.
+    let status = unsafe { (protocol.get_boot_hart_id)(&*protocol, &mut hart_id) };
+    status.is_success().then_some(hart_id)
+}
@@ -0,0 +1,525 @@
+//! Sv48 and Sv39 paging.
+//!
+//! The mode is chosen once, before any page table is built: Sv48 if the hart
+//! supports it, and Sv39 otherwise. Addresses are always canonicalized as Sv48
+//! addresses, which Sv39 addresses are a subset of; under Sv39, free pages
+//! are only handed out from the higher half, and mapping a page outside of the
+//! Sv39 address space panics.
+//!
+//! The firmware usually runs with translation disabled, in which case the
+//! bootloader switches to its own identity-mapped tables to write the boot
+//! info.
+
+use crate::{
+    memory::{
+        CountingFrameAllocator, Frame, FrameAllocator, Page, PhysicalAddress, VirtualAddress,
+        GUARD_SIZE, PAGE_SIZE,
+    },
+    RuntimeContext,
+};
+use bit_field::BitField;
+use core::{
+    arch::asm,
+    ops::{Index, IndexMut},
+    ptr,
+};
+use goblin::elf64::program_header::ProgramHeader;
+use log::info;
+use spin::Once;
+
+/// The `satp` mode disabling translation.
+const SATP_MODE_BARE: usize = 0;
+/// The `satp` mode selecting Sv39.
+const SATP_MODE_SV39: usize = 8;
+/// The `satp` mode selecting Sv48.
+const SATP_MODE_SV48: usize = 9;
+
+/// The number of page table levels of the paging mode in use.
+static LEVELS: Once<usize> = Once::new();
+
+/// A root table identity-mapping the first 512 GiB with 1 GiB pages under
+/// Sv39, and with a 512 GiB page under Sv48, used to probe the paging modes.
+static PROBE_TABLE: PageTable = {
+    let mut entries = [const { PageTableEntry(0) }; 512];
+    let mut i = 0;
+    while i < entries.len() {
+        // Under Sv48, only the first entry is aligned, and only it is used.
+        entries[i] = PageTableEntry(((i as u64) << 28) | PteFlags::IDENTITY);
+        i += 1;
+    }
+    PageTable { entries }
+};
+
+/// Returns the number of page table levels, choosing the paging mode on the
+/// first call.
+fn levels() -> usize {
+    *LEVELS.call_once(|| {
+        let old_satp = read_satp();
+        let probe_root = ptr::addr_of!(PROBE_TABLE) as usize / PAGE_SIZE;
+        let mut levels = None;
+        for (mode, mode_levels) in [(SATP_MODE_SV48, 4), (SATP_MODE_SV39, 3)] {
+            let satp = (mode << 60) | probe_root;
+            // SAFETY: The probe table identity-maps the first 512 GiB, like the
+            // firmware, and `satp` is restored right after. Writing an unsupported
+            // mode leaves `satp` unchanged.
+            unsafe { asm!("csrw satp, {}", "sfence.vma", in(reg) satp, options(nostack)) };
+            let supported = read_satp() == satp;
+            // SAFETY: This restores the firmware's address space.
+            unsafe { asm!("csrw satp, {}", "sfence.vma", in(reg) old_satp, options(nostack)) };
+            if supported {
+                levels = Some(mode_levels);
+                break;
+            }
+        }
+        let levels = levels.expect("the hart supports neither Sv48 nor Sv39");
+        info!("using Sv{} paging", 12 + 9 * levels);
+        levels
+    })
+}
+
+/// The number of bits in a virtual address of the paging mode in use.
+fn virtual_address_bits() -> usize {
+    12 + 9 * levels()
+}
+
+/// The size of the memory mapped by a root page table entry.
+fn root_entry_size() -> usize {
+    PAGE_SIZE << (9 * (levels() - 1))
+}
+
+/// Returns the address mapped by the root page table entry at `index`.
+#[allow(clippy::cast_possible_wrap, clippy::cast_sign_loss)]
+fn root_entry_address(index: usize) -> VirtualAddress {
+    let unused_bits = 64 - virtual_address_bits();
+    let address = (((index * root_entry_size()) << unused_bits) as isize >> unused_bits) as usize;
+    VirtualAddress::new_canonical(address)
+}
+
+/// Returns whether `address` is in the address space of the paging mode in
+/// use, i.e. is sign-extended from its highest bit.
+fn is_mappable(address: VirtualAddress) -> bool {
+    let bits = address.value().get_bits(virtual_address_bits() - 1..64);
+    bits == 0 || bits.count_ones() as usize == 64 - (virtual_address_bits() - 1)
+}
+
+/// Sv48 virtual addresses are sign-extended from bit 47. Sv39 addresses are
+/// checked when they are mapped.
+pub(crate) fn is_canonical_virtual_address(virt_addr: usize) -> bool {
+    matches!(virt_addr.get_bits(47..64), 0 | 0b1_1111_1111_1111_1111)
+}
+
+/// Sv48 virtual addresses are sign-extended from bit 47.
+#[allow(clippy::cast_possible_wrap, clippy::cast_sign_loss)]
+pub(crate) const fn canonicalize_virtual_address(virt_addr: usize) -> usize {
+    ((virt_addr << 16) as isize >> 16) as usize
+}
+
+/// Sv48 physical addresses are 56 bits long.
+pub(crate) fn is_canonical_physical_address(phys_addr: usize) -> bool {
+    phys_addr.get_bits(56..64) == 0
+}
+
+/// Sv48 physical addresses are 56 bits long.
+pub(crate) const fn canonicalize_physical_address(phys_addr: usize) -> usize {
+    phys_addr & 0x00FF_FFFF_FFFF_FFFF
+}
+
+/// The number of levels in the kernel's page tables.
+pub(crate) fn page_table_levels() -> u8 {
+    levels() as u8
+}
+
+pub(crate) fn set_up_arch_specific_mappings(_: &mut RuntimeContext) {}
+
+/// Returns the `satp` value selecting the paging mode in use with the given
+/// root page table.
+pub(crate) fn satp(root: Frame) -> usize {
+    let mode = if levels() == 4 {
+        SATP_MODE_SV48
+    } else {
+        SATP_MODE_SV39
+    };
+    (mode << 60) | root.number()
+}
+
+fn read_satp() -> usize {
+    let satp;
+    // SAFETY: Reading `satp` has no side effects.
+    unsafe { asm!("csrr {}, satp", out(reg) satp, options(nomem, nostack)) };
+    satp
+}
+
+/// The bits of a page table entry.
+///
+/// Leaf entries are always readable, and accessed and dirty so that harts
+/// which don't update these bits in hardware don't fault.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PteFlags(u64);
+
+impl PteFlags {
+    const VALID: u64 = 1;
+    const READABLE: u64 = 1 << 1;
+    const WRITABLE: u64 = 1 << 2;
+    const EXECUTABLE: u64 = 1 << 3;
+    const USER: u64 = 1 << 4;
+    const GLOBAL: u64 = 1 << 5;
+    const ACCESSED: u64 = 1 << 6;
+    const DIRTY: u64 = 1 << 7;
+    /// The bits of an identity-mapping leaf entry.
+    const IDENTITY: u64 = Self::VALID
+        | Self::READABLE
+        | Self::WRITABLE
+        | Self::EXECUTABLE
+        | Self::ACCESSED
+        | Self::DIRTY;
+
+    pub(crate) fn new() -> Self {
+        Self(Self::READABLE | Self::EXECUTABLE | Self::ACCESSED | Self::DIRTY)
+    }
+
+    pub(crate) fn present(self, enable: bool) -> Self {
+        if enable {
+            Self(self.0 | Self::VALID)
+        } else {
+            Self(self.0 & !Self::VALID)
+        }
+    }
+
+    pub(crate) fn writable(self, enable: bool) -> Self {
+        if enable {
+            Self(self.0 | Self::WRITABLE)
+        } else {
+            Self(self.0 & !Self::WRITABLE)
+        }
+    }
+
+    pub(crate) fn no_execute(self, enable: bool) -> Self {
+        if enable {
+            Self(self.0 & !Self::EXECUTABLE)
+        } else {
+            Self(self.0 | Self::EXECUTABLE)
+        }
+    }
+
+    /// Sets whether the page is accessible from user mode, and only from it
+    /// unless `sstatus.SUM` is set.
+    pub(crate) fn user(self, enable: bool) -> Self {
+        if enable {
+            Self(self.0 | Self::USER)
+        } else {
+            Self(self.0 & !Self::USER)
+        }
+    }
+
+    /// Sets whether the mapping exists in all address spaces, so that it isn't
+    /// flushed when switching between them.
+    pub(crate) fn global(self, enable: bool) -> Self {
+        if enable {
+            Self(self.0 | Self::GLOBAL)
+        } else {
+            Self(self.0 & !Self::GLOBAL)
+        }
+    }
+}
+
+impl Page {
+    /// Returns the index into the page table at the given level, where level 0
+    /// holds the leaf entries.
+    const fn index(self, level: usize) -> usize {
+        (self.number >> (9 * level)) & 0x1ff
+    }
+}
+
+/// Hands out virtual addresses in units of root page table entries.
+pub(crate) struct PageAllocator {
+    root_entries: [bool; 512],
+}
+
+impl PageAllocator {
+    pub(crate) fn new() -> Self {
+        let mut page_allocator = Self {
+            root_entries: [false; 512],
+        };
+        // The lower half is used for identity mappings. Under Sv48, it is much
+        // larger than physical memory, so only the first entry is reserved.
+        let reserved = if levels() == 4 { 1 } else { 256 };
+        page_allocator.root_entries[..reserved].fill(true);
+
+        page_allocator
+    }
+
+    fn get_free_entries(&mut self, num: usize) -> usize {
+        // Create an iterator over all available root indices with `num`
+        // contiguous free entries.
+        let mut free_entries = self
+            .root_entries
+            .windows(num)
+            .enumerate()
+            .filter(|(_, entries)| entries.iter().all(|used| !used))
+            .map(|(idx, _)| idx);
+
+        let idx = free_entries
+            .next()
+            .expect("no usable root page table entries found");
+
+        // Mark the entries as used.
+        for i in 0..num {
+            self.root_entries[idx + i] = true;
+        }
+
+        idx
+    }
+
+    pub(crate) fn get_free_address(&mut self, len: usize) -> VirtualAddress {
+        let num_root_entries = (len + GUARD_SIZE).div_ceil(root_entry_size());
+
+        let root_index = self.get_free_entries(num_root_entries);
+        root_entry_address(root_index)
+    }
+
+    pub(crate) fn mark_segment_as_used(&mut self, segment: &ProgramHeader) {
+        self.mark_range_as_used(
+            VirtualAddress::new_canonical(segment.p_vaddr as usize),
+            segment.p_memsz as usize,
+        );
+    }
+
+    /// Prevents the root entries covering the given range from being returned
+    /// by [`get_free_address`](Self::get_free_address).
+    pub(crate) fn mark_range_as_used(&mut self, start: VirtualAddress, len: usize) {
+        let end_inclusive = (start + len) - 1;
+
+        let start_page = Page::containing_address(start);
+        // The guard after the range is reserved too.
+        let end_page_inclusive = Page::containing_address(end_inclusive) + GUARD_SIZE / PAGE_SIZE;
+
+        let root_level = levels() - 1;
+        for root_index in start_page.index(root_level)..=end_page_inclusive.index(root_level) {
+            self.root_entries[root_index] = true;
+        }
+    }
+}
+
+pub(crate) struct Mapper {
+    root: &'static mut PageTable,
+    /// The number of frames allocated for page tables.
+    page_table_frames: usize,
+}
+
+impl Mapper {
+    pub(crate) fn new<T>(frame_allocator: &mut T) -> Self
+    where
+        T: FrameAllocator,
+    {
+        Self {
+            root: allocate_table(frame_allocator),
+            page_table_frames: 1,
+        }
+    }
+
+    /// Returns a mapper for the bootloader's own address space.
+    ///
+    /// The bootloader switches to a table sharing the firmware's mappings of
+    /// the first 512 GiB, or identity-mapping them if the firmware runs with
+    /// translation disabled, so that pages can be mapped into it.
+    pub(crate) fn current<T>(frame_allocator: &mut T) -> Self
+    where
+        T: FrameAllocator,
+    {
+        let old_satp = read_satp();
+        let root = allocate_table(frame_allocator);
+        // SAFETY: Physical memory is identity-mapped, and the firmware's root table
+        // stays valid as long as boot services, or the bootloader, run.
+        let old_root = || unsafe { &*((old_satp.get_bits(0..44) * PAGE_SIZE) as *const PageTable) };
+        // Only the lower half is relevant, as the firmware identity-maps memory.
+        match (old_satp >> 60, levels()) {
+            (SATP_MODE_BARE, _) => {
+                *root = PROBE_TABLE.clone();
+                if levels() == 4 {
+                    root.entries[1..].fill(PageTableEntry(0));
+                } else {
+                    root.entries[256..].fill(PageTableEntry(0));
+                }
+            }
+            (SATP_MODE_SV48, 4) => root[0] = old_root()[0].clone(),
+            // An Sv39 root table is laid out like the level 2 tables of Sv48.
+            (SATP_MODE_SV39, 4) => {
+                root[0].0 = ((old_satp.get_bits(0..44) as u64) << 10) | PteFlags::VALID;
+            }
+            (SATP_MODE_SV39, 3) => root.entries[..256].clone_from_slice(&old_root().entries[..256]),
+            (mode, levels) => panic!(
+                "firmware uses satp mode {mode}, which can't be switched from to Sv{}",
+                12 + 9 * levels
+            ),
+        }
+
+        let mapper = Self {
+            root,
+            page_table_frames: 1,
+        };
+        let satp = satp(Frame::containing_address(PhysicalAddress::new_canonical(
+            mapper.root as *const _ as usize,
+        )));
+        // SAFETY: The first 512GiB, which hold the bootloader, are mapped the same.
+        unsafe { asm!("csrw satp, {}", "sfence.vma", in(reg) satp, options(nostack)) };
+        assert!(
+            read_satp() == satp,
+            "failed to switch to the bootloader's page table"
+        );
+
+        mapper
+    }
+
+    pub(crate) fn frame(&mut self) -> Frame {
+        Frame::containing_address(PhysicalAddress::new_canonical(
+            self.root as *const _ as usize,
+        ))
+    }
+
+    /// Returns the number of frames allocated for page tables.
+    pub(crate) fn page_table_frames(&self) -> usize {
+        self.page_table_frames
+    }
+
+    pub(crate) fn translate(&self, address: VirtualAddress) -> Option<PhysicalAddress> {
+        let page = Page::containing_address(address);
+        let mut table: &PageTable = &*self.root;
+
+        for level in (0..levels()).rev() {
+            let entry = &table[page.index(level)];
+            if !entry.is_valid() {
+                return None;
+            }
+            if entry.is_leaf() {
+                let offset = address.value() & ((PAGE_SIZE << (9 * level)) - 1);
+                return Some(entry.output_address() + offset);
+            }
+            // SAFETY: Valid non-leaf entries reference page tables.
+            table = unsafe { entry.as_page_table() };
+        }
+
+        None
+    }
+
+    pub(crate) fn map<T>(
+        &mut self,
+        page: Page,
+        frame: Frame,
+        flags: PteFlags,
+        frame_allocator: &mut T,
+    ) where
+        T: FrameAllocator,
+    {
+        let frame_allocator = &mut CountingFrameAllocator {
+            inner: frame_allocator,
+            count: &mut self.page_table_frames,
+        };
+        assert!(
+            is_mappable(page.start_address()),
+            "{page:?} is outside of the Sv{} address space",
+            virtual_address_bits()
+        );
+        let mut table: &mut PageTable = self.root;
+        for level in (1..levels()).rev() {
+            // SAFETY: The page tables were created by the mappers.
+            table = unsafe { table.create_next_table(page.index(level), frame_allocator) };
+        }
+
+        let entry = &mut table[page.index(0)];
+        assert!(!entry.is_valid(), "{page:?} is already mapped");
+        entry.set(frame, flags);
+    }
+}
+
+/// Allocates a zeroed page table.
+fn allocate_table<T>(frame_allocator: &mut T) -> &'static mut PageTable
+where
+    T: FrameAllocator,
+{
+    let pointer = frame_allocator
+        .allocate_frame()
+        .expect("failed to allocate frame for page table")
+        .start_address()
+        .value() as *mut PageTable;
+    // SAFETY: Physical memory is identity-mapped, and the frame was just
+    // allocated.
+    unsafe {
+        ptr::write_bytes(pointer, 0, 1);
+        &mut *pointer
+    }
+}
+
+#[derive(Clone, Debug)]
+#[repr(C, align(4096))]
+struct PageTable {
+    entries: [PageTableEntry; 512],
+}
+
+impl PageTable {
+    /// Returns the table referenced by the entry at `index`, creating it if the
+    /// entry is unused.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure valid entries reference valid page tables.
+    unsafe fn create_next_table<T>(
+        &mut self,
+        index: usize,
+        frame_allocator: &mut T,
+    ) -> &mut PageTable
+    where
+        T: FrameAllocator,
+    {
+        let entry = &mut self[index];
+        if !entry.is_valid() {
+            let table = allocate_table(frame_allocator);
+            // Non-leaf entries have no permission bits.
+            entry.0 = ((table as *const PageTable as u64) >> 2) | PteFlags::VALID;
+        }
+        assert!(!entry.is_leaf(), "tried to map a page inside a huge page");
+        // SAFETY: Guaranteed by caller.
+        unsafe { entry.as_page_table() }
+    }
+}
+
+impl Index<usize> for PageTable {
+    type Output = PageTableEntry;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.entries[index]
+    }
+}
+
+impl IndexMut<usize> for PageTable {
+    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
+        &mut self.entries[index]
+    }
+}
+
+#[derive(Clone, Debug)]
+#[repr(transparent)]
+struct PageTableEntry(u64);
+
+impl PageTableEntry {
+    fn is_valid(&self) -> bool {
+        self.0 & PteFlags::VALID != 0
+    }
+
+    fn is_leaf(&self) -> bool {
+        self.0 & (PteFlags::READABLE | PteFlags::EXECUTABLE) != 0
+    }
+
+    fn output_address(&self) -> PhysicalAddress {
+        PhysicalAddress::new_canonical((self.0.get_bits(10..54) as usize) * PAGE_SIZE)
+    }
+
+    fn set(&mut self, frame: Frame, flags: PteFlags) {
+        self.0 = ((frame.number() as u64) << 10) | flags.0;
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn as_page_table(&self) -> &'static mut PageTable {
+        // SAFETY: Address validity guaranteed by caller.
+        unsafe { &mut *(self.output_address().value() as *mut _) }
+    }
+}
@@ -0,0 +1,103 @@
+//! The kernel is entered with the id of the booting hart in `a0` and the boot
+//! info pointer in `a1`, like an `extern "C"` function taking them as its two
+//! arguments. This matches the convention of SBI firmware entering a
+//! supervisor-mode kernel, which gets the hart id in `a0` and its
+//! device tree in `a1`.
+
+use crate::{config::Interrupts, KernelContext};
+use core::arch::asm;
+use log::warn;
+use uefi_bootloader_api::PagingFeatures;
+
+mod firmware;
+pub(crate) mod memory;
+
+pub(crate) use self::firmware::read_firmware_info;
+
+/// The ELF machine type of kernels for this architecture.
+pub(crate) const KERNEL_MACHINE: u16 = goblin::elf64::header::EM_RISCV;
+
+// There are no legacy PICs to mask.
+pub(crate) fn pre_context_switch_actions(config: Interrupts) {
+    if config.disable {
+        // SAFETY: Clearing `sstatus.SIE` only masks supervisor interrupts.
+        unsafe { asm!("csrci sstatus, 0x2", options(nomem, nostack)) };
+    }
+}
+
+/// Busy-waits for `ms` milliseconds using the `time` counter, whose frequency
+/// is read from the device tree.
+pub(crate) fn busy_wait(ms: usize) {
+    let Some(frequency) = firmware::timebase_frequency() else {
+        warn!("can't wait {ms} ms without the timebase frequency, not waiting");
+        return;
+    };
+    let read = || {
+        let time: u64;
+        // SAFETY: Reading the time counter doesn't have side effects.
+        unsafe { asm!("rdtime {}", out(reg) time, options(nomem, nostack)) };
+        time
+    };
+
+    let ticks = frequency * ms as u64 / 1000;
+    let start = read();
+    while read().wrapping_sub(start) < ticks {
+        core::hint::spin_loop();
+    }
+}
+
+// The function needs to take ownership of the context so that it remains valid
+// when we switch page tables.
+//
+// The `sfence.vma` after writing `satp` orders the page table stores before
+// the table walks of the new address space, and flushes any stale
+// translations. The return address and frame pointer are zeroed to mark the
+// end of the call chain for stack unwinders.
+#[allow(clippy::needless_pass_by_value)]
+pub(crate) unsafe fn jump_to_kernel(context: KernelContext) -> ! {
+    // The stack pointer must always be 16-byte aligned.
+    debug_assert!(
+        context.stack_top.value() % 16 == 0,
+        "kernel stack top isn't 16-byte aligned"
+    );
+
+    // SAFETY: The caller guarantees that the context switch function is
+    // identity-mapped, the stack pointer is mapped in the new page table, and the
+    // kernel entry point is correct.
+    unsafe {
+        asm!(
+            "csrw satp, t0",
+            "sfence.vma",
+            "mv sp, t1",
+            "li ra, 0",
+            "li s0, 0",
+            "jr t2",
+            in("t0") memory::satp(context.page_table_frame),
+            in("t1") context.stack_top.value(),
+            in("t2") context.entry_point.value(),
+            in("a0") firmware::boot_hart_id(),
+            in("a1") context.boot_info,
+            options(noreturn),
+        );
+    }
+}
+
+// The reported features are specific to x86_64.
+pub(crate) fn paging_features() -> PagingFeatures {
+    PagingFeatures {
+        write_protect: false,
+        global_pages: false,
+        physical_address_extension: false,
+        no_execute: false,
+        five_level_paging: false,
+        supervisor_mode_execution_prevention: false,
+        supervisor_mode_access_prevention: false,
+    }
+}
+
+pub(crate) fn halt() -> ! {
+    loop {
+        // SAFETY: These instructions will stop the hart.
+        unsafe { asm!("csrci sstatus, 0x2", "wfi") };
+    }
+}
@@ -1,5 +1,5 @@
 use crate::{
-    memory::{Frame, FrameAllocator, Page, VirtualAddress},
+    memory::{Frame, FrameAllocator, Page, PhysicalAddress, VirtualAddress},
     RuntimeContext,
 };
 use goblin::elf64::program_header::ProgramHeader;
@@ -20,6 +20,10 @@ pub(crate) const fn canonicalize_physical_address(_physical_address: usize) -> u
     unimplemented!();
 }
 
+pub(crate) const fn page_table_levels() -> u8 {
+    unimplemented!();
+}
+
 pub(crate) fn set_up_arch_specific_mappings(_context: &mut RuntimeContext) {
     unimplemented!();
 }
@@ -63,6 +67,10 @@ impl PageAllocator {
     pub(crate) fn mark_segment_as_used(&mut self, _segment: &ProgramHeader) {
         unimplemented!();
     }
+
+    pub(crate) fn mark_range_as_used(&mut self, _start: VirtualAddress, _len: usize) {
+        unimplemented!();
+    }
 }
 
 pub(crate) struct Mapper;
@@ -86,6 +94,14 @@ impl Mapper {
         unimplemented!();
     }
 
+    pub(crate) fn page_table_frames(&self) -> usize {
+        unimplemented!();
+    }
+
+    pub(crate) fn translate(&self, _address: VirtualAddress) -> Option<PhysicalAddress> {
+        unimplemented!();
+    }
+
     pub(crate) fn map<T>(
         &mut self,
         _page: Page,
@@ -1,8 +1,15 @@
-use crate::KernelContext;
+use crate::{config::Interrupts, KernelContext};
+use uefi_bootloader_api::PagingFeatures;
 
 pub(crate) mod memory;
 
-pub(crate) fn pre_context_switch_actions() {
+pub(crate) const KERNEL_MACHINE: u16 = goblin::elf64::header::EM_NONE;
+
+pub(crate) fn pre_context_switch_actions(_config: Interrupts) {
+    unimplemented!();
+}
+
+pub(crate) fn busy_wait(_ms: usize) {
     unimplemented!();
 }
 
@@ -13,6 +20,10 @@ pub(crate) unsafe fn jump_to_kernel(_context: KernelContext) -> ! {
     unimplemented!();
 }
 
+pub(crate) fn paging_features() -> PagingFeatures {
+    unimplemented!();
+}
+
 pub(crate) fn halt() -> ! {
     unimplemented!();
 }
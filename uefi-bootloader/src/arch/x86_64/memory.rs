@@ -1,12 +1,15 @@
 use crate::{
-    memory::{Frame, FrameAllocator, Page, PhysicalAddress, VirtualAddress},
+    memory::{
+        CountingFrameAllocator, Frame, FrameAllocator, Page, PhysicalAddress, VirtualAddress,
+        GUARD_SIZE, PAGE_SIZE,
+    },
     RuntimeContext,
 };
 use bit_field::BitField;
 use goblin::elf64::program_header::ProgramHeader;
 use x86_64::{
-    registers::control::{Cr3, Cr3Flags},
-    structures::paging::{self, OffsetPageTable, PageTable, PageTableIndex},
+    registers::control::{Cr3, Cr3Flags, Cr4, Cr4Flags},
+    structures::paging::{self, mapper::Translate, OffsetPageTable, PageTable, PageTableIndex},
 };
 
 pub(crate) fn is_canonical_virtual_address(virt_addr: usize) -> bool {
@@ -33,6 +36,23 @@ pub(crate) const fn canonicalize_physical_address(phys_addr: usize) -> usize {
     phys_addr & 0x000F_FFFF_FFFF_FFFF
 }
 
+/// The number of levels in the kernel's page tables.
+pub(crate) const fn page_table_levels() -> u8 {
+    4
+}
+
+/// Panics if the firmware enabled 5-level paging.
+///
+/// The paging mode can't be changed while paging is enabled, so the kernel's
+/// page tables must have as many levels as the firmware's. Only 4-level paging
+/// is supported.
+fn assert_4_level_paging() {
+    assert!(
+        !Cr4::read().contains(Cr4Flags::L5_PAGING),
+        "firmware enabled 5-level paging (LA57), but only 4-level paging is supported"
+    );
+}
+
 pub(crate) fn set_up_arch_specific_mappings(context: &mut RuntimeContext) {
     let p4_frame = paging::PhysFrame::from_start_address(x86_64::PhysAddr::new(
         context.mapper.inner.level_4_table() as *const _ as u64,
@@ -170,7 +190,7 @@ impl PageAllocator {
 
     pub(crate) fn get_free_address(&mut self, len: usize) -> VirtualAddress {
         const LEVEL_4_SIZE: usize = 4096 * 512 * 512 * 512;
-        let num_level_4_entries = (len + (LEVEL_4_SIZE - 1)) / LEVEL_4_SIZE;
+        let num_level_4_entries = (len + GUARD_SIZE + (LEVEL_4_SIZE - 1)) / LEVEL_4_SIZE;
 
         // This is technically a 512 GiB page.
         paging::Page::from_page_table_indices_1gib(
@@ -182,11 +202,20 @@ impl PageAllocator {
     }
 
     pub(crate) fn mark_segment_as_used(&mut self, segment: &ProgramHeader) {
-        let start = VirtualAddress::new_canonical(segment.p_vaddr as usize);
-        let end_inclusive = (start + segment.p_memsz as usize) - 1;
+        self.mark_range_as_used(
+            VirtualAddress::new_canonical(segment.p_vaddr as usize),
+            segment.p_memsz as usize,
+        );
+    }
+
+    /// Prevents the P4 entries covering the given range from being returned
+    /// by [`get_free_address`](Self::get_free_address).
+    pub(crate) fn mark_range_as_used(&mut self, start: VirtualAddress, len: usize) {
+        let end_inclusive = (start + len) - 1;
 
         let start_page = Page::containing_address(start);
-        let end_page_inclusive = Page::containing_address(end_inclusive);
+        // The guard after the range is reserved too.
+        let end_page_inclusive = Page::containing_address(end_inclusive) + GUARD_SIZE / PAGE_SIZE;
 
         for p4_index in start_page.p4_index()..=end_page_inclusive.p4_index() {
             self.level_4_entries[p4_index] = true;
@@ -213,6 +242,8 @@ where
 
 pub(crate) struct Mapper {
     inner: OffsetPageTable<'static>,
+    /// The number of frames allocated for page tables.
+    page_table_frames: usize,
 }
 
 impl Mapper {
@@ -220,6 +251,8 @@ impl Mapper {
     where
         T: FrameAllocator,
     {
+        assert_4_level_paging();
+
         let frame = frame_allocator
             .allocate_frame()
             .expect("failed to allocate frame for page table");
@@ -232,6 +265,7 @@ impl Mapper {
         Self {
             // SAFETY: The physical offset is zero.
             inner: unsafe { OffsetPageTable::new(level_4_table, x86_64::VirtAddr::zero()) },
+            page_table_frames: 1,
         }
     }
 
@@ -239,6 +273,8 @@ impl Mapper {
     where
         T: FrameAllocator,
     {
+        assert_4_level_paging();
+
         // We copy the old table as some loaders mark the top-level page table as
         // read-only.
         let old_table = {
@@ -267,6 +303,7 @@ impl Mapper {
         Self {
             // SAFETY: The physical offset is zero.
             inner: unsafe { OffsetPageTable::new(new_table, x86_64::VirtAddr::zero()) },
+            page_table_frames: 1,
         }
     }
 
@@ -277,6 +314,17 @@ impl Mapper {
             as usize))
     }
 
+    /// Returns the number of frames allocated for page tables.
+    pub(crate) fn page_table_frames(&self) -> usize {
+        self.page_table_frames
+    }
+
+    pub(crate) fn translate(&self, address: VirtualAddress) -> Option<PhysicalAddress> {
+        self.inner
+            .translate_addr(x86_64::VirtAddr::new(address.value() as u64))
+            .map(PhysicalAddress::from)
+    }
+
     pub(crate) fn map<T>(
         &mut self,
         page: Page,
@@ -294,7 +342,10 @@ impl Mapper {
                 frame.into(),
                 flags.into(),
                 &mut FrameAllocatorWrapper {
-                    inner: frame_allocator,
+                    inner: &mut CountingFrameAllocator {
+                        inner: frame_allocator,
+                        count: &mut self.page_table_frames,
+                    },
                 },
             )
         }
@@ -303,3 +354,42 @@ impl Mapper {
         .flush();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The size of the memory mapped by a P4 entry.
+    const LEVEL_4_SIZE: usize = 4096 * 512 * 512 * 512;
+
+    #[test]
+    fn free_addresses_are_followed_by_a_guard() {
+        let mut page_allocator = PageAllocator::new();
+        // The guard doesn't fit in the entry a full-sized region takes...
+        let address = page_allocator.get_free_address(LEVEL_4_SIZE);
+        assert_eq!(address.value(), LEVEL_4_SIZE);
+        let address = page_allocator.get_free_address(PAGE_SIZE);
+        assert_eq!(address.value(), 3 * LEVEL_4_SIZE);
+        // ...but does in the entry of a region it leaves room in.
+        let address = page_allocator.get_free_address(LEVEL_4_SIZE - GUARD_SIZE);
+        assert_eq!(address.value(), 4 * LEVEL_4_SIZE);
+        let address = page_allocator.get_free_address(PAGE_SIZE);
+        assert_eq!(address.value(), 5 * LEVEL_4_SIZE);
+    }
+
+    #[test]
+    fn used_ranges_are_followed_by_a_guard() {
+        let mut page_allocator = PageAllocator::new();
+        page_allocator
+            .mark_range_as_used(VirtualAddress::new_canonical(LEVEL_4_SIZE), LEVEL_4_SIZE);
+        let address = page_allocator.get_free_address(PAGE_SIZE);
+        assert_eq!(address.value(), 3 * LEVEL_4_SIZE);
+
+        page_allocator.mark_range_as_used(
+            VirtualAddress::new_canonical(4 * LEVEL_4_SIZE),
+            LEVEL_4_SIZE - GUARD_SIZE,
+        );
+        let address = page_allocator.get_free_address(PAGE_SIZE);
+        assert_eq!(address.value(), 5 * LEVEL_4_SIZE);
+    }
+}
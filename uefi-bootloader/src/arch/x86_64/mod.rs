@@ -1,23 +1,146 @@
-use crate::KernelContext;
+use crate::{config::Interrupts, KernelContext};
 use core::arch::asm;
+use uefi_bootloader_api::PagingFeatures;
+use x86_64::{
+    instructions::{interrupts, port::Port},
+    registers::{
+        control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+        model_specific::{Efer, EferFlags},
+    },
+};
 
 pub(crate) mod memory;
 
-pub(crate) fn pre_context_switch_actions() {}
+/// The ELF machine type of kernels for this architecture.
+pub(crate) const KERNEL_MACHINE: u16 = goblin::elf64::header::EM_X86_64;
+
+pub(crate) fn pre_context_switch_actions(config: Interrupts) {
+    if config.disable {
+        interrupts::disable();
+    }
+    if config.mask_pic {
+        mask_legacy_pics();
+    }
+}
+
+/// Returns the paging features currently enabled in the control registers.
+pub(crate) fn paging_features() -> PagingFeatures {
+    let cr0 = Cr0::read();
+    let cr4 = Cr4::read();
+    let efer = Efer::read();
+    PagingFeatures {
+        write_protect: cr0.contains(Cr0Flags::WRITE_PROTECT),
+        global_pages: cr4.contains(Cr4Flags::PAGE_GLOBAL),
+        physical_address_extension: cr4.contains(Cr4Flags::PHYSICAL_ADDRESS_EXTENSION),
+        no_execute: efer.contains(EferFlags::NO_EXECUTE_ENABLE),
+        five_level_paging: cr4.contains(Cr4Flags::L5_PAGING),
+        supervisor_mode_execution_prevention: cr4
+            .contains(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION),
+        supervisor_mode_access_prevention: cr4
+            .contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION),
+    }
+}
+
+/// Masks all interrupt lines of the primary and secondary 8259 PICs.
+fn mask_legacy_pics() {
+    // SAFETY: Writing to the data ports of the PICs only changes their interrupt
+    // masks.
+    unsafe {
+        Port::<u8>::new(0x21).write(0xff);
+        Port::<u8>::new(0xa1).write(0xff);
+    }
+}
+
+/// The frequency of the PIT's input clock in Hz.
+const PIT_FREQUENCY: usize = 1_193_182;
+
+/// Busy-waits for `ms` milliseconds using channel 2 of the PIT, which works
+/// without boot services and doesn't need calibrating like the TSC.
+///
+/// The PC speaker stays off, as only the gate of channel 2 is enabled.
+pub(crate) fn busy_wait(ms: usize) {
+    const TICKS_PER_MS: u16 = ((PIT_FREQUENCY + 500) / 1000) as u16;
+
+    let mut control = Port::<u8>::new(0x61);
+    let mut command = Port::<u8>::new(0x43);
+    let mut channel_2 = Port::<u8>::new(0x42);
+
+    // SAFETY: Channel 2 of the PIT is only connected to the PC speaker, which is
+    // kept disabled.
+    unsafe {
+        let original = control.read();
+        for _ in 0..ms {
+            // Disable the gate and the speaker, then program channel 2 to count down
+            // once (mode 0, low then high byte).
+            control.write(original & !0b11);
+            command.write(0b1011_0000);
+            channel_2.write(TICKS_PER_MS as u8);
+            channel_2.write((TICKS_PER_MS >> 8) as u8);
+            // Enabling the gate starts the count, and the output goes high when it
+            // reaches zero.
+            control.write((original & !0b10) | 0b01);
+            while control.read() & 0b10_0000 == 0 {
+                core::hint::spin_loop();
+            }
+        }
+        control.write(original);
+    }
+}
 
 // The function needs to take ownership of the context so that it remains valid
 // when we switch page tables.
+//
+// The page tables live in memory allocated from the firmware, which maps all
+// conventional memory as write-back, so the page table walker observes the
+// cache-coherent view of the tables and no cache flush (i.e. `wbinvd`) is
+// needed. The `mfence` ensures all page table stores are globally visible
+// before loading CR3.
+//
+// The kernel entry point is called like a SysV `extern "C"` function taking the
+// boot info as its only argument: at its first instruction, `rsp + 8` is
+// 16-byte aligned. As the entry point is jumped to rather than called, a null
+// return address is pushed to the 16-byte aligned stack top, which also marks
+// the end of the call chain for stack unwinders.
+//
+// So that the kernel doesn't inherit any firmware or bootloader state, all
+// general-purpose registers other than `rdi` (the boot info) and `rsp` are
+// zeroed. The entry point is pushed to the stack and returned to, so that it
+// doesn't have to be kept in a register.
 #[allow(clippy::needless_pass_by_value)]
 pub(crate) unsafe fn jump_to_kernel(context: KernelContext) -> ! {
+    debug_assert!(
+        context.stack_top.value() % 16 == 0,
+        "kernel stack top isn't 16-byte aligned"
+    );
+
     // SAFETY: The caller guarantees that the context switch function is
     // identity-mapped, the stack pointer is mapped in the new page table, and the
     // kernel entry point is correct.
     unsafe {
         asm!(
-            "mov cr3, {}; mov rsp, {}; jmp {}",
-            in(reg) context.page_table_frame.start_address().value(),
-            in(reg) context.stack_top.value(),
-            in(reg) context.entry_point.value(),
+            "mfence",
+            "mov cr3, rax",
+            "mov rsp, rcx",
+            "push 0",
+            "push rdx",
+            "xor eax, eax",
+            "xor ebx, ebx",
+            "xor ecx, ecx",
+            "xor edx, edx",
+            "xor esi, esi",
+            "xor ebp, ebp",
+            "xor r8d, r8d",
+            "xor r9d, r9d",
+            "xor r10d, r10d",
+            "xor r11d, r11d",
+            "xor r12d, r12d",
+            "xor r13d, r13d",
+            "xor r14d, r14d",
+            "xor r15d, r15d",
+            "ret",
+            in("rax") context.page_table_frame.start_address().value(),
+            in("rcx") context.stack_top.value(),
+            in("rdx") context.entry_point.value(),
             in("rdi") context.boot_info,
             options(noreturn),
         );
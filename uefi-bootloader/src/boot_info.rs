@@ -1,19 +1,60 @@
 use crate::{
-    arch::memory::Mapper,
+    arch::{
+        memory::{page_table_levels, Mapper},
+        paging_features,
+    },
+    boot_log::BOOT_LOG,
     context::RuntimeContext,
-    memory::{FrameAllocator, Page, PageRange, PteFlags},
+    kernel::LoadedKernel,
+    memory::{FrameAllocator, Page, PageRange, PteFlags, VirtualAddress, PAGE_SIZE},
+};
+use core::{
+    alloc::Layout,
+    mem::{self, MaybeUninit},
+    slice,
+};
+use log::debug;
+use uefi::table::boot::MemoryDescriptor;
+use uefi_bootloader_api::{
+    AnonymousRegion, BootInformation, BootLogo, Bytes, ConfigurationTable, ElfSection, Firmware,
+    FrameBuffer, HardwareDescription, KernelSegment, MemoryRegion, MemoryRegionKind, Module,
+    RawMemoryMap, RsdpAddresses, Stack,
 };
-use core::{alloc::Layout, mem::MaybeUninit, slice};
-use uefi_bootloader_api::{BootInformation, ElfSection, FrameBuffer, MemoryRegion, Module};
 
 impl RuntimeContext {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn create_boot_info(
         mut self,
         frame_buffer: Option<FrameBuffer>,
-        rsdp_address: Option<usize>,
+        rsdp_addresses: RsdpAddresses,
+        device_tree_address: Option<usize>,
+        cpu_count: Option<usize>,
+        firmware: Firmware,
         modules: &'static [Module],
-        elf_sections: &'static [ElfSection],
+        modules_address: usize,
+        kernel: LoadedKernel,
+        symbol_map: Option<&'static [u8]>,
+        command_line: Option<&'static [u8]>,
+        kernel_config: Option<Bytes>,
+        stack: Stack,
+        boot_logo: Option<BootLogo>,
+        configuration_tables: &'static [ConfigurationTable],
+        secure_boot_enabled: bool,
     ) -> &'static BootInformation {
+        let elf_sections = kernel.elf_sections;
+        let kernel_segments = kernel.segments;
+        let hardware_description = self.config.hardware_description;
+        let rsdp_addresses = match hardware_description {
+            HardwareDescription::DeviceTree => RsdpAddresses {
+                acpi1: None,
+                acpi2: None,
+            },
+            HardwareDescription::All | HardwareDescription::Acpi => rsdp_addresses,
+        };
+        let device_tree_address = match hardware_description {
+            HardwareDescription::Acpi => None,
+            HardwareDescription::All | HardwareDescription::DeviceTree => device_tree_address,
+        };
         let boot_info_layout = Layout::new::<BootInformation>();
 
         let memory_regions_count = self.frame_allocator.len();
@@ -35,6 +76,60 @@ impl RuntimeContext {
             .extend(elf_sections_layout)
             .expect("failed to extend boot info layout with elf sections");
 
+        let kernel_segments_layout = Layout::array::<KernelSegment>(kernel_segments.len())
+            .expect("failed to create kernel segments layout");
+        let (combined, kernel_segments_offset) = combined
+            .extend(kernel_segments_layout)
+            .expect("failed to extend boot info layout with kernel segments");
+
+        // Nothing is logged until the boot log has been copied, so the length doesn't
+        // change.
+        let boot_log_len = BOOT_LOG.lock().as_bytes().len();
+        let boot_log_layout =
+            Layout::array::<u8>(boot_log_len).expect("failed to create boot log layout");
+        let (combined, boot_log_offset) = combined
+            .extend(boot_log_layout)
+            .expect("failed to extend boot info layout with boot log");
+
+        let symbol_map_len = symbol_map.map_or(0, <[u8]>::len);
+        let symbol_map_layout =
+            Layout::array::<u8>(symbol_map_len).expect("failed to create symbol map layout");
+        let (combined, symbol_map_offset) = combined
+            .extend(symbol_map_layout)
+            .expect("failed to extend boot info layout with symbol map");
+
+        let command_line_len = command_line.map_or(0, <[u8]>::len);
+        let command_line_layout =
+            Layout::array::<u8>(command_line_len).expect("failed to create command line layout");
+        let (combined, command_line_offset) = combined
+            .extend(command_line_layout)
+            .expect("failed to extend boot info layout with command line");
+
+        let anonymous_regions_count = self.config.anonymous_regions().count();
+        let anonymous_regions_layout = Layout::array::<AnonymousRegion>(anonymous_regions_count)
+            .expect("failed to create anonymous regions layout");
+        let (combined, anonymous_regions_offset) = combined
+            .extend(anonymous_regions_layout)
+            .expect("failed to extend boot info layout with anonymous regions");
+
+        let configuration_tables_layout =
+            Layout::array::<ConfigurationTable>(configuration_tables.len())
+                .expect("failed to create configuration tables layout");
+        let (combined, configuration_tables_offset) = combined
+            .extend(configuration_tables_layout)
+            .expect("failed to extend boot info layout with configuration tables");
+
+        let raw_memory_map_count = if self.config.raw_memory_map {
+            self.frame_allocator.descriptors().count()
+        } else {
+            0
+        };
+        let raw_memory_map_layout = Layout::array::<MemoryDescriptor>(raw_memory_map_count)
+            .expect("failed to create raw memory map layout");
+        let (combined, raw_memory_map_offset) = combined
+            .extend(raw_memory_map_layout)
+            .expect("failed to extend boot info layout with raw memory map");
+
         let boot_info_address = self.page_allocator.get_free_address(combined.size());
 
         let pages = PageRange::new(
@@ -58,6 +153,13 @@ impl RuntimeContext {
         let memory_map_regions_address = boot_info_address + memory_regions_offset;
         let modules_address = boot_info_address + modules_offset;
         let elf_sections_address = boot_info_address + elf_sections_offset;
+        let kernel_segments_address = boot_info_address + kernel_segments_offset;
+        let boot_log_address = boot_info_address + boot_log_offset;
+        let symbol_map_address = boot_info_address + symbol_map_offset;
+        let command_line_address = boot_info_address + command_line_offset;
+        let anonymous_regions_address = boot_info_address + anonymous_regions_offset;
+        let configuration_tables_address = boot_info_address + configuration_tables_offset;
+        let raw_memory_map_address = boot_info_address + raw_memory_map_offset;
 
         let uninit_boot_info: &'static mut MaybeUninit<BootInformation> =
             // SAFETY: We allocated it.
@@ -76,23 +178,275 @@ impl RuntimeContext {
         let uninit_elf_sections: &'static mut [MaybeUninit<ElfSection>] = unsafe {
             slice::from_raw_parts_mut(elf_sections_address.value() as *mut _, elf_sections.len())
         };
+        // SAFETY: We allocated it.
+        let uninit_kernel_segments: &'static mut [MaybeUninit<KernelSegment>] = unsafe {
+            slice::from_raw_parts_mut(
+                kernel_segments_address.value() as *mut _,
+                kernel_segments.len(),
+            )
+        };
+        let uninit_boot_log: &'static mut [MaybeUninit<u8>] =
+            // SAFETY: We allocated it.
+            unsafe { slice::from_raw_parts_mut(boot_log_address.value() as *mut _, boot_log_len) };
+        // SAFETY: We allocated it.
+        let uninit_symbol_map: &'static mut [MaybeUninit<u8>] = unsafe {
+            slice::from_raw_parts_mut(symbol_map_address.value() as *mut _, symbol_map_len)
+        };
+        // SAFETY: We allocated it.
+        let uninit_command_line: &'static mut [MaybeUninit<u8>] = unsafe {
+            slice::from_raw_parts_mut(command_line_address.value() as *mut _, command_line_len)
+        };
+        // SAFETY: We allocated it.
+        let uninit_anonymous_regions: &'static mut [MaybeUninit<AnonymousRegion>] = unsafe {
+            slice::from_raw_parts_mut(
+                anonymous_regions_address.value() as *mut _,
+                anonymous_regions_count,
+            )
+        };
+        // SAFETY: We allocated it.
+        let uninit_configuration_tables: &'static mut [MaybeUninit<ConfigurationTable>] = unsafe {
+            slice::from_raw_parts_mut(
+                configuration_tables_address.value() as *mut _,
+                configuration_tables.len(),
+            )
+        };
+        // SAFETY: We allocated it.
+        let uninit_raw_memory_map: &'static mut [MaybeUninit<MemoryDescriptor>] = unsafe {
+            slice::from_raw_parts_mut(
+                raw_memory_map_address.value() as *mut _,
+                raw_memory_map_count,
+            )
+        };
 
-        let memory_regions = self
+        let memory_regions: &'static [MemoryRegion] = self
             .frame_allocator
-            .construct_memory_map(uninit_memory_regions)
-            .into();
+            .construct_memory_map(uninit_memory_regions);
+        let max_usable_physical_address =
+            max_end(memory_regions, |kind| kind == MemoryRegionKind::Usable);
+        let max_physical_address = max_end(memory_regions, |_| true);
         let modules = MaybeUninit::write_slice(uninit_modules, modules).into();
         let elf_sections = MaybeUninit::write_slice(uninit_elf_sections, elf_sections).into();
+        let kernel_segments =
+            MaybeUninit::write_slice(uninit_kernel_segments, kernel_segments).into();
+        let configuration_tables =
+            MaybeUninit::write_slice(uninit_configuration_tables, configuration_tables).into();
+        let boot_log: &'static [u8] =
+            MaybeUninit::write_slice(uninit_boot_log, &BOOT_LOG.lock().as_bytes()[..boot_log_len]);
+        // Logging before this point could make the boot log drop lines, and with
+        // them the bytes measured above.
+        if self.config.map_verbose {
+            debug!(
+                "mapped boot info: {boot_info_address:?}..{:?}",
+                boot_info_address + combined.size()
+            );
+        }
+        let symbol_map = symbol_map.map(|symbol_map| {
+            let symbol_map: &'static [u8] = MaybeUninit::write_slice(uninit_symbol_map, symbol_map);
+            symbol_map.into()
+        });
+        let command_line = command_line.map(|command_line| {
+            let command_line: &'static [u8] =
+                MaybeUninit::write_slice(uninit_command_line, command_line);
+            command_line.into()
+        });
+
+        for (uninit_region, (start, size)) in uninit_anonymous_regions
+            .iter_mut()
+            .zip(self.config.anonymous_regions())
+        {
+            uninit_region.write(AnonymousRegion {
+                start,
+                len: size.next_multiple_of(PAGE_SIZE),
+            });
+        }
+        // SAFETY: We initialised all the regions.
+        let anonymous_regions =
+            unsafe { MaybeUninit::slice_assume_init_mut(uninit_anonymous_regions) };
 
-        uninit_boot_info.write({
+        // The descriptors are copied without whatever padding the firmware's
+        // descriptor size included.
+        let raw_memory_map = self.config.raw_memory_map.then(|| {
+            for (uninit_descriptor, descriptor) in uninit_raw_memory_map
+                .iter_mut()
+                .zip(self.frame_allocator.descriptors())
+            {
+                uninit_descriptor.write(*descriptor);
+            }
+            let descriptor_size = mem::size_of::<MemoryDescriptor>();
+            RawMemoryMap {
+                // SAFETY: We initialised all the descriptors, which are plain data.
+                descriptors: unsafe {
+                    Bytes::from_raw_parts(
+                        uninit_raw_memory_map.as_ptr().cast(),
+                        raw_memory_map_count * descriptor_size,
+                    )
+                },
+                descriptor_size,
+                descriptor_version: MemoryDescriptor::VERSION,
+            }
+        });
+
+        let boot_info = uninit_boot_info.write({
             BootInformation {
                 size: combined.size(),
                 frame_buffer,
-                rsdp_address,
-                memory_regions,
+                rsdp_address: rsdp_addresses.preferred(),
+                rsdp_addresses,
+                device_tree_address,
+                hardware_description,
+                cpu_count,
+                firmware,
+                page_table_levels: page_table_levels(),
+                page_size: PAGE_SIZE,
+                // Mappers only map pages of `PAGE_SIZE`.
+                large_page_sizes: 0,
+                memory_regions: memory_regions.into(),
+                max_usable_physical_address,
+                max_physical_address,
                 modules,
+                modules_address,
                 elf_sections,
+                kernel_segments,
+                kernel_crc32: kernel.crc32,
+                entry_point: kernel.entry_point.value(),
+                physical_entry_point: kernel.physical_entry_point.map(|address| address.value()),
+                boot_log: boot_log.into(),
+                symbol_map,
+                command_line,
+                kernel_config,
+                stack,
+                boot_logo,
+                anonymous_regions: anonymous_regions.into(),
+                paging_features: paging_features(),
+                configuration_tables,
+                boot_services: self.boot_services,
+                raw_memory_map,
+                secure_boot_enabled,
             }
-        })
+        });
+
+        assert_mapped(
+            &self.mapper,
+            "boot info",
+            boot_info as *const _ as usize,
+            mem::size_of::<BootInformation>(),
+        );
+        assert_mapped(
+            &self.mapper,
+            "memory regions",
+            boot_info.memory_regions.as_ptr() as usize,
+            mem::size_of_val(&*boot_info.memory_regions),
+        );
+        assert_mapped(
+            &self.mapper,
+            "modules",
+            boot_info.modules.as_ptr() as usize,
+            mem::size_of_val(&*boot_info.modules),
+        );
+        assert_mapped(
+            &self.mapper,
+            "elf sections",
+            boot_info.elf_sections.as_ptr() as usize,
+            mem::size_of_val(&*boot_info.elf_sections),
+        );
+        assert_mapped(
+            &self.mapper,
+            "kernel segments",
+            boot_info.kernel_segments.as_ptr() as usize,
+            mem::size_of_val(&*boot_info.kernel_segments),
+        );
+        assert_mapped(
+            &self.mapper,
+            "boot log",
+            boot_info.boot_log.as_ptr() as usize,
+            boot_info.boot_log.len(),
+        );
+        assert_mapped(
+            &self.mapper,
+            "anonymous regions",
+            boot_info.anonymous_regions.as_ptr() as usize,
+            mem::size_of_val(&*boot_info.anonymous_regions),
+        );
+        assert_mapped(
+            &self.mapper,
+            "configuration tables",
+            boot_info.configuration_tables.as_ptr() as usize,
+            mem::size_of_val(&*boot_info.configuration_tables),
+        );
+        if let Some(raw_memory_map) = boot_info.raw_memory_map {
+            assert_mapped(
+                &self.mapper,
+                "raw memory map",
+                raw_memory_map.descriptors.as_ptr() as usize,
+                raw_memory_map.descriptors.len(),
+            );
+        }
+        if let Some(frame_buffer) = boot_info.frame_buffer {
+            assert_mapped(
+                &self.mapper,
+                "framebuffer",
+                frame_buffer.virtual_start,
+                frame_buffer.info.size,
+            );
+        }
+        for region in boot_info.anonymous_regions.iter() {
+            assert_mapped(&self.mapper, "anonymous region", region.start, region.len);
+        }
+        if let Some(kernel_config) = boot_info.kernel_config {
+            assert_mapped(
+                &self.mapper,
+                "kernel config",
+                kernel_config.as_ptr() as usize,
+                kernel_config.len(),
+            );
+        }
+        if let Some(command_line) = boot_info.command_line {
+            assert_mapped(
+                &self.mapper,
+                "command line",
+                command_line.as_ptr() as usize,
+                command_line.len(),
+            );
+        }
+        if let Some(symbol_map) = boot_info.symbol_map {
+            assert_mapped(
+                &self.mapper,
+                "symbol map",
+                symbol_map.as_ptr() as usize,
+                symbol_map.len(),
+            );
+        }
+
+        boot_info
+    }
+}
+
+/// Returns the address after the highest byte of the regions whose kind
+/// matches `filter`, or zero if there are none.
+fn max_end<F>(regions: &[MemoryRegion], filter: F) -> usize
+where
+    F: Fn(MemoryRegionKind) -> bool,
+{
+    regions
+        .iter()
+        .filter(|region| filter(region.kind))
+        .map(|region| region.start + region.len)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Panics if any byte of the given range isn't mapped by `mapper`.
+fn assert_mapped(mapper: &Mapper, name: &str, start: usize, len: usize) {
+    if len == 0 {
+        return;
+    }
+
+    let start = VirtualAddress::new_canonical(start);
+    for page in PageRange::from_virt_addr(start, len) {
+        assert!(
+            mapper.translate(page.start_address()).is_some(),
+            "{name} at {start:?} (length {len:#x}) isn't mapped in the kernel page table: \
+             {page:?} is unmapped"
+        );
     }
 }
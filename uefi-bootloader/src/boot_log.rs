@@ -0,0 +1,103 @@
+//! A copy of the bootloader's log, passed to the kernel so that it can display
+//! or persist messages that scrolled off the screen.
+//!
+//! If the `boot_log_file` config key is set, the log is also appended to
+//! [`FILE_PATH`] on the boot volume right before exiting boot services.
+
+use crate::{fs, BootContext};
+use core::fmt::{self, Write};
+use log::{info, warn};
+use spin::Mutex;
+use uefi::{
+    cstr16,
+    proto::media::file::{File, FileAttribute, FileMode, RegularFile},
+};
+
+/// The path of the file the boot log is appended to.
+const FILE_PATH: &str = "boot\\bootlog.txt";
+
+/// The maximum size of the boot log in bytes.
+///
+/// Once full, the oldest lines are discarded to make space for new ones.
+const CAPACITY: usize = 16 * 1024;
+
+/// The global boot log.
+pub(crate) static BOOT_LOG: Mutex<BootLog> = Mutex::new(BootLog {
+    buf: [0; CAPACITY],
+    len: 0,
+});
+
+/// A buffer of the most recent log lines.
+pub(crate) struct BootLog {
+    buf: [u8; CAPACITY],
+    len: usize,
+}
+
+impl BootLog {
+    /// The text of the boot log.
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    /// Discards the oldest lines until at least `len` bytes are free.
+    fn make_space(&mut self, len: usize) {
+        let mut start = 0;
+        while CAPACITY - (self.len - start) < len && start < self.len {
+            start += self.buf[start..self.len]
+                .iter()
+                .position(|byte| *byte == b'\n')
+                .map_or(self.len - start, |idx| idx + 1);
+        }
+
+        self.buf.copy_within(start..self.len, 0);
+        self.len -= start;
+    }
+}
+
+impl BootContext {
+    /// Appends the boot log to [`FILE_PATH`], if enabled in the config.
+    ///
+    /// Failing to write the log doesn't abort the boot.
+    pub(crate) fn write_boot_log_file(&self) {
+        if !self.config.boot_log_file {
+            return;
+        }
+        match self.append_boot_log() {
+            Some(()) => info!("appended boot log to {FILE_PATH}"),
+            None => warn!("failed to append boot log to {FILE_PATH}"),
+        }
+    }
+
+    fn append_boot_log(&self) -> Option<()> {
+        let mut root = self.open_file_system_root()?;
+        let mut dir = fs::open(&mut root, cstr16!("boot"))?.into_directory()?;
+        let mut file = dir
+            .open(
+                cstr16!("bootlog.txt"),
+                FileMode::CreateReadWrite,
+                FileAttribute::empty(),
+            )
+            .ok()?
+            .into_regular_file()?;
+        file.set_position(RegularFile::END_OF_FILE).ok()?;
+
+        let log = BOOT_LOG.lock();
+        file.write(log.as_bytes()).ok()?;
+        file.flush().ok()
+    }
+}
+
+impl Write for BootLog {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // Lines longer than the whole buffer are truncated.
+        let mut len = s.len().min(CAPACITY);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        self.make_space(len);
+        self.buf[self.len..(self.len + len)].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
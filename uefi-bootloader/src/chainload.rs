@@ -0,0 +1,85 @@
+//! Chainloading EFI applications, e.g. another bootloader or a kernel with an
+//! EFI stub, instead of loading an ELF kernel.
+//!
+//! The application is started through the firmware, so none of the kernel
+//! loading, mapping or boot info creation happens.
+
+use crate::BootContext;
+use log::info;
+use uefi::{
+    proto::{loaded_image::LoadedImage, media::file::FileHandle},
+    table::boot::{LoadImageSource, MemoryType},
+    Status,
+};
+
+/// The magic at the start of the DOS header of PE/COFF images.
+const PE_MAGIC: [u8; 2] = *b"MZ";
+
+impl BootContext {
+    /// Returns whether the file at `path` is a PE/COFF image.
+    pub(crate) fn is_pe_image(&self, path: &'static str) -> bool {
+        let Some(mut file) = self.open_path(path).and_then(FileHandle::into_regular_file) else {
+            return false;
+        };
+
+        let mut magic = [0; 2];
+        matches!(file.read(&mut magic), Ok(2)) && magic == PE_MAGIC
+    }
+
+    /// Loads and starts the EFI application at `path`, returning its exit
+    /// status.
+    ///
+    /// The application is passed the `chainload_options` config value as its
+    /// load options, encoded as a null-terminated UCS-2 string.
+    pub(crate) fn chainload(&self, path: &'static str) -> Status {
+        let file = self
+            .open_path(path)
+            .expect("failed to open chainloaded image");
+        let image = self
+            .read_file(file, MemoryType::LOADER_DATA)
+            .expect("failed to read chainloaded image");
+
+        let boot_services = self.system_table.boot_services();
+        let handle = boot_services
+            .load_image(
+                self.image_handle,
+                LoadImageSource::FromBuffer {
+                    buffer: image,
+                    file_path: None,
+                },
+            )
+            .expect("failed to load chainloaded image");
+
+        let options = self.encode_load_options(self.config.chainload_options);
+        {
+            let mut loaded_image = boot_services
+                .open_protocol_exclusive::<LoadedImage>(handle)
+                .expect("failed to open chainloaded image's loaded image protocol");
+            let size = u32::try_from(core::mem::size_of_val(options))
+                .expect("chainload options are too long");
+            // SAFETY: The options are never freed.
+            unsafe { loaded_image.set_load_options(options.as_ptr().cast(), size) };
+        }
+
+        if let Some(initrd) = self.config.chainload_initrd {
+            self.install_initrd(initrd);
+        }
+
+        info!("starting chainloaded image: {path}");
+        match boot_services.start_image(handle) {
+            Ok(()) => Status::SUCCESS,
+            Err(error) => error.status(),
+        }
+    }
+
+    /// Encodes `options` as a null-terminated UCS-2 string.
+    fn encode_load_options(&self, options: &str) -> &'static [u16] {
+        let len = options.encode_utf16().count() + 1;
+        let buffer = self.allocate_small_slice(len);
+        for (slot, c) in buffer.iter_mut().zip(options.encode_utf16().chain([0])) {
+            slot.write(c);
+        }
+        // SAFETY: We initialised all `len` elements.
+        unsafe { core::mem::MaybeUninit::slice_assume_init_ref(buffer) }
+    }
+}
@@ -0,0 +1,758 @@
+//! Bootloader configuration.
+//!
+//! The configuration is read from `\boot\bootloader.cfg`, followed by every
+//! `*.cfg` file in `\boot\bootloader.cfg.d\` in name order. Each line consists
+//! of a key and a value separated by whitespace. Empty lines and lines starting
+//! with `#` are ignored.
+//!
+//! Scalar keys set by a later line, in the same or a later file, override
+//! those set earlier, with a warning, whereas list keys (e.g. `module` and
+//! `optional_module`) are appended to.
+
+use crate::{
+    fs,
+    memory::PAGE_SIZE,
+    util::{decode_base64, decode_hex, DecodeError},
+    BootContext,
+};
+use core::mem::MaybeUninit;
+use log::{info, warn};
+use uefi::{
+    proto::media::file::{Directory, FileAttribute, FileHandle},
+    table::boot::MemoryType,
+    CStr16, Status,
+};
+use uefi_bootloader_api::HardwareDescription;
+
+const CONFIG_PATH: &str = "boot\\bootloader.cfg";
+const DROP_IN_DIRECTORY_PATH: &str = "boot\\bootloader.cfg.d";
+const DROP_IN_EXTENSION: &str = ".cfg";
+
+/// The default maximum size of the kernel, both as a file and in memory.
+const DEFAULT_MAX_KERNEL_SIZE: usize = 256 * 1024 * 1024;
+
+/// The default maximum number of modules loaded in total.
+const DEFAULT_MAX_LOADED_MODULES: usize = 4096;
+
+/// The maximum number of modules that can be specified in the configuration.
+const MAX_MODULES: usize = 64;
+
+/// The maximum number of anonymous regions that can be specified in the
+/// configuration.
+const MAX_ANONYMOUS_REGIONS: usize = 16;
+
+/// The maximum number of distinct scalar keys that can be set, which is more
+/// than there are.
+const MAX_SCALAR_KEYS: usize = 64;
+
+/// The keys whose values are appended to a list rather than overridden.
+const LIST_KEYS: [&str; 3] = ["module", "optional_module", "anonymous_region"];
+
+/// The bootloader configuration.
+#[derive(Clone, Debug)]
+pub(crate) struct Config {
+    /// The path of the kernel, relative to the root of the boot volume unless
+    /// prefixed with another volume (see [`fs`]).
+    pub(crate) kernel: &'static str,
+    /// The maximum size of the kernel in bytes, both as a file and in memory.
+    pub(crate) max_kernel_size: usize,
+    /// The physical address all of the kernel's segments must be allocated
+    /// below, if any.
+    pub(crate) kernel_phys_max: Option<usize>,
+    /// The virtual address to enter the kernel at instead of the ELF entry
+    /// point. It must lie within an executable segment.
+    pub(crate) entry: Option<usize>,
+    /// The command line passed to the kernel, given by the `cmdline`,
+    /// `cmdline_hex` or `cmdline_base64` key.
+    pub(crate) command_line: Option<CommandLine>,
+    /// The paths of modules to load in addition to those in the `modules`
+    /// directory, each optionally followed by an `align=<alignment>` hint.
+    pub(crate) modules: List<MAX_MODULES>,
+    /// The paths of modules that are loaded if present and readable, but whose
+    /// absence doesn't abort the boot.
+    pub(crate) optional_modules: List<MAX_MODULES>,
+    /// The maximum number of modules loaded in total, from the `modules`
+    /// directory and the configuration.
+    pub(crate) max_modules: usize,
+    /// Virtual ranges, as `<address> <size>`, that are backed by zeroed frames
+    /// and mapped writable and no-execute in the kernel's address space.
+    pub(crate) anonymous_regions: List<MAX_ANONYMOUS_REGIONS>,
+    /// Whether prompts should immediately take their default action rather
+    /// than wait for input.
+    pub(crate) noninteractive: bool,
+    /// How long prompts wait for input before taking their default action, in
+    /// seconds.
+    pub(crate) timeout: usize,
+    /// Whether to wait for a key press, for at most the timeout, before
+    /// halting after a fatal error, and to reboot if a key is pressed.
+    pub(crate) pause_on_error: bool,
+    /// Whether to draw a spinner in the top right corner of the screen while
+    /// the kernel and modules are loaded.
+    pub(crate) spinner: bool,
+    /// Whether to compute the CRC-32 of the kernel file and pass it to the
+    /// kernel.
+    pub(crate) kernel_crc32: bool,
+    /// Whether to halt after preparing everything for the kernel instead of
+    /// entering it.
+    pub(crate) dry_run: bool,
+    /// The number of milliseconds to wait for right before jumping to the
+    /// kernel, e.g. to attach a debugger or read the final log output.
+    pub(crate) prejump_delay_ms: usize,
+    /// Whether to append the boot log to `boot\bootlog.txt` before exiting boot
+    /// services.
+    pub(crate) boot_log_file: bool,
+    /// Whether to exit boot services before entering the kernel. If not, the
+    /// kernel gets the UEFI system table and must exit boot services itself.
+    pub(crate) exit_boot_services: bool,
+    /// Whether to also identity-map the kernel's segments, and enter the
+    /// kernel at the physical address of its entry point.
+    pub(crate) identity_map_kernel: bool,
+    /// Whether to pass the UEFI memory map to the kernel in addition to the
+    /// memory regions derived from it.
+    pub(crate) raw_memory_map: bool,
+    /// Whether kernel segments that are both writable and executable are
+    /// mapped as such, rather than rejected.
+    ///
+    /// Pages that are writable and executable let any memory corruption in the
+    /// kernel inject code, so this should only be set for kernels that modify
+    /// their own code.
+    pub(crate) allow_wx: bool,
+    /// How many times a failed file read is retried before giving up.
+    pub(crate) read_retries: usize,
+    /// How long to wait before retrying a failed file read, in milliseconds.
+    pub(crate) read_retry_delay: usize,
+    /// Whether to log the details of every memory mapping at the debug level.
+    pub(crate) map_verbose: bool,
+    /// The number of bytes at the start of physical memory that aren't
+    /// allocated and are reported as reserved, in addition to the legacy VGA
+    /// hole.
+    pub(crate) low_memory_reserve: usize,
+    /// The number of unmapped pages below the kernel's stack.
+    pub(crate) stack_guard_pages: usize,
+    /// Whether to fill the lowest page of the kernel's stack with
+    /// `STACK_GUARD_PATTERN`.
+    pub(crate) stack_guard_pattern: bool,
+    /// The interrupt state the kernel is entered with.
+    pub(crate) interrupts: Interrupts,
+    /// A substring of the text form of the device path of the GOP to use,
+    /// e.g. `PciRoot(0x0)/Pci(0x2,0x0)`.
+    pub(crate) gop_device_path: Option<&'static str>,
+    /// The minimum number of bits per pixel of the GOP mode. If the current
+    /// mode is shallower, the first deep enough mode is set instead, and if
+    /// there is none the kernel gets no framebuffer.
+    pub(crate) min_bits_per_pixel: usize,
+    /// A substring of the text form of the device path of a second GOP that
+    /// log output is mirrored to, e.g. a dedicated debug display. The kernel
+    /// is only passed the primary framebuffer.
+    pub(crate) mirror_gop_device_path: Option<&'static str>,
+    /// Whether the logger clears only the line it moves to, continuing at the
+    /// top once it reaches the bottom, instead of clearing the whole screen
+    /// when it is full.
+    pub(crate) log_clear_lines: bool,
+    /// Whether the logger clears a framebuffer when it starts drawing to it.
+    ///
+    /// This only applies to the framebuffers switched to once the config is
+    /// loaded, i.e. one selected by `gop_device_path` or `min_bits_per_pixel`,
+    /// and the mirror. The framebuffer the firmware set up is always cleared,
+    /// as the logger starts drawing to it before the config is read.
+    pub(crate) log_clear_screen: bool,
+    /// The path of an EFI application to start instead of loading the kernel.
+    pub(crate) chainload: Option<&'static str>,
+    /// The load options passed to chainloaded EFI applications, e.g. a Linux
+    /// command line.
+    pub(crate) chainload_options: &'static str,
+    /// The path of an initrd served to chainloaded EFI-stub kernels through
+    /// Linux's LoadFile2 initrd protocol.
+    pub(crate) chainload_initrd: Option<&'static str>,
+    /// Which hardware descriptions are passed to the kernel: `acpi`, `dtb`, or
+    /// `all`, the default.
+    pub(crate) hardware_description: HardwareDescription,
+    /// The scalar keys that were set, to warn when one is overridden.
+    scalar_keys: List<MAX_SCALAR_KEYS>,
+}
+
+/// A kernel command line, as given in the config.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum CommandLine {
+    /// The command line as-is, with surrounding whitespace trimmed.
+    Plain(&'static str),
+    /// The hexadecimal encoding of the exact bytes of the command line.
+    Hex(&'static str),
+    /// The padded, standard base64 encoding of the exact bytes of the command
+    /// line.
+    Base64(&'static str),
+}
+
+impl CommandLine {
+    /// Calls `f` with each byte of the decoded command line.
+    fn decode<F>(self, f: F) -> Result<(), DecodeError>
+    where
+        F: FnMut(u8),
+    {
+        match self {
+            Self::Plain(value) => {
+                value.bytes().for_each(f);
+                Ok(())
+            }
+            Self::Hex(value) => decode_hex(value, f),
+            Self::Base64(value) => decode_base64(value, f),
+        }
+    }
+}
+
+/// The interrupt state the kernel is entered with.
+///
+/// Anything not covered here is left as the firmware set it.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Interrupts {
+    /// Whether to mask all lines of both legacy 8259 PICs on x86_64, so that
+    /// they can't fire into the kernel before it sets up an IDT.
+    pub(crate) mask_pic: bool,
+    /// Whether to disable interrupts on the boot processor, i.e. clear
+    /// `RFLAGS.IF` on x86_64 and mask `DAIF` on aarch64.
+    pub(crate) disable: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            kernel: "kernel.elf",
+            max_kernel_size: DEFAULT_MAX_KERNEL_SIZE,
+            kernel_phys_max: None,
+            entry: None,
+            command_line: None,
+            modules: List::new(),
+            optional_modules: List::new(),
+            max_modules: DEFAULT_MAX_LOADED_MODULES,
+            anonymous_regions: List::new(),
+            noninteractive: false,
+            timeout: 5,
+            pause_on_error: false,
+            spinner: false,
+            kernel_crc32: false,
+            dry_run: false,
+            prejump_delay_ms: 0,
+            boot_log_file: false,
+            exit_boot_services: true,
+            identity_map_kernel: false,
+            raw_memory_map: false,
+            allow_wx: false,
+            read_retries: 3,
+            read_retry_delay: 100,
+            map_verbose: false,
+            low_memory_reserve: 0,
+            stack_guard_pages: 1,
+            stack_guard_pattern: false,
+            gop_device_path: None,
+            min_bits_per_pixel: 0,
+            mirror_gop_device_path: None,
+            log_clear_lines: false,
+            log_clear_screen: true,
+            chainload: None,
+            chainload_options: "",
+            chainload_initrd: None,
+            hardware_description: HardwareDescription::All,
+            interrupts: Interrupts {
+                mask_pic: true,
+                disable: true,
+            },
+            scalar_keys: List::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Returns the path and alignment of each required module.
+    pub(crate) fn module_entries(&self) -> impl Iterator<Item = (&'static str, usize)> + '_ {
+        self.modules
+            .iter()
+            .map(|value| parse_module("module", value))
+    }
+
+    /// Returns the path and alignment of each optional module.
+    pub(crate) fn optional_module_entries(
+        &self,
+    ) -> impl Iterator<Item = (&'static str, usize)> + '_ {
+        self.optional_modules
+            .iter()
+            .map(|value| parse_module("optional_module", value))
+    }
+
+    /// Returns the start address and size of each anonymous region.
+    pub(crate) fn anonymous_regions(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.anonymous_regions
+            .iter()
+            .map(|value| parse_region("anonymous_region", value))
+    }
+
+    fn parse(&mut self, source: &'static str) {
+        for line in source.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once(char::is_whitespace)
+                .map_or((line, ""), |(key, value)| (key, value.trim()));
+
+            let mut known = true;
+            match key {
+                "kernel" => self.kernel = value,
+                "max_kernel_size" => self.max_kernel_size = parse_usize(key, value),
+                "max_modules" => self.max_modules = parse_usize(key, value),
+                "kernel_phys_max" => {
+                    let ceiling = parse_usize(key, value);
+                    assert!(
+                        ceiling % PAGE_SIZE == 0 && ceiling > 0,
+                        "invalid value for config key `{key}`: expected a non-zero, page-aligned \
+                         address"
+                    );
+                    self.kernel_phys_max = Some(ceiling);
+                }
+                "entry" => self.entry = Some(parse_usize(key, value)),
+                "cmdline" => self.command_line = Some(CommandLine::Plain(value)),
+                "cmdline_hex" => {
+                    self.command_line = Some(parse_command_line(key, CommandLine::Hex(value)));
+                }
+                "cmdline_base64" => {
+                    self.command_line = Some(parse_command_line(key, CommandLine::Base64(value)));
+                }
+                "module" => {
+                    parse_module(key, value);
+                    self.modules.push(key, value);
+                }
+                "optional_module" => {
+                    parse_module(key, value);
+                    self.optional_modules.push(key, value);
+                }
+                "anonymous_region" => {
+                    parse_region(key, value);
+                    self.anonymous_regions.push(key, value);
+                }
+                "noninteractive" => self.noninteractive = parse_bool(key, value),
+                "timeout" => self.timeout = parse_usize(key, value),
+                "pause_on_error" => self.pause_on_error = parse_bool(key, value),
+                "spinner" => self.spinner = parse_bool(key, value),
+                "kernel_crc32" => self.kernel_crc32 = parse_bool(key, value),
+                "dry_run" => self.dry_run = parse_bool(key, value),
+                "prejump_delay_ms" => self.prejump_delay_ms = parse_usize(key, value),
+                "boot_log_file" => self.boot_log_file = parse_bool(key, value),
+                "exit_boot_services" => self.exit_boot_services = parse_bool(key, value),
+                "identity_map_kernel" => self.identity_map_kernel = parse_bool(key, value),
+                "raw_memory_map" => self.raw_memory_map = parse_bool(key, value),
+                "allow_wx" => self.allow_wx = parse_bool(key, value),
+                "read_retries" => self.read_retries = parse_usize(key, value),
+                "read_retry_delay" => self.read_retry_delay = parse_usize(key, value),
+                "map_verbose" => self.map_verbose = parse_bool(key, value),
+                "gop_device_path" => self.gop_device_path = Some(value),
+                "min_bits_per_pixel" => self.min_bits_per_pixel = parse_usize(key, value),
+                "mirror_gop_device_path" => self.mirror_gop_device_path = Some(value),
+                "log_clear_lines" => self.log_clear_lines = parse_bool(key, value),
+                "log_clear_screen" => self.log_clear_screen = parse_bool(key, value),
+                "chainload" => self.chainload = Some(value),
+                "chainload_options" => self.chainload_options = value,
+                "chainload_initrd" => self.chainload_initrd = Some(value),
+                "hwdesc" => {
+                    self.hardware_description = match value {
+                        "all" => HardwareDescription::All,
+                        "acpi" => HardwareDescription::Acpi,
+                        "dtb" => HardwareDescription::DeviceTree,
+                        _ => panic!(
+                            "invalid value for config key `{key}`: expected `all`, `acpi` or `dtb`"
+                        ),
+                    }
+                }
+                "low_memory_reserve" => self.low_memory_reserve = parse_usize(key, value),
+                "stack_guard_pages" => self.stack_guard_pages = parse_usize(key, value),
+                "stack_guard_pattern" => self.stack_guard_pattern = parse_bool(key, value),
+                "mask_pic" => self.interrupts.mask_pic = parse_bool(key, value),
+                "disable_interrupts" => self.interrupts.disable = parse_bool(key, value),
+                _ => {
+                    known = false;
+                    warn!("ignoring unknown config key: {key}");
+                }
+            }
+            if known && !LIST_KEYS.contains(&key) {
+                self.note_scalar_key(key);
+            }
+        }
+    }
+
+    /// Records that the scalar `key` was set, warning if it was set before.
+    fn note_scalar_key(&mut self, key: &'static str) {
+        // All keys setting the command line override each other.
+        let key = if key.starts_with("cmdline") {
+            "cmdline"
+        } else {
+            key
+        };
+        if self.scalar_keys.iter().any(|set| set == key) {
+            warn!("config key `{key}` is set more than once, using the last value");
+        } else {
+            self.scalar_keys.push("scalar", key);
+        }
+    }
+}
+
+fn parse_bool(key: &str, value: &str) -> bool {
+    match value {
+        "true" => true,
+        "false" => false,
+        _ => panic!("invalid value for config key `{key}`: expected `true` or `false`"),
+    }
+}
+
+/// Checks that the encoded command line given by `key` can be decoded.
+fn parse_command_line(key: &str, command_line: CommandLine) -> CommandLine {
+    if let Err(error) = command_line.decode(|_| {}) {
+        panic!("invalid value for config key `{key}`: {error}");
+    }
+    command_line
+}
+
+/// Parses a decimal or `0x`-prefixed hexadecimal number.
+fn parse_usize(key: &str, value: &str) -> usize {
+    match value.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .unwrap_or_else(|_| panic!("invalid value for config key `{key}`: expected a number"))
+}
+
+/// Parses a module given as a path optionally followed by an `align=` hint,
+/// returning the path and the alignment, which is at least the page size.
+pub(crate) fn parse_module(key: &str, value: &'static str) -> (&'static str, usize) {
+    let Some((path, align)) = value
+        .rsplit_once(char::is_whitespace)
+        .and_then(|(path, hint)| Some((path.trim_end(), hint.strip_prefix("align=")?)))
+    else {
+        return (value, PAGE_SIZE);
+    };
+
+    let align = parse_usize(key, align);
+    assert!(
+        align.is_power_of_two(),
+        "invalid alignment for config key `{key}`: expected a power of two"
+    );
+    (path, align.max(PAGE_SIZE))
+}
+
+/// Parses a region given as an address and a size separated by whitespace.
+fn parse_region(key: &str, value: &str) -> (usize, usize) {
+    let (address, size) = value.split_once(char::is_whitespace).unwrap_or_else(|| {
+        panic!("invalid value for config key `{key}`: expected an address and a size")
+    });
+    (parse_usize(key, address), parse_usize(key, size.trim()))
+}
+
+/// A list of configuration values with a fixed capacity, as the bootloader
+/// doesn't have an allocator.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct List<const N: usize> {
+    values: [&'static str; N],
+    len: usize,
+}
+
+impl<const N: usize> List<N> {
+    const fn new() -> Self {
+        Self {
+            values: [""; N],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, key: &str, value: &'static str) {
+        assert!(self.len < N, "more than {N} `{key}` config entries");
+        self.values[self.len] = value;
+        self.len += 1;
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.values[..self.len].iter().copied()
+    }
+}
+
+impl BootContext {
+    /// Decodes the kernel command line given in the config, if any.
+    pub(crate) fn command_line(&self) -> Option<&'static [u8]> {
+        let command_line = self.config.command_line?;
+        let mut len = 0;
+        command_line
+            .decode(|_| len += 1)
+            .expect("command line was validated when parsing the config");
+
+        let buffer = self.allocate_small_slice(len);
+        let mut index = 0;
+        command_line
+            .decode(|byte| {
+                buffer[index].write(byte);
+                index += 1;
+            })
+            .expect("command line was validated when parsing the config");
+        // SAFETY: We initialised all `len` bytes.
+        Some(unsafe { MaybeUninit::slice_assume_init_ref(buffer) })
+    }
+
+    pub(crate) fn load_config(&mut self) {
+        let Some(mut root) = self.open_file_system_root() else {
+            // An embedded kernel can be booted without a file system.
+            assert!(
+                cfg!(feature = "embedded-kernel"),
+                "failed to open file system root"
+            );
+            info!("no file system found, using defaults");
+            return;
+        };
+
+        match fs::open_path(&mut root, CONFIG_PATH) {
+            Some(file) => {
+                info!("parsing {CONFIG_PATH}");
+                self.parse_config_file(file);
+            }
+            None => info!("no config file found, using defaults"),
+        }
+
+        if let Some(directory) =
+            fs::open_path(&mut root, DROP_IN_DIRECTORY_PATH).and_then(FileHandle::into_directory)
+        {
+            self.load_drop_ins(directory);
+        }
+    }
+
+    /// Parses all drop-in config files in `directory`, sorted by name.
+    fn load_drop_ins(&mut self, mut directory: Directory) {
+        // The directory can't be sorted without an allocator, so instead it is
+        // searched for the smallest name greater than the previously parsed one.
+        let mut previous: Option<Name> = None;
+        let mut buf = [0; fs::FILE_INFO_BUFFER_SIZE];
+
+        loop {
+            let mut next: Option<Name> = None;
+
+            directory
+                .reset_entry_readout()
+                .expect("failed to reset config drop-in directory entry readout");
+            loop {
+                let info = match directory.read_entry(&mut buf) {
+                    Ok(Some(info)) => info,
+                    Ok(None) => break,
+                    Err(error) if error.status() == Status::BUFFER_TOO_SMALL => {
+                        let size =
+                            (*error.data()).expect("firmware didn't report directory entry size");
+                        self.skip_entry(&mut directory, size);
+                        // The directory is read again after each drop-in, so only
+                        // warn the first time.
+                        if previous.is_none() {
+                            warn!("ignoring config drop-in directory entry with overlong name");
+                        }
+                        continue;
+                    }
+                    Err(error) => panic!(
+                        "failed to read config drop-in directory entry: {:?}",
+                        error.status()
+                    ),
+                };
+                let name = info.file_name().to_u16_slice();
+                if info.attribute().contains(FileAttribute::DIRECTORY)
+                    || !is_drop_in(fs::strip_version(name))
+                    || previous
+                        .as_ref()
+                        .map_or(false, |previous| name <= previous.as_slice())
+                    || next.as_ref().map_or(false, |next| name >= next.as_slice())
+                {
+                    continue;
+                }
+
+                match Name::new(name) {
+                    Some(name) => next = Some(name),
+                    None if previous.is_none() => {
+                        warn!("ignoring config drop-in with overlong name");
+                    }
+                    None => {}
+                }
+            }
+
+            let Some(name) = next else {
+                break;
+            };
+            let file = fs::open(&mut directory, name.as_c_str())
+                .expect("failed to open config drop-in file");
+            info!("parsing config drop-in {}", name.as_c_str());
+            self.parse_config_file(file);
+            previous = Some(name);
+        }
+    }
+
+    fn parse_config_file(&mut self, file: FileHandle) {
+        let bytes = self
+            .read_file(file, MemoryType::LOADER_DATA)
+            .expect("failed to read config file");
+        let source = core::str::from_utf8(bytes).expect("config file is not valid UTF-8");
+        self.config.parse(source);
+    }
+}
+
+fn is_drop_in(name: &[u16]) -> bool {
+    name.len() > DROP_IN_EXTENSION.len()
+        && name[(name.len() - DROP_IN_EXTENSION.len())..]
+            .iter()
+            .zip(DROP_IN_EXTENSION.bytes())
+            .all(|(a, b)| u8::try_from(*a).map_or(false, |a| a.eq_ignore_ascii_case(&b)))
+}
+
+/// A null-terminated UCS-2 file name.
+struct Name {
+    buf: [u16; 256],
+    len: usize,
+}
+
+impl Name {
+    fn new(name: &[u16]) -> Option<Self> {
+        let mut buf = [0; 256];
+        // The last element must remain zero as the null terminator.
+        if name.len() >= buf.len() {
+            return None;
+        }
+        buf[..name.len()].copy_from_slice(name);
+        Some(Self {
+            buf,
+            len: name.len(),
+        })
+    }
+
+    fn as_slice(&self) -> &[u16] {
+        &self.buf[..self.len]
+    }
+
+    fn as_c_str(&self) -> &CStr16 {
+        CStr16::from_u16_with_nul(&self.buf[..=self.len]).expect("invalid file name")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sets every scalar key once, with a valid value.
+    const EVERY_SCALAR_KEY: &str = "\
+        kernel kernel.elf
+        max_kernel_size 0x1000
+        max_modules 8
+        kernel_phys_max 0x40000000
+        entry 0x100000
+        cmdline quiet
+        noninteractive true
+        timeout 0
+        pause_on_error true
+        spinner true
+        kernel_crc32 true
+        dry_run true
+        prejump_delay_ms 10
+        boot_log_file true
+        exit_boot_services false
+        identity_map_kernel true
+        raw_memory_map true
+        allow_wx true
+        read_retries 1
+        read_retry_delay 1
+        map_verbose true
+        gop_device_path PciRoot(0x0)
+        min_bits_per_pixel 24
+        mirror_gop_device_path PciRoot(0x1)
+        log_clear_lines true
+        log_clear_screen false
+        chainload vmlinuz.efi
+        chainload_options console=ttyS0
+        chainload_initrd initrd.img
+        hwdesc acpi
+        low_memory_reserve 0x1000
+        stack_guard_pages 2
+        stack_guard_pattern true
+        mask_pic false
+        disable_interrupts false
+    ";
+
+    fn parse(source: &'static str) -> Config {
+        let mut config = Config::default();
+        config.parse(source);
+        config
+    }
+
+    fn scalar_keys(config: &Config) -> Vec<&'static str> {
+        config.scalar_keys.iter().collect()
+    }
+
+    #[test]
+    fn later_scalar_overrides_earlier() {
+        let config = parse("timeout 1\nspinner true\ntimeout 2\n");
+        assert_eq!(config.timeout, 2);
+        assert_eq!(scalar_keys(&config), ["timeout", "spinner"]);
+    }
+
+    #[test]
+    fn command_line_keys_override_each_other() {
+        let config = parse("cmdline quiet\ncmdline_hex 71756965740a\n");
+        assert!(matches!(
+            config.command_line,
+            Some(CommandLine::Hex("71756965740a"))
+        ));
+        assert_eq!(scalar_keys(&config), ["cmdline"]);
+
+        let config = parse("cmdline_base64 cXVpZXQ=\ncmdline quiet\n");
+        assert!(matches!(
+            config.command_line,
+            Some(CommandLine::Plain("quiet"))
+        ));
+        assert_eq!(scalar_keys(&config), ["cmdline"]);
+    }
+
+    #[test]
+    fn list_keys_accumulate() {
+        let config = parse(
+            "module a.bin\nmodule b.bin align=0x2000\noptional_module c.bin\nanonymous_region \
+             0x1000 0x2000\nanonymous_region 0x8000 0x1000\n",
+        );
+        assert_eq!(
+            config.module_entries().collect::<Vec<_>>(),
+            [("a.bin", PAGE_SIZE), ("b.bin", 0x2000)]
+        );
+        assert_eq!(
+            config.optional_module_entries().collect::<Vec<_>>(),
+            [("c.bin", PAGE_SIZE)]
+        );
+        assert_eq!(
+            config.anonymous_regions().collect::<Vec<_>>(),
+            [(0x1000, 0x2000), (0x8000, 0x1000)]
+        );
+        assert!(scalar_keys(&config).is_empty());
+    }
+
+    #[test]
+    fn comments_and_unknown_keys_are_ignored() {
+        let config = parse("# timeout 1\n\n  \nno_such_key 1\ntimeout 3\n");
+        assert_eq!(config.timeout, 3);
+        assert_eq!(scalar_keys(&config), ["timeout"]);
+    }
+
+    #[test]
+    fn every_scalar_key_fits() {
+        let mut config = parse(EVERY_SCALAR_KEY);
+        let count = EVERY_SCALAR_KEY.trim().lines().count();
+        assert!(count <= MAX_SCALAR_KEYS);
+        assert_eq!(scalar_keys(&config).len(), count);
+
+        // Setting them all again only warns.
+        config.parse(EVERY_SCALAR_KEY);
+        assert_eq!(scalar_keys(&config).len(), count);
+    }
+
+    #[test]
+    #[should_panic(expected = "more than 2 `module` config entries")]
+    fn full_list_panics() {
+        let mut list = List::<2>::new();
+        for value in ["a", "b", "c"] {
+            list.push("module", value);
+        }
+    }
+}
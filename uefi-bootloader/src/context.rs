@@ -1,12 +1,23 @@
 use crate::{
+    config::Config,
+    fs::MAX_CACHED_VOLUMES,
+    mappings::MappingStatistics,
     memory::{
         Frame, FrameRange, LegacyFrameAllocator, Mapper, Page, PageAllocator, PageRange,
-        PhysicalAddress, PteFlags, UefiFrameAllocator, VirtualAddress, KERNEL_MEMORY,
+        PhysicalAddress, PteFlags, UefiFrameAllocator, VirtualAddress, KERNEL_IMAGE_MEMORY,
+        PAGE_SIZE,
     },
+    phase,
     util::calculate_pages,
 };
-use core::mem::MaybeUninit;
+use core::{
+    alloc::Layout,
+    cell::{Cell, RefCell},
+    fmt,
+    mem::MaybeUninit,
+};
 use goblin::elf64::program_header::ProgramHeader;
+use log::{debug, info, warn};
 use uefi::{
     proto::{
         device_path::DevicePath,
@@ -14,11 +25,51 @@ use uefi::{
         media::{file::Directory, fs::SimpleFileSystem},
     },
     table::{
-        boot::{AllocateType, MemoryMapSize, MemoryType},
+        boot::{AllocateType, Event, MemoryMapSize, MemoryType},
         Boot, SystemTable,
     },
     Handle,
 };
+use uefi_bootloader_api::BootServices;
+
+/// The size of each arena that small loader data is sub-allocated from.
+const ARENA_SIZE: usize = 16 * PAGE_SIZE;
+
+/// The memory backing a kernel segment couldn't be allocated.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct SegmentAllocationError {
+    /// The virtual address of the segment.
+    pub(crate) virtual_address: usize,
+    /// The number of bytes that couldn't be allocated.
+    pub(crate) size: usize,
+    /// The address the allocation had to be below, if any.
+    pub(crate) ceiling: Option<usize>,
+    /// The physical address the allocation had to be at, if the kernel is
+    /// identity-linked.
+    pub(crate) physical_address: Option<usize>,
+}
+
+impl fmt::Display for SegmentAllocationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.physical_address {
+            Some(physical_address) => write!(
+                f,
+                "failed to allocate {:#x} bytes at {physical_address:#x} for the identity-linked \
+                 kernel segment at {:#x}",
+                self.size, self.virtual_address
+            )?,
+            None => write!(
+                f,
+                "out of memory: failed to allocate {:#x} bytes for the kernel segment at {:#x}",
+                self.size, self.virtual_address
+            )?,
+        }
+        if let Some(ceiling) = self.ceiling {
+            write!(f, " below {ceiling:#x}")?;
+        }
+        Ok(())
+    }
+}
 
 /// Bootloader context before extiting boot services.
 pub(crate) struct BootContext {
@@ -26,6 +77,23 @@ pub(crate) struct BootContext {
     pub(crate) system_table: SystemTable<Boot>,
     pub(crate) page_allocator: PageAllocator,
     pub(crate) mapper: Mapper,
+    pub(crate) config: Config,
+    /// The number of page allocations made through the context.
+    allocations: Cell<usize>,
+    /// The number of bytes allocated through the context, in whole pages.
+    allocated_bytes: Cell<usize>,
+    /// The number of file reads made through
+    /// [`read_retrying`](Self::read_retrying), including retries.
+    pub(crate) file_reads: Cell<usize>,
+    /// The number of bytes read from files.
+    pub(crate) bytes_read: Cell<usize>,
+    /// The unused part of the current arena.
+    arena: Cell<&'static mut [u8]>,
+    /// The handles of the volumes that configured paths were prefixed with.
+    pub(crate) volumes: RefCell<[Option<(&'static str, Handle)>; MAX_CACHED_VOLUMES]>,
+    pub(crate) mapping_statistics: MappingStatistics,
+    /// The timer event that advances the spinner, if it is running.
+    pub(crate) spinner: Option<Event>,
 }
 
 impl BootContext {
@@ -40,6 +108,15 @@ impl BootContext {
             system_table,
             page_allocator: PageAllocator::new(),
             mapper,
+            config: Config::default(),
+            allocations: Cell::new(0),
+            allocated_bytes: Cell::new(0),
+            file_reads: Cell::new(0),
+            bytes_read: Cell::new(0),
+            arena: Cell::new(&mut []),
+            volumes: RefCell::new([None; MAX_CACHED_VOLUMES]),
+            mapping_statistics: MappingStatistics::default(),
+            spinner: None,
         }
     }
 
@@ -49,12 +126,25 @@ impl BootContext {
         let loaded_image = boot_services
             .open_protocol_exclusive::<LoadedImage>(self.image_handle)
             .ok()?;
+        let device = loaded_image.device();
         let device_path = boot_services
-            .open_protocol_exclusive::<DevicePath>(loaded_image.device())
-            .ok()?;
-        let device_handle = boot_services
-            .locate_device_path::<SimpleFileSystem>(&mut &*device_path)
+            .open_protocol_exclusive::<DevicePath>(device)
             .ok()?;
+        let device_handle =
+            match boot_services.locate_device_path::<SimpleFileSystem>(&mut &*device_path) {
+                Ok(device_handle) => device_handle,
+                Err(_) => {
+                    // Some firmware only installs the file system protocol once the boot
+                    // device's controllers have been connected.
+                    info!("no file system found on boot device, connecting controllers");
+                    if let Err(error) = boot_services.connect_controller(device, None, None, true) {
+                        warn!("failed to connect boot device controllers: {error:?}");
+                    }
+                    boot_services
+                        .locate_device_path::<SimpleFileSystem>(&mut &*device_path)
+                        .ok()?
+                }
+            };
         boot_services
             .open_protocol_exclusive::<SimpleFileSystem>(device_handle)
             .ok()?
@@ -66,12 +156,17 @@ impl BootContext {
         &self.system_table
     }
 
-    fn allocate_slice_inner<T>(
+    fn try_allocate_slice_inner<T>(
         &self,
         len: usize,
         allocate_type: AllocateType,
         memory_type: MemoryType,
-    ) -> &'static mut [MaybeUninit<T>] {
+    ) -> Option<&'static mut [MaybeUninit<T>]> {
+        // The firmware can't allocate zero pages.
+        if len == 0 {
+            return Some(&mut []);
+        }
+
         let bytes_len = core::mem::size_of::<T>() * len;
         let num_pages = calculate_pages(bytes_len);
         let pointer = self
@@ -79,12 +174,25 @@ impl BootContext {
             .boot_services()
             // TODO: Allocate pool?
             .allocate_pages(allocate_type, memory_type, num_pages)
-            .expect("failed to allocate pages for slice") as *mut _;
+            .ok()? as *mut _;
+        self.allocations.set(self.allocations.get() + 1);
+        self.allocated_bytes
+            .set(self.allocated_bytes.get() + num_pages * PAGE_SIZE);
         // SAFETY: We just allocated the memory at `pointer`.
         unsafe { core::ptr::write_bytes(pointer, 0, len) };
         // SAFETY: We just allocated the memory at `pointer`.
         let slice = unsafe { core::slice::from_raw_parts_mut(pointer, len) };
-        slice
+        Some(slice)
+    }
+
+    fn allocate_slice_inner<T>(
+        &self,
+        len: usize,
+        allocate_type: AllocateType,
+        memory_type: MemoryType,
+    ) -> &'static mut [MaybeUninit<T>] {
+        self.try_allocate_slice_inner(len, allocate_type, memory_type)
+            .expect("failed to allocate pages for slice")
     }
 
     pub(crate) fn allocate_slice<T>(
@@ -101,27 +209,117 @@ impl BootContext {
         unsafe { MaybeUninit::slice_assume_init_mut(slice) }
     }
 
-    pub(crate) fn map_segment(&mut self, segment: &ProgramHeader) -> &'static mut [u8] {
-        // x86_64 .init section
-        let slice = if segment.p_paddr == 0x10_0000 {
-            let maybe_uninit_slice = self.allocate_slice_inner(
-                segment.p_memsz as usize,
-                AllocateType::Address(0x10_0000),
-                KERNEL_MEMORY,
-            );
-            // SAFETY: allocate_slice_inner zeroed the bytes so they are initialised.
-            unsafe { MaybeUninit::slice_assume_init_mut(maybe_uninit_slice) }
+    /// Allocates a zeroed slice of loader data, sub-allocating small slices
+    /// from an arena.
+    ///
+    /// Each firmware allocation can add descriptors to the memory map, so
+    /// batching small allocations keeps the map from growing. Like any loader
+    /// data, the arenas are reported to the kernel as usable memory, so only
+    /// data that is copied or no longer needed once the kernel runs may be
+    /// allocated this way.
+    pub(crate) fn allocate_small_slice<T>(&self, len: usize) -> &'static mut [MaybeUninit<T>] {
+        let layout = Layout::array::<T>(len).expect("slice is too large");
+        if layout.size() > ARENA_SIZE / 4 {
+            return self.allocate_slice(len, MemoryType::LOADER_DATA);
+        }
+
+        let mut arena = self.arena.take();
+        let mut offset = arena.as_ptr().align_offset(layout.align());
+        if offset + layout.size() > arena.len() {
+            // The rest of the current arena is wasted.
+            arena = self.allocate_byte_slice(ARENA_SIZE, MemoryType::LOADER_DATA);
+            offset = 0;
+        }
+        let (allocation, rest) = arena[offset..].split_at_mut(layout.size());
+        self.arena.set(rest);
+
+        // SAFETY: The bytes are zeroed, aligned for `T`, and large enough to hold
+        // `len` elements.
+        unsafe { core::slice::from_raw_parts_mut(allocation.as_mut_ptr().cast(), len) }
+    }
+
+    /// Allocates a zeroed byte slice whose start is aligned to `align`, which
+    /// must be a power of two no smaller than the page size.
+    ///
+    /// The allocation is padded to reach the alignment, and the padding is
+    /// freed again, so that the memory of the given type starts at the aligned
+    /// address.
+    pub(crate) fn allocate_aligned_byte_slice(
+        &self,
+        len: usize,
+        align: usize,
+        ty: MemoryType,
+    ) -> &'static mut [u8] {
+        if align <= PAGE_SIZE || len == 0 {
+            return self.allocate_byte_slice(len, ty);
+        }
+
+        let boot_services = self.system_table.boot_services();
+        let num_pages = calculate_pages(len);
+        let padding_pages = align / PAGE_SIZE - 1;
+        let start = boot_services
+            .allocate_pages(AllocateType::AnyPages, ty, num_pages + padding_pages)
+            .expect("failed to allocate pages for aligned slice") as usize;
+        self.allocations.set(self.allocations.get() + 1);
+        self.allocated_bytes
+            .set(self.allocated_bytes.get() + num_pages * PAGE_SIZE);
+
+        let aligned_start = start.next_multiple_of(align);
+        let head_pages = (aligned_start - start) / PAGE_SIZE;
+        let tail_pages = padding_pages - head_pages;
+        if head_pages > 0 {
+            boot_services
+                .free_pages(start as u64, head_pages)
+                .expect("failed to free alignment padding");
+            self.allocations.set(self.allocations.get() + 1);
+        }
+        if tail_pages > 0 {
+            boot_services
+                .free_pages((aligned_start + num_pages * PAGE_SIZE) as u64, tail_pages)
+                .expect("failed to free alignment padding");
+            self.allocations.set(self.allocations.get() + 1);
+        }
+
+        let pointer = aligned_start as *mut u8;
+        // SAFETY: We allocated the memory at `pointer`, and only freed the padding
+        // around it.
+        unsafe {
+            core::ptr::write_bytes(pointer, 0, len);
+            core::slice::from_raw_parts_mut(pointer, len)
+        }
+    }
+
+    /// Allocates memory for `segment` and maps it, returning the memory
+    /// starting at the segment's virtual address.
+    ///
+    /// If the kernel is `identity_linked`, the memory must be allocated at the
+    /// segment's physical address, and an error is returned if it can't be.
+    pub(crate) fn map_segment(
+        &mut self,
+        segment: &ProgramHeader,
+        identity_linked: bool,
+    ) -> Result<&'static mut [u8], SegmentAllocationError> {
+        // The allocation is page aligned, so it is extended by the offset of the
+        // segment's virtual address into its page. This way the virtual and physical
+        // addresses of the segment have the same offset into their page and frame.
+        let page_offset = segment.p_vaddr as usize % PAGE_SIZE;
+        let len = page_offset + segment.p_memsz as usize;
+
+        let maybe_uninit_slice = if identity_linked {
+            self.allocate_segment_at_physical_address(segment, len)?
         } else {
-            self.allocate_byte_slice(segment.p_memsz as usize, KERNEL_MEMORY)
+            self.allocate_segment(segment, len)?
         };
+        // SAFETY: try_allocate_slice_inner zeroed the bytes so they are initialised.
+        let allocation = unsafe { MaybeUninit::slice_assume_init_mut(maybe_uninit_slice) };
 
         self.page_allocator.mark_segment_as_used(segment);
 
         let virtual_start = VirtualAddress::new_canonical(segment.p_vaddr as usize);
         let virtual_end_inclusive = virtual_start + segment.p_memsz as usize - 1;
 
-        let physical_start = PhysicalAddress::new_canonical(slice.as_ptr() as usize);
-        let physical_end_inclusive = physical_start + segment.p_memsz as usize - 1;
+        let physical_start = PhysicalAddress::new_canonical(allocation.as_ptr() as usize);
+        let physical_end_inclusive = physical_start + len - 1;
 
         let pages = PageRange::new(
             Page::containing_address(virtual_start),
@@ -145,39 +343,217 @@ impl BootContext {
             flags = flags.writable(true);
         }
 
+        if self.config.identity_map_kernel {
+            self.page_allocator
+                .mark_range_as_used(VirtualAddress::new_canonical(physical_start.value()), len);
+        }
+
         for (page, frame) in pages.zip(frames) {
-            self.mapper.map(
-                page,
-                frame,
-                flags,
-                &mut UefiFrameAllocator {
-                    system_table: &self.system_table,
-                },
+            let mut frame_allocator = UefiFrameAllocator {
+                system_table: &self.system_table,
+            };
+            self.mapper.map(page, frame, flags, &mut frame_allocator);
+            self.mapping_statistics.segments += PAGE_SIZE;
+            // The kernel can start executing at its physical address and jump to its
+            // virtual address afterwards. If the kernel is identity-linked, the page is
+            // already identity-mapped.
+            let identity_page = Page::containing_address(VirtualAddress::new_canonical(
+                frame.start_address().value(),
+            ));
+            if self.config.identity_map_kernel && identity_page != page {
+                self.mapper
+                    .map(identity_page, frame, flags, &mut frame_allocator);
+                self.mapping_statistics.segments += PAGE_SIZE;
+            }
+        }
+
+        Ok(&mut allocation[page_offset..])
+    }
+
+    /// Allocates `len` bytes for `segment` at any address below the
+    /// configured ceiling.
+    fn allocate_segment(
+        &self,
+        segment: &ProgramHeader,
+        len: usize,
+    ) -> Result<&'static mut [MaybeUninit<u8>], SegmentAllocationError> {
+        // x86_64 .init section. The physical address is only a hint as the kernel
+        // accesses the segment through its virtual address, so any frames will do if
+        // the memory at the physical address is occupied.
+        let ceiling = self.config.kernel_phys_max;
+        let below_ceiling = ceiling.map_or(true, |ceiling| 0x10_0000 + len <= ceiling);
+        let fixed_allocation = if segment.p_paddr == 0x10_0000 && below_ceiling {
+            let allocation = self.try_allocate_slice_inner::<u8>(
+                len,
+                AllocateType::Address(0x10_0000),
+                KERNEL_IMAGE_MEMORY,
             );
+            if allocation.is_none() {
+                warn!(
+                    "memory at {:#x} is occupied, relocating segment",
+                    segment.p_paddr
+                );
+            }
+            allocation
+        } else {
+            None
+        };
+        // The firmware returns pages whose last byte is at most the given address.
+        let allocate_type = match ceiling {
+            Some(ceiling) => AllocateType::MaxAddress((ceiling - 1) as _),
+            None => AllocateType::AnyPages,
+        };
+        fixed_allocation
+            .or_else(|| self.try_allocate_slice_inner(len, allocate_type, KERNEL_IMAGE_MEMORY))
+            .ok_or(SegmentAllocationError {
+                virtual_address: segment.p_vaddr as usize,
+                size: len,
+                ceiling,
+                physical_address: None,
+            })
+    }
+
+    /// Allocates `len` bytes for `segment` of an identity-linked kernel, at the
+    /// segment's physical address.
+    fn allocate_segment_at_physical_address(
+        &self,
+        segment: &ProgramHeader,
+        len: usize,
+    ) -> Result<&'static mut [MaybeUninit<u8>], SegmentAllocationError> {
+        let physical_address = segment.p_paddr as usize - segment.p_vaddr as usize % PAGE_SIZE;
+        let ceiling = self.config.kernel_phys_max;
+        let error = SegmentAllocationError {
+            virtual_address: segment.p_vaddr as usize,
+            size: len,
+            ceiling,
+            physical_address: Some(physical_address),
+        };
+        if ceiling.map_or(false, |ceiling| physical_address + len > ceiling) {
+            return Err(error);
         }
+        self.try_allocate_slice_inner(
+            len,
+            AllocateType::Address(physical_address as _),
+            KERNEL_IMAGE_MEMORY,
+        )
+        .ok_or(error)
+    }
 
-        slice
+    /// Logs how many boot services calls the bootloader made to allocate
+    /// memory and read files so far.
+    ///
+    /// Page allocations include frees of alignment padding, but not the frames
+    /// of the kernel's page table, which are logged with the mapping
+    /// statistics. The bootloader doesn't use pool allocations.
+    pub(crate) fn log_boot_services_statistics(&self) {
+        info!(
+            "boot services usage: {} page allocations ({:#x} bytes), {} file reads ({:#x} bytes)",
+            self.allocations.get(),
+            self.allocated_bytes.get(),
+            self.file_reads.get(),
+            self.bytes_read.get()
+        );
     }
 
-    pub(crate) fn exit_boot_services(self) -> RuntimeContext {
+    pub(crate) fn exit_boot_services(mut self) -> RuntimeContext {
+        // Allocating pages can split a descriptor into at most three, i.e. add two
+        // descriptors.
+        const DESCRIPTORS_PER_ALLOCATION: usize = 2;
+        // The firmware may allocate memory itself, e.g. when handling events.
+        const SAFETY_MARGIN: usize = 2;
+        // The only allocation between querying the size and exiting boot services is
+        // the storage for the memory map itself.
+        const ALLOCATIONS_AFTER_QUERY: usize = 1;
+
+        // The spinner's notification function must not run once the firmware no
+        // longer owns the framebuffer.
+        self.stop_spinner();
+
         let MemoryMapSize {
             entry_size,
             map_size,
         } = self.system_table.boot_services().memory_map_size();
-        let predicted_map_size = map_size + (4 * entry_size);
+        let predicted_map_size = map_size
+            + (ALLOCATIONS_AFTER_QUERY * DESCRIPTORS_PER_ALLOCATION + SAFETY_MARGIN) * entry_size;
 
+        let allocations = self.allocations.get();
+        // The storage can't be freed as boot services are gone by the time the frame
+        // allocator is done with it, in `create_boot_info`. As nothing references it
+        // once the kernel is running, it is allocated as loader data so that the
+        // kernel sees it as usable memory.
         let memory_map_storage =
             self.allocate_byte_slice(predicted_map_size, MemoryType::LOADER_DATA);
+        debug_assert_eq!(
+            self.allocations.get() - allocations,
+            ALLOCATIONS_AFTER_QUERY,
+            "unexpected allocations after querying the memory map size"
+        );
+
+        // The framebuffer mustn't be drawn to once the firmware no longer owns it.
+        phase::enter_exited();
 
+        // The map key is never held across allocations: `exit_boot_services` fetches
+        // the memory map, and with it the key, right before exiting, and fetches them
+        // again if the firmware changed the map in between.
         let (_, memory_map) = self
             .system_table
             .exit_boot_services(self.image_handle, memory_map_storage)
             .expect("failed to exit boot services");
+        debug!(
+            "predicted memory map size: {predicted_map_size:#x}, actual: {:#x} ({} allocations \
+             made by the bootloader)",
+            memory_map.len() * entry_size,
+            self.allocations.get()
+        );
+
+        RuntimeContext {
+            page_allocator: self.page_allocator,
+            frame_allocator: LegacyFrameAllocator::new(memory_map, self.config.low_memory_reserve),
+            mapper: self.mapper,
+            config: self.config,
+            boot_services: None,
+            mapping_statistics: self.mapping_statistics,
+        }
+    }
+
+    /// Switches to the runtime context without exiting boot services, so that
+    /// the kernel can keep using them.
+    ///
+    /// The frame allocator allocates each frame from the firmware. The memory
+    /// map passed to the kernel is a snapshot in which no firmware memory is
+    /// reported as usable.
+    pub(crate) fn keep_boot_services(mut self) -> RuntimeContext {
+        // The kernel may take over the framebuffer while boot services are still
+        // running, so the spinner's notification function must not run anymore.
+        self.stop_spinner();
+
+        let MemoryMapSize {
+            entry_size,
+            map_size,
+        } = self.system_table.boot_services().memory_map_size();
+        // Allocating the storage adds at most two descriptors.
+        let storage = self.allocate_byte_slice(map_size + 2 * entry_size, MemoryType::LOADER_DATA);
+        let (_, memory_map) = self
+            .system_table
+            .boot_services()
+            .memory_map(storage)
+            .expect("failed to get memory map");
+
+        let boot_services = BootServices {
+            system_table: self.system_table.as_ptr() as usize,
+            image_handle: self.image_handle.as_ptr() as usize,
+        };
+        let mut frame_allocator =
+            LegacyFrameAllocator::new(memory_map, self.config.low_memory_reserve);
+        frame_allocator.claim_from_firmware(self.system_table);
 
         RuntimeContext {
             page_allocator: self.page_allocator,
-            frame_allocator: LegacyFrameAllocator::new(memory_map),
+            frame_allocator,
             mapper: self.mapper,
+            config: self.config,
+            boot_services: Some(boot_services),
+            mapping_statistics: self.mapping_statistics,
         }
     }
 }
@@ -187,6 +563,10 @@ pub(crate) struct RuntimeContext {
     pub(crate) page_allocator: PageAllocator,
     pub(crate) frame_allocator: LegacyFrameAllocator,
     pub(crate) mapper: Mapper,
+    pub(crate) config: Config,
+    /// The handles passed to the kernel if boot services weren't exited.
+    pub(crate) boot_services: Option<BootServices>,
+    pub(crate) mapping_statistics: MappingStatistics,
 }
 
 impl RuntimeContext {
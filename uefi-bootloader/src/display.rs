@@ -0,0 +1,93 @@
+//! Drawing to the framebuffer outside of the logger, e.g. to show progress.
+//!
+//! All drawing goes through the logger's lock so it can't interleave with log
+//! output. Once boot services have been exited, the display becomes inert.
+
+use crate::{
+    logger::{self, Logger, RESERVED_HEIGHT},
+    phase,
+};
+use spin::MutexGuard;
+
+/// A handle to the framebuffer used by the logger.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Display {
+    _private: (),
+}
+
+impl Display {
+    /// Returns a handle to the display, if there is a framebuffer and boot
+    /// services haven't been exited.
+    pub(crate) fn get() -> Option<Self> {
+        (phase::current().can_draw() && logger::get().is_some()).then_some(Self { _private: () })
+    }
+
+    fn lock(self) -> Option<MutexGuard<'static, Logger>> {
+        phase::current()
+            .can_draw()
+            .then(|| logger::get().map(logger::LockedLogger::lock))
+            .flatten()
+    }
+
+    /// Fills the given rectangle with the text colour at the given
+    /// intensity.
+    pub(crate) fn fill_rect(self, x: usize, y: usize, width: usize, height: usize, intensity: u8) {
+        if let Some(mut logger) = self.lock() {
+            logger.fill_rect(x, y, width, height, intensity);
+        }
+    }
+
+    /// Draws a char with its top left corner at the given position.
+    pub(crate) fn blit_glyph(self, x: usize, y: usize, c: char) {
+        if let Some(mut logger) = self.lock() {
+            logger.write_char_at(x, y, c);
+        }
+    }
+
+    /// Sets whether text is kept out of the column the spinner is drawn in.
+    pub(crate) fn reserve_spinner_column(self, reserve: bool) {
+        if let Some(mut logger) = self.lock() {
+            logger.set_spinner_column(reserve);
+        }
+    }
+
+    /// Draws a spinner frame in the top right corner of the screen.
+    pub(crate) fn draw_spinner(self, c: char) {
+        if let Some(mut logger) = self.lock() {
+            logger.draw_spinner(c);
+        }
+    }
+
+    /// Draws a spinner frame in the top right corner of the screen, unless the
+    /// phase or the logger is locked. Returns whether it was drawn.
+    ///
+    /// Unlike the other methods, this never waits for a lock, so it can be
+    /// called from an event notification function that interrupted the code
+    /// holding it.
+    pub(crate) fn try_draw_spinner(c: char) -> bool {
+        if !phase::try_current().is_some_and(phase::Phase::can_draw) {
+            return false;
+        }
+        let Some(mut logger) = logger::get().and_then(logger::LockedLogger::try_lock) else {
+            return false;
+        };
+        logger.draw_spinner(c);
+        true
+    }
+
+    /// Draws a progress bar across the bottom of the screen.
+    pub(crate) fn draw_progress(self, done: usize, total: usize) {
+        if let Some(mut logger) = self.lock() {
+            let width = logger.width();
+            let y = logger.height().saturating_sub(RESERVED_HEIGHT);
+            let done_width = if total == 0 {
+                width
+            } else {
+                width * done.min(total) / total
+            };
+
+            logger.fill_rect(0, y, done_width, RESERVED_HEIGHT, u8::MAX);
+            logger.fill_rect(done_width, y, width - done_width, RESERVED_HEIGHT, 0);
+        }
+    }
+}
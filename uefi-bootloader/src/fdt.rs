@@ -0,0 +1,105 @@
+//! Reading properties from the flattened device tree the firmware installed as
+//! a configuration table.
+//!
+//! Only properties of the root node's direct children, e.g. `/chosen` or
+//! `/cpus`, can be looked up, which is all the bootloader needs.
+
+/// The magic at the start of a flattened device tree.
+const FDT_MAGIC: u32 = 0xd00d_feed;
+/// The size of the header fields read from a device tree.
+const HEADER_SIZE: usize = 16;
+/// The token starting a node in the device tree's structure block.
+const FDT_BEGIN_NODE: u32 = 1;
+/// The token ending a node.
+const FDT_END_NODE: u32 = 2;
+/// The token starting a property.
+const FDT_PROP: u32 = 3;
+/// The token that is ignored.
+const FDT_NOP: u32 = 4;
+
+/// Returns the flattened device tree at `address`, or `None` if there is no
+/// valid header there.
+///
+/// # Safety
+///
+/// `address` must point to readable memory that, if it starts with the device
+/// tree magic, holds a device tree of the size given in its header.
+pub(crate) unsafe fn from_address(address: usize) -> Option<&'static [u8]> {
+    // SAFETY: Guaranteed by caller.
+    let header = unsafe { core::slice::from_raw_parts(address as *const u8, HEADER_SIZE) };
+    if read_u32(header, 0)? != FDT_MAGIC {
+        return None;
+    }
+    let total_size = read_u32(header, 4)? as usize;
+    // SAFETY: Guaranteed by caller.
+    Some(unsafe { core::slice::from_raw_parts(address as *const u8, total_size) })
+}
+
+/// Returns the value of the property `name` of the root node's child `node`.
+///
+/// Returns `None` if there is no such property, or if the tree is malformed.
+pub(crate) fn property<'a>(tree: &'a [u8], node: &[u8], name: &[u8]) -> Option<&'a [u8]> {
+    if read_u32(tree, 0)? != FDT_MAGIC {
+        return None;
+    }
+    let mut offset = read_u32(tree, 8)? as usize;
+    let strings = read_u32(tree, 12)? as usize;
+
+    // The root node has depth 1, so its children have depth 2.
+    let mut depth = 0;
+    let mut in_node = false;
+    loop {
+        let token = read_u32(tree, offset)?;
+        offset += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let node_name = read_str(tree, offset)?;
+                offset = (offset + node_name.len() + 1).next_multiple_of(4);
+                depth += 1;
+                if depth == 2 {
+                    in_node = node_name == node;
+                }
+            }
+            FDT_END_NODE => {
+                if depth == 2 && in_node {
+                    return None;
+                }
+                depth -= 1;
+            }
+            FDT_PROP => {
+                let len = read_u32(tree, offset)? as usize;
+                let property_name = read_str(tree, strings + read_u32(tree, offset + 4)? as usize)?;
+                let value = tree.get(offset + 8..offset + 8 + len)?;
+                offset = (offset + 8 + len).next_multiple_of(4);
+                if depth == 2 && in_node && property_name == name {
+                    return Some(value);
+                }
+            }
+            FDT_NOP => {}
+            // The end of the structure block, or a token from a newer version.
+            _ => return None,
+        }
+    }
+}
+
+/// Returns the value of a property holding a single 32-bit or 64-bit cell.
+pub(crate) fn read_cell(value: &[u8]) -> Option<u64> {
+    match *value {
+        [a, b, c, d] => Some(u32::from_be_bytes([a, b, c, d]).into()),
+        [a, b, c, d, e, f, g, h] => Some(u64::from_be_bytes([a, b, c, d, e, f, g, h])),
+        _ => None,
+    }
+}
+
+/// Reads a big-endian `u32`, as all device tree fields are.
+fn read_u32(tree: &[u8], offset: usize) -> Option<u32> {
+    let bytes = tree.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
+
+/// Reads a null-terminated string, without its terminator.
+fn read_str(tree: &[u8], offset: usize) -> Option<&[u8]> {
+    let rest = tree.get(offset..)?;
+    let len = rest.iter().position(|&byte| byte == 0)?;
+    Some(&rest[..len])
+}
@@ -0,0 +1,324 @@
+//! Fonts used by the logger to render text.
+//!
+//! The built-in font is [`noto_sans_mono_bitmap`]. A PC Screen Font (PSF1 or
+//! PSF2) can be supplied at `\boot\font.psf` to replace it. Only the glyph
+//! bitmaps of PSF fonts are used: characters are looked up by their code
+//! point, ignoring any unicode table.
+//!
+//! Characters a font doesn't have a glyph for are drawn as a box (`□`), one
+//! per character, so that non-ASCII text such as firmware vendor strings stays
+//! readable.
+
+use crate::{fs, logger, BootContext};
+use log::{info, warn};
+use noto_sans_mono_bitmap::{
+    get_raster, get_raster_width, FontWeight, RasterHeight, RasterizedChar,
+};
+use spin::Once;
+use uefi::table::boot::MemoryType;
+
+/// The path of the custom font, relative to the file system root.
+const FONT_PATH: &str = "boot\\font.psf";
+
+/// The built-in font.
+pub(crate) static BUILTIN_FONT: Font = Font::Builtin;
+
+/// The font loaded from [`FONT_PATH`], if any.
+static CUSTOM_FONT: Once<Font> = Once::new();
+
+/// Constants for the usage of the [`noto_sans_mono_bitmap`] crate.
+mod font_constants {
+    use super::{get_raster_width, FontWeight, RasterHeight};
+
+    /// Height of each char raster. The font size is ~0.84% of this. Thus, this
+    /// is the line height that enables multiple characters to be
+    /// side-by-side and appear optically in one line in a natural way.
+    pub(crate) const CHAR_RASTER_HEIGHT: RasterHeight = RasterHeight::Size16;
+
+    /// The width of each single symbol of the mono space font.
+    pub(crate) const CHAR_RASTER_WIDTH: usize =
+        get_raster_width(FontWeight::Regular, CHAR_RASTER_HEIGHT);
+
+    pub(crate) const FONT_WEIGHT: FontWeight = FontWeight::Regular;
+}
+
+/// Returns the raster of the given char, if the built-in font has it.
+fn get_char_raster(c: char) -> Option<RasterizedChar> {
+    get_raster(
+        c,
+        font_constants::FONT_WEIGHT,
+        font_constants::CHAR_RASTER_HEIGHT,
+    )
+}
+
+/// Calls `put_pixel` for each pixel of a `width` by `height` glyph showing a
+/// box outline, the replacement for characters without a glyph.
+fn draw_replacement<F>(width: usize, height: usize, mut put_pixel: F)
+where
+    F: FnMut(usize, usize, u8),
+{
+    // Leave a margin so that adjacent boxes don't merge, and keep the box roughly
+    // within the height of an uppercase letter.
+    let (left, right) = (1, width.saturating_sub(2));
+    let (top, bottom) = (height / 4, height.saturating_sub(3));
+    for y in 0..height {
+        for x in 0..width {
+            let on_edge = ((x == left || x == right) && (top..=bottom).contains(&y))
+                || ((y == top || y == bottom) && (left..=right).contains(&x));
+            put_pixel(x, y, if on_edge { u8::MAX } else { 0 });
+        }
+    }
+}
+
+/// A monospace font.
+pub(crate) enum Font {
+    /// The built-in Noto Sans Mono font.
+    Builtin,
+    /// A PC Screen Font.
+    Psf(Psf),
+}
+
+impl Font {
+    /// The width of each glyph in pixels.
+    pub(crate) fn width(&self) -> usize {
+        match self {
+            Self::Builtin => font_constants::CHAR_RASTER_WIDTH,
+            Self::Psf(psf) => psf.width,
+        }
+    }
+
+    /// The height of each glyph in pixels.
+    pub(crate) fn height(&self) -> usize {
+        match self {
+            Self::Builtin => font_constants::CHAR_RASTER_HEIGHT.val(),
+            Self::Psf(psf) => psf.height,
+        }
+    }
+
+    /// Calls `put_pixel` with the position and intensity of each pixel of the
+    /// glyph for `c`, relative to the glyph's top left corner.
+    ///
+    /// If the font has no glyph for `c`, a replacement box is drawn instead.
+    pub(crate) fn draw<F>(&self, c: char, mut put_pixel: F)
+    where
+        F: FnMut(usize, usize, u8),
+    {
+        match self {
+            Self::Builtin => {
+                let Some(raster) = get_char_raster(c) else {
+                    draw_replacement(self.width(), self.height(), put_pixel);
+                    return;
+                };
+                for (y, row) in raster.raster().iter().enumerate() {
+                    for (x, intensity) in row.iter().enumerate() {
+                        put_pixel(x, y, *intensity);
+                    }
+                }
+            }
+            Self::Psf(psf) => {
+                let Some(glyph) = psf.glyph(c) else {
+                    draw_replacement(psf.width, psf.height, put_pixel);
+                    return;
+                };
+                for (y, row) in glyph.chunks_exact(psf.row_size).enumerate() {
+                    for x in 0..psf.width {
+                        let set = row[x / 8] & (0x80 >> (x % 8)) != 0;
+                        put_pixel(x, y, if set { u8::MAX } else { 0 });
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The glyph bitmaps of a PC Screen Font.
+pub(crate) struct Psf {
+    width: usize,
+    height: usize,
+    /// The number of bytes in each row of a glyph.
+    row_size: usize,
+    glyph_count: usize,
+    glyphs: &'static [u8],
+}
+
+impl Psf {
+    const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+    const PSF1_MODE_512: u8 = 0x01;
+    const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+    /// The largest glyph dimension that is accepted, to reject garbage.
+    const MAX_DIMENSION: usize = 64;
+
+    /// Parses a PSF1 or PSF2 font, returning `None` if it is malformed.
+    pub(crate) fn parse(bytes: &'static [u8]) -> Option<Self> {
+        let (width, height, glyph_count, header_size) = if bytes.starts_with(&Self::PSF1_MAGIC) {
+            let mode = *bytes.get(2)?;
+            let height = usize::from(*bytes.get(3)?);
+            let glyph_count = if mode & Self::PSF1_MODE_512 == 0 {
+                256
+            } else {
+                512
+            };
+            (8, height, glyph_count, 4)
+        } else if bytes.starts_with(&Self::PSF2_MAGIC) {
+            let field = |index: usize| -> Option<usize> {
+                let bytes = bytes.get(index * 4..(index + 1) * 4)?;
+                Some(u32::from_le_bytes(bytes.try_into().ok()?) as usize)
+            };
+            let header_size = field(2)?;
+            let glyph_count = field(4)?;
+            let glyph_size = field(5)?;
+            let height = field(6)?;
+            let width = field(7)?;
+            if glyph_size != height * ((width + 7) / 8) {
+                return None;
+            }
+            (width, height, glyph_count, header_size)
+        } else {
+            return None;
+        };
+
+        if width == 0
+            || height == 0
+            || width > Self::MAX_DIMENSION
+            || height > Self::MAX_DIMENSION
+            || glyph_count == 0
+        {
+            return None;
+        }
+
+        let row_size = (width + 7) / 8;
+        let glyphs_len = glyph_count.checked_mul(row_size * height)?;
+        let glyphs = bytes.get(header_size..header_size.checked_add(glyphs_len)?)?;
+
+        Some(Self {
+            width,
+            height,
+            row_size,
+            glyph_count,
+            glyphs,
+        })
+    }
+
+    /// Returns the bitmap of the glyph for `c`, or `None` if the font doesn't
+    /// contain it.
+    fn glyph(&self, c: char) -> Option<&'static [u8]> {
+        let index = c as usize;
+        if index >= self.glyph_count {
+            return None;
+        }
+        let glyph_size = self.row_size * self.height;
+        Some(&self.glyphs[index * glyph_size..(index + 1) * glyph_size])
+    }
+}
+
+impl BootContext {
+    /// Loads the custom font, if present, and switches the logger to it.
+    pub(crate) fn load_font(&self) {
+        let Some(mut root) = self.open_file_system_root() else {
+            return;
+        };
+        let Some(file) = fs::open_path(&mut root, FONT_PATH) else {
+            return;
+        };
+        let bytes = self
+            .read_file(file, MemoryType::LOADER_DATA)
+            .expect("failed to read font");
+
+        let Some(psf) = Psf::parse(bytes) else {
+            warn!("{FONT_PATH} isn't a valid PSF1 or PSF2 font, using the built-in font");
+            return;
+        };
+        let font = CUSTOM_FONT.call_once(|| Font::Psf(psf));
+        if let Some(logger) = logger::LOGGER.get() {
+            logger.set_font(font);
+        }
+        info!(
+            "loaded {}x{} font from {FONT_PATH}",
+            font.width(),
+            font.height()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a PSF2 font with `glyph_count` glyphs, where every row of glyph
+    /// `i` is `i` repeated.
+    fn psf2(width: usize, height: usize, glyph_count: usize) -> &'static [u8] {
+        let row_size = (width + 7) / 8;
+        let mut bytes = Vec::new();
+        for field in [0, 0, 32, 0, glyph_count, row_size * height, height, width] {
+            bytes.extend_from_slice(&(field as u32).to_le_bytes());
+        }
+        bytes[..4].copy_from_slice(&Psf::PSF2_MAGIC);
+        for glyph in 0..glyph_count {
+            bytes.extend(core::iter::repeat(glyph as u8).take(row_size * height));
+        }
+        Vec::leak(bytes)
+    }
+
+    /// Draws `text` with `font`, checking that every pixel is inside its glyph,
+    /// and returns the number of lit pixels.
+    fn draw(font: &Font, text: &str) -> usize {
+        let mut lit = 0;
+        for c in text.chars() {
+            font.draw(c, |x, y, intensity| {
+                assert!(
+                    x < font.width() && y < font.height(),
+                    "{c:?} drew out of bounds at ({x}, {y})"
+                );
+                if intensity > 0 {
+                    lit += 1;
+                }
+            });
+        }
+        lit
+    }
+
+    #[test]
+    fn builtin_font_replaces_non_ascii() {
+        assert!(draw(&BUILTIN_FONT, "\u{fffd}漢字 ☃") > 0);
+    }
+
+    #[test]
+    fn psf_draws_glyph_bits() {
+        let font = Font::Psf(Psf::parse(psf2(8, 4, 4)).expect("valid font"));
+        // Glyph 3 has the two rightmost bits of each row set.
+        assert_eq!(draw(&font, "\u{3}"), 2 * 4);
+        assert_eq!(draw(&font, "\0"), 0);
+    }
+
+    #[test]
+    fn psf_replaces_code_points_past_its_glyphs() {
+        let font = Font::Psf(Psf::parse(psf2(8, 16, 2)).expect("valid font"));
+        for text in ["A", "é", "漢", "\u{10ffff}"] {
+            assert!(draw(&font, text) > 0, "no replacement drawn for {text:?}");
+        }
+    }
+
+    #[test]
+    fn psf1_has_256_glyphs() {
+        let mut bytes = vec![0x36, 0x04, 0, 8];
+        bytes.resize(4 + 256 * 8, 0xff);
+        let font = Font::Psf(Psf::parse(Vec::leak(bytes)).expect("valid font"));
+        assert_eq!(draw(&font, "\u{ff}"), 8 * 8);
+        assert!(draw(&font, "\u{100}") < 8 * 8);
+    }
+
+    #[test]
+    fn truncated_psf_is_rejected() {
+        let bytes = psf2(8, 8, 4);
+        assert!(Psf::parse(&bytes[..bytes.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn replacement_fits_tiny_glyphs() {
+        for (width, height) in [(0, 0), (1, 1), (2, 3), (3, 2)] {
+            draw_replacement(width, height, |x, y, _| {
+                assert!(x < width && y < height);
+            });
+        }
+    }
+}
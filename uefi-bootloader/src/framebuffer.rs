@@ -0,0 +1,134 @@
+//! A view of the framebuffer that handles its layout and pixel format.
+
+use core::{ops::Range, ptr};
+use uefi_bootloader_api::{FrameBufferInfo, PixelFormat};
+
+/// A framebuffer, addressed by pixel coordinates.
+pub(crate) struct Framebuffer {
+    buffer: &'static mut [u8],
+    info: FrameBufferInfo,
+}
+
+impl Framebuffer {
+    /// Creates a view of `buffer`, which is laid out as described by `info`.
+    pub(crate) fn new(buffer: &'static mut [u8], info: FrameBufferInfo) -> Self {
+        Self { buffer, info }
+    }
+
+    pub(crate) fn width(&self) -> usize {
+        self.info.width
+    }
+
+    pub(crate) fn height(&self) -> usize {
+        self.info.height
+    }
+
+    /// Sets every byte of the framebuffer, including padding, to zero.
+    pub(crate) fn clear(&mut self) {
+        self.buffer.fill(0);
+    }
+
+    /// Sets the visible pixels of the given rows to zero.
+    pub(crate) fn clear_rows(&mut self, rows: Range<usize>) {
+        for y in rows {
+            self.row_mut(y).fill(0);
+        }
+    }
+
+    /// Returns the bytes of the visible pixels in row `y`.
+    pub(crate) fn row_mut(&mut self, y: usize) -> &mut [u8] {
+        debug_assert!(y < self.height(), "row {y} is out of bounds");
+        let start = y * self.info.pitch_bytes;
+        let end = start + self.width() * self.info.bytes_per_pixel;
+        &mut self.buffer[start..end]
+    }
+
+    /// Sets the pixel at the given position to the given red, green and blue
+    /// values.
+    pub(crate) fn put_pixel(&mut self, x: usize, y: usize, [red, green, blue]: [u8; 3]) {
+        debug_assert!(x < self.width(), "column {x} is out of bounds");
+        let color = match self.info.pixel_format {
+            PixelFormat::Rgb => [red, green, blue, 0],
+            PixelFormat::Bgr => [blue, green, red, 0],
+        };
+        let bytes_per_pixel = self.info.bytes_per_pixel;
+        let byte_offset = x * bytes_per_pixel;
+
+        // Bytes past the fourth, in formats with more than 32 bits per pixel, are
+        // left as they are.
+        let color_size = bytes_per_pixel.min(color.len());
+
+        let row = self.row_mut(y);
+        row[byte_offset..(byte_offset + color_size)].copy_from_slice(&color[..color_size]);
+        // SAFETY: The frame buffer is valid.
+        let _ = unsafe { ptr::read_volatile(&row[byte_offset]) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a 2x2 framebuffer with `bytes_per_pixel` bytes per pixel and a
+    /// padding byte at the end of each row, filled with `0xaa`.
+    fn framebuffer(pixel_format: PixelFormat, bytes_per_pixel: usize) -> Framebuffer {
+        let pitch_bytes = 2 * bytes_per_pixel + 1;
+        let info = FrameBufferInfo {
+            size: 2 * pitch_bytes,
+            width: 2,
+            height: 2,
+            pixel_format,
+            bytes_per_pixel,
+            stride: 2,
+            pitch_bytes,
+        };
+        Framebuffer::new(Vec::leak(vec![0xaa; info.size]), info)
+    }
+
+    /// Returns the bytes of the pixel at (1, 1).
+    fn last_pixel(framebuffer: &mut Framebuffer) -> Vec<u8> {
+        let bytes_per_pixel = framebuffer.info.bytes_per_pixel;
+        framebuffer.row_mut(1)[bytes_per_pixel..].to_vec()
+    }
+
+    #[test]
+    fn put_pixel_orders_channels() {
+        for (pixel_format, bytes_per_pixel, expected) in [
+            (PixelFormat::Rgb, 3, &[1, 2, 3][..]),
+            (PixelFormat::Rgb, 4, &[1, 2, 3, 0]),
+            (PixelFormat::Bgr, 3, &[3, 2, 1]),
+            (PixelFormat::Bgr, 4, &[3, 2, 1, 0]),
+        ] {
+            let mut framebuffer = framebuffer(pixel_format, bytes_per_pixel);
+            framebuffer.put_pixel(1, 1, [1, 2, 3]);
+            assert_eq!(last_pixel(&mut framebuffer), expected);
+            // The other pixels and the row padding are untouched.
+            let untouched = framebuffer
+                .buffer
+                .iter()
+                .filter(|&&byte| byte == 0xaa)
+                .count();
+            assert_eq!(untouched, framebuffer.buffer.len() - bytes_per_pixel);
+        }
+    }
+
+    #[test]
+    fn put_pixel_leaves_bytes_past_the_fourth() {
+        let mut framebuffer = framebuffer(PixelFormat::Bgr, 8);
+        framebuffer.put_pixel(1, 1, [1, 2, 3]);
+        assert_eq!(
+            last_pixel(&mut framebuffer),
+            [3, 2, 1, 0, 0xaa, 0xaa, 0xaa, 0xaa]
+        );
+    }
+
+    #[test]
+    fn clear_rows_keeps_padding() {
+        let mut framebuffer = framebuffer(PixelFormat::Rgb, 4);
+        framebuffer.clear_rows(0..2);
+        for row in framebuffer.buffer.chunks(framebuffer.info.pitch_bytes) {
+            assert_eq!(row[..8], [0; 8]);
+            assert_eq!(row[8], 0xaa);
+        }
+    }
+}
@@ -0,0 +1,291 @@
+//! Helpers for locating and reading files on the boot volume.
+//!
+//! Bootable ISO images expose an ISO9660 file system, on which names are
+//! usually upper-case 8.3 names with a version suffix (e.g. `KERNEL.ELF;1`).
+//! Files are first opened by their exact name, falling back to comparing the
+//! requested name against every directory entry with the version suffix
+//! stripped and case ignored.
+//!
+//! Configured paths are relative to the boot volume unless prefixed with
+//! another volume, as `<volume>:<path>`, where `<volume>` is part of the text
+//! form of the volume's device path, e.g. `HD(2,GPT`.
+
+use crate::{
+    util::{self, calculate_pages},
+    BootContext,
+};
+use log::warn;
+use uefi::{
+    proto::media::{
+        file::{Directory, File, FileAttribute, FileHandle, FileInfo, FileMode, RegularFile},
+        fs::SimpleFileSystem,
+    },
+    table::boot::{AllocateType, MemoryType},
+    CStr16, Handle, Identify,
+};
+
+/// The maximum number of volumes whose handles are cached.
+pub(crate) const MAX_CACHED_VOLUMES: usize = 8;
+
+/// The size of a buffer that holds the info of any file whose name has at most
+/// 255 characters, the most FAT allows.
+///
+/// The fixed-size fields of `EFI_FILE_INFO` take 80 bytes and the name up to
+/// 256 UCS-2 characters including the terminator; the info may have to be
+/// moved by up to 7 bytes to be aligned within the buffer.
+pub(crate) const FILE_INFO_BUFFER_SIZE: usize = 80 + 256 * 2 + 7;
+
+impl BootContext {
+    /// Skips the entry of `directory` that didn't fit in a buffer, by reading
+    /// it into a temporary allocation of `size` bytes.
+    pub(crate) fn skip_entry(&self, directory: &mut Directory, size: usize) {
+        let boot_services = self.system_table.boot_services();
+        let num_pages = calculate_pages(size);
+        let start = boot_services
+            .allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, num_pages)
+            .expect("failed to allocate pages for directory entry");
+        // SAFETY: We just allocated the memory at `start`, and free it below.
+        let buf = unsafe { core::slice::from_raw_parts_mut(start as *mut u8, size) };
+        let result = directory.read_entry(buf).map(|_| ());
+        boot_services
+            .free_pages(start, num_pages)
+            .expect("failed to free pages for directory entry");
+        result.expect("failed to skip directory entry");
+    }
+
+    /// Reads the contents of `file` into newly allocated memory of the given
+    /// type.
+    pub(crate) fn read_file(
+        &self,
+        file: FileHandle,
+        memory_type: MemoryType,
+    ) -> Option<&'static mut [u8]> {
+        let mut file = file.into_regular_file()?;
+        let size = file_size(&mut file)?;
+        if size == 0 {
+            return Some(&mut []);
+        }
+
+        let bytes = self.allocate_byte_slice(size, memory_type);
+        self.read_retrying(&mut file, bytes).ok()?;
+        Some(bytes)
+    }
+
+    /// Reads from `file` into `buffer`, retrying failed reads as configured by
+    /// the `read_retries` and `read_retry_delay` config keys.
+    ///
+    /// Slow or flaky media, e.g. cheap USB sticks, occasionally fail reads that
+    /// succeed when attempted again.
+    pub(crate) fn read_retrying(
+        &self,
+        file: &mut RegularFile,
+        buffer: &mut [u8],
+    ) -> uefi::Result<usize, Option<usize>> {
+        let retries = self.config.read_retries;
+        // A failed read may have moved the position, so it is restored before
+        // retrying.
+        let position = file.get_position().ok();
+
+        let mut attempt = 0;
+        loop {
+            self.file_reads.set(self.file_reads.get() + 1);
+            let error = match file.read(buffer) {
+                Ok(len) => {
+                    self.bytes_read.set(self.bytes_read.get() + len);
+                    return Ok(len);
+                }
+                Err(error) => error,
+            };
+            let Some(position) = position.filter(|_| attempt < retries) else {
+                return Err(error);
+            };
+            attempt += 1;
+            warn!(
+                "failed to read file: {:?}, retrying ({attempt}/{retries})",
+                error.status()
+            );
+            self.system_table
+                .boot_services()
+                .stall(self.config.read_retry_delay * 1000);
+            if file.set_position(position).is_err() {
+                return Err(error);
+            }
+        }
+    }
+
+    /// Opens the file at the configured `path`, which may be prefixed with the
+    /// volume it is on.
+    pub(crate) fn open_path(&self, path: &'static str) -> Option<FileHandle> {
+        let (volume, path) = split_volume(path);
+        let mut root = match volume {
+            Some(volume) => self.open_volume_root(volume)?,
+            None => self.open_file_system_root()?,
+        };
+        open_path(&mut root, path)
+    }
+
+    /// Opens the root directory of the volume whose device path contains
+    /// `volume`.
+    fn open_volume_root(&self, volume: &'static str) -> Option<Directory> {
+        let handle = self.volume_handle(volume)?;
+        self.system_table
+            .boot_services()
+            .open_protocol_exclusive::<SimpleFileSystem>(handle)
+            .ok()?
+            .open_volume()
+            .ok()
+    }
+
+    /// Returns the handle of the volume whose device path contains `volume`,
+    /// caching it so that the device paths aren't searched again for the next
+    /// file on the same volume.
+    fn volume_handle(&self, volume: &'static str) -> Option<Handle> {
+        let mut volumes = self.volumes.borrow_mut();
+        if let Some((_, handle)) = volumes.iter().flatten().find(|(id, _)| *id == volume) {
+            return Some(*handle);
+        }
+
+        let Some(handle) = util::find_handle_by_device_path(
+            self.system_table.boot_services(),
+            &SimpleFileSystem::GUID,
+            "volume",
+            volume,
+        ) else {
+            warn!("no volume's device path contains {volume:?}");
+            return None;
+        };
+        if let Some(slot) = volumes.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some((volume, handle));
+        }
+        Some(handle)
+    }
+}
+
+/// Splits a configured path into the volume it is prefixed with, if any, and
+/// the path on that volume.
+pub(crate) fn split_volume(path: &str) -> (Option<&str>, &str) {
+    match path.split_once(':') {
+        Some((volume, path)) => (Some(volume), path),
+        None => (None, path),
+    }
+}
+
+/// Opens the file called `name` in `directory`.
+pub(crate) fn open(directory: &mut Directory, name: &CStr16) -> Option<FileHandle> {
+    if let Ok(file) = directory.open(name, FileMode::Read, FileAttribute::empty()) {
+        return Some(file);
+    }
+
+    directory.reset_entry_readout().ok()?;
+    let mut buf = [0; FILE_INFO_BUFFER_SIZE];
+
+    while let Some(info) = directory.read_entry(&mut buf).ok()? {
+        if names_match(info.file_name(), name) {
+            return directory
+                .open(info.file_name(), FileMode::Read, FileAttribute::empty())
+                .ok();
+        }
+    }
+
+    None
+}
+
+/// Opens the file at the backslash-separated `path`, relative to `directory`.
+pub(crate) fn open_path(directory: &mut Directory, path: &str) -> Option<FileHandle> {
+    let mut buf = [0; 256];
+    let mut components = path.split('\\').filter(|component| !component.is_empty());
+
+    let mut file = open(
+        directory,
+        CStr16::from_str_with_buf(components.next()?, &mut buf).ok()?,
+    )?;
+    for component in components {
+        let mut directory = file.into_directory()?;
+        file = open(
+            &mut directory,
+            CStr16::from_str_with_buf(component, &mut buf).ok()?,
+        )?;
+    }
+
+    Some(file)
+}
+
+/// Returns the size of `file` in bytes.
+pub(crate) fn file_size(file: &mut RegularFile) -> Option<usize> {
+    let mut buf = [0; FILE_INFO_BUFFER_SIZE];
+    file.get_info::<FileInfo>(&mut buf)
+        .ok()
+        .map(|info| info.file_size() as usize)
+}
+
+/// Returns `name` without an ISO9660 version suffix (e.g. `;1`), and without
+/// the trailing dot ISO9660 appends to names lacking an extension.
+pub(crate) fn strip_version(name: &[u16]) -> &[u16] {
+    let name = match name.iter().rposition(|c| *c == u16::from(b';')) {
+        Some(idx)
+            if idx + 1 < name.len()
+                && name[(idx + 1)..]
+                    .iter()
+                    .all(|c| (u16::from(b'0')..=u16::from(b'9')).contains(c)) =>
+        {
+            &name[..idx]
+        }
+        _ => name,
+    };
+    name.strip_suffix(&[u16::from(b'.')]).unwrap_or(name)
+}
+
+/// Returns whether the directory entry `candidate` refers to the file `name`,
+/// ignoring ISO9660 version suffixes and ASCII case.
+fn names_match(candidate: &CStr16, name: &CStr16) -> bool {
+    fn to_upper(c: u16) -> u16 {
+        u8::try_from(c).map_or(c, |c| c.to_ascii_uppercase().into())
+    }
+
+    let candidate = strip_version(candidate.to_u16_slice());
+    let name = name.to_u16_slice();
+
+    candidate.len() == name.len()
+        && candidate
+            .iter()
+            .zip(name)
+            .all(|(a, b)| to_upper(*a) == to_upper(*b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uefi::prelude::cstr16;
+
+    fn strip(name: &str) -> String {
+        let name: Vec<u16> = name.encode_utf16().collect();
+        String::from_utf16(strip_version(&name)).expect("valid UTF-16")
+    }
+
+    #[test]
+    fn strip_version_removes_iso9660_suffixes() {
+        assert_eq!(strip("KERNEL.ELF;1"), "KERNEL.ELF");
+        assert_eq!(strip("KERNEL.;12"), "KERNEL");
+        assert_eq!(strip("KERNEL."), "KERNEL");
+        assert_eq!(strip("kernel.elf"), "kernel.elf");
+    }
+
+    #[test]
+    fn strip_version_keeps_other_semicolons() {
+        assert_eq!(strip("A;B"), "A;B");
+        assert_eq!(strip("KERNEL;"), "KERNEL;");
+        assert_eq!(strip("KERNEL;1A"), "KERNEL;1A");
+        assert_eq!(strip(";1"), "");
+    }
+
+    #[test]
+    fn names_match_ignores_version_and_ascii_case() {
+        assert!(names_match(cstr16!("KERNEL.ELF;1"), cstr16!("kernel.elf")));
+        assert!(names_match(cstr16!("INITRD.;1"), cstr16!("initrd")));
+        assert!(names_match(cstr16!("kernel.elf"), cstr16!("Kernel.Elf")));
+        assert!(!names_match(cstr16!("KERNEL.ELF;1"), cstr16!("kernel")));
+        assert!(!names_match(cstr16!("KERNEL"), cstr16!("kernel.elf")));
+        // Only ASCII letters are folded.
+        assert!(!names_match(cstr16!("É.ELF"), cstr16!("é.elf")));
+    }
+}
@@ -0,0 +1,111 @@
+//! Passing an initrd to chainloaded EFI-stub kernels.
+//!
+//! Linux's EFI stub loads its initrd through the LoadFile2 protocol installed
+//! on the handle whose device path is a vendor media node identified by
+//! `LINUX_EFI_INITRD_MEDIA_GUID`. The bootloader installs such a handle,
+//! serving an initrd it read from its own volume.
+
+use crate::BootContext;
+use core::{ffi::c_void, ptr};
+use log::info;
+use spin::Once;
+use uefi::{proto::device_path::DevicePath, table::boot::MemoryType, Guid, Identify, Status};
+
+/// The GUID of the LoadFile2 protocol.
+const LOAD_FILE2_GUID: Guid =
+    Guid::from_values(0x4006_c0c1, 0xfcb3, 0x403e, 0x996d, 0x4a6c_8724_e06d);
+
+/// The device path of the handle the initrd is served from.
+static INITRD_DEVICE_PATH: [u8; 24] = [
+    // Vendor-defined media device path node, 20 bytes long.
+    0x04, 0x03, 20, 0,
+    // LINUX_EFI_INITRD_MEDIA_GUID, i.e. 5568e427-68fc-4f3d-ac74-ca555231cc68.
+    0x27, 0xe4, 0x68, 0x55, 0xfc, 0x68, 0x3d, 0x4f, 0xac, 0x74, 0xca, 0x55, 0x52, 0x31, 0xcc, 0x68,
+    // End of entire device path node, 4 bytes long.
+    0x7f, 0xff, 4, 0,
+];
+
+/// The LoadFile2 protocol interface serving the initrd.
+static LOAD_FILE2: LoadFile2 = LoadFile2 { load_file };
+
+/// The initrd served by [`LOAD_FILE2`].
+static INITRD: Once<&'static [u8]> = Once::new();
+
+#[repr(C)]
+struct LoadFile2 {
+    load_file: unsafe extern "efiapi" fn(
+        this: *const LoadFile2,
+        file_path: *const c_void,
+        boot_policy: bool,
+        buffer_size: *mut usize,
+        buffer: *mut c_void,
+    ) -> Status,
+}
+
+/// Copies the initrd to `buffer`, or sets `buffer_size` to the size of the
+/// initrd if the buffer is too small.
+unsafe extern "efiapi" fn load_file(
+    _this: *const LoadFile2,
+    _file_path: *const c_void,
+    boot_policy: bool,
+    buffer_size: *mut usize,
+    buffer: *mut c_void,
+) -> Status {
+    // LoadFile2 never loads boot options.
+    if boot_policy {
+        return Status::UNSUPPORTED;
+    }
+    let Some(initrd) = INITRD.get() else {
+        return Status::NOT_FOUND;
+    };
+    if buffer_size.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+
+    // SAFETY: The caller passes a valid pointer.
+    let size = unsafe { &mut *buffer_size };
+    if buffer.is_null() || *size < initrd.len() {
+        *size = initrd.len();
+        return Status::BUFFER_TOO_SMALL;
+    }
+    // SAFETY: The caller guarantees the buffer is `size` bytes long, which we
+    // checked is enough.
+    unsafe { ptr::copy_nonoverlapping(initrd.as_ptr(), buffer.cast(), initrd.len()) };
+    *size = initrd.len();
+    Status::SUCCESS
+}
+
+impl BootContext {
+    /// Reads the initrd at `path` and installs the handle chainloaded EFI-stub
+    /// kernels load it from.
+    pub(crate) fn install_initrd(&self, path: &'static str) {
+        let file = self.open_path(path).expect("failed to open initrd");
+        let initrd: &'static [u8] = self
+            .read_file(file, MemoryType::LOADER_DATA)
+            .expect("failed to read initrd");
+        INITRD.call_once(|| initrd);
+
+        let boot_services = self.system_table.boot_services();
+        // SAFETY: The device path is a static, so it outlives the handle, and the
+        // firmware never writes to it.
+        let handle = unsafe {
+            boot_services.install_protocol_interface(
+                None,
+                &DevicePath::GUID,
+                INITRD_DEVICE_PATH.as_ptr() as *mut c_void,
+            )
+        }
+        .expect("failed to install initrd device path");
+        // SAFETY: Same as above.
+        unsafe {
+            boot_services.install_protocol_interface(
+                Some(handle),
+                &LOAD_FILE2_GUID,
+                ptr::addr_of!(LOAD_FILE2) as *mut c_void,
+            )
+        }
+        .expect("failed to install initrd load file protocol");
+
+        info!("installed initrd from {path} ({:#x} bytes)", initrd.len());
+    }
+}
@@ -0,0 +1,126 @@
+//! Console input for interactive prompts.
+//!
+//! Headless systems (e.g. serial-only or automated setups) may not have a
+//! usable console input, so prompts must take their default action instead of
+//! blocking when the bootloader is non-interactive.
+
+use crate::{phase, BootContext};
+use log::info;
+use spin::Mutex;
+use uefi::{
+    proto::console::text::{Input, Key},
+    table::{
+        boot::{EventType, TimerTrigger, Tpl},
+        runtime::ResetType,
+        Boot, SystemTable,
+    },
+    Status,
+};
+
+/// The system table and timeout used to wait for a key press after a fatal
+/// error, if the bootloader is configured to.
+static PAUSE_ON_ERROR: Mutex<Option<Pause>> = Mutex::new(None);
+
+struct Pause {
+    system_table: SystemTable<Boot>,
+    timeout: usize,
+}
+
+// SAFETY: The bootloader is single-threaded.
+unsafe impl Send for Pause {}
+
+impl BootContext {
+    /// Returns whether prompts may wait for input.
+    ///
+    /// The bootloader is non-interactive if configured to be, or if the
+    /// firmware doesn't provide a console input protocol.
+    pub(crate) fn is_interactive(&self) -> bool {
+        !self.config.noninteractive
+            && self
+                .system_table
+                .boot_services()
+                .get_handle_for_protocol::<Input>()
+                .is_ok()
+    }
+
+    /// Waits for a key press for at most the configured timeout.
+    ///
+    /// Returns `None` without waiting if the bootloader is non-interactive, in
+    /// which case the caller should take its default action.
+    pub(crate) fn wait_for_key(&mut self) -> Option<Key> {
+        if !self.is_interactive() {
+            return None;
+        }
+        wait_for_key(&mut self.system_table, self.config.timeout)
+    }
+
+    /// Makes the panic handler wait for a key press before halting, if
+    /// configured to and the bootloader is interactive.
+    pub(crate) fn enable_pause_on_error(&self) {
+        if self.config.pause_on_error && self.is_interactive() {
+            *PAUSE_ON_ERROR.lock() = Some(Pause {
+                // SAFETY: The panic handler only uses the system table while boot services
+                // are active.
+                system_table: unsafe { self.system_table.unsafe_clone() },
+                timeout: self.config.timeout,
+            });
+        }
+    }
+}
+
+/// Waits for a key press for at most `timeout` seconds.
+fn wait_for_key(system_table: &mut SystemTable<Boot>, timeout: usize) -> Option<Key> {
+    // SAFETY: The event is only used for the duration of this function.
+    let key_event = unsafe { system_table.stdin().wait_for_key_event().unsafe_clone() };
+
+    let boot_services = system_table.boot_services();
+    // SAFETY: The event doesn't have a notification function.
+    let timer =
+        unsafe { boot_services.create_event(EventType::TIMER, Tpl::APPLICATION, None, None) }
+            .ok()?;
+    // The trigger time is in units of 100ns.
+    let timeout = timeout as u64 * 10_000_000;
+
+    let index = boot_services
+        .set_timer(&timer, TimerTrigger::Relative(timeout))
+        .ok()
+        .and_then(|_| {
+            // SAFETY: The timer is closed after waiting.
+            let mut events = [key_event, unsafe { timer.unsafe_clone() }];
+            boot_services.wait_for_event(&mut events).ok()
+        });
+    let _ = boot_services.close_event(timer);
+
+    if index == Some(0) {
+        system_table.stdin().read_key().ok().flatten()
+    } else {
+        None
+    }
+}
+
+/// Waits for a key press after a fatal error, if configured to, and reboots if
+/// a key is pressed. Returns if no key was pressed before the timeout, or
+/// boot services were exited.
+///
+/// # Safety
+///
+/// Must only be called from the panic handler, and the caller must ensure no
+/// other thread could simultaneously access the pause state.
+pub(crate) unsafe fn pause_from_panic() {
+    // SAFETY: Guaranteed by caller.
+    unsafe { PAUSE_ON_ERROR.force_unlock() };
+    let Some(mut pause) = PAUSE_ON_ERROR.lock().take() else {
+        return;
+    };
+    if !phase::current().can_draw() {
+        return;
+    }
+
+    info!("press any key to reboot");
+    if wait_for_key(&mut pause.system_table, pause.timeout).is_some() {
+        pause
+            .system_table
+            .runtime_services()
+            .reset(ResetType::Cold, Status::ABORTED, None);
+    }
+}
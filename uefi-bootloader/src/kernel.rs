@@ -1,36 +1,93 @@
-use crate::{memory::VirtualAddress, BootContext};
+use crate::{
+    arch::KERNEL_MACHINE,
+    context::SegmentAllocationError,
+    fs,
+    memory::{
+        FrameRange, PageRange, PhysicalAddress, PteFlags, UefiFrameAllocator, VirtualAddress,
+        KERNEL_CONFIG_MEMORY, PAGE_SIZE,
+    },
+    util::Crc32,
+    BootContext,
+};
 use core::mem::MaybeUninit;
 use goblin::elf64::{
-    header::Header,
-    program_header::{ProgramHeader, SIZEOF_PHDR},
-    section_header::{SectionHeader, SIZEOF_SHDR},
+    dynamic::{DT_NULL, DT_RELA, DT_RELASZ},
+    header::{machine_to_str, Header, ET_DYN},
+    program_header::{
+        pt_to_str, ProgramHeader, PF_W, PF_X, PT_DYNAMIC, PT_GNU_EH_FRAME, PT_GNU_RELRO,
+        PT_GNU_STACK, PT_LOAD, PT_NOTE, PT_NULL, PT_PHDR, PT_TLS, SIZEOF_PHDR,
+    },
+    section_header::{SectionHeader, SHF_ALLOC, SHF_TLS, SHT_NOBITS, SIZEOF_SHDR},
 };
-use log::info;
+use log::{debug, info, warn};
 use plain::Plain;
+#[cfg(not(feature = "embedded-kernel"))]
+use uefi::proto::media::file::FileType;
 use uefi::{
-    prelude::cstr16,
-    proto::media::file::{File, FileAttribute, FileMode, FileType, RegularFile},
+    proto::media::file::{File, RegularFile},
     table::boot::MemoryType,
-    CStr16,
 };
-use uefi_bootloader_api::ElfSection;
+use uefi_bootloader_api::{Bytes, ElfSection, KernelSegment};
+
+/// The path of the kernel's symbol map, relative to the file system root.
+const SYMBOL_MAP_PATH: &str = "boot\\kernel.map";
+/// The path of the kernel's config blob, relative to the file system root.
+const KERNEL_CONFIG_PATH: &str = "boot\\kernel.cfg";
 
-const KERNEL_NAME: &CStr16 = cstr16!("kernel.elf");
+/// The segment type of the GNU program property note, which goblin doesn't
+/// define.
+const PT_GNU_PROPERTY: u32 = 0x6474_e553;
+
+/// The kernel embedded in the bootloader at build time.
+#[cfg(feature = "embedded-kernel")]
+static EMBEDDED_KERNEL: &[u8] = include_bytes!(env!("UEFI_BOOTLOADER_KERNEL"));
+
+/// The result of loading the kernel.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct LoadedKernel {
+    /// The virtual address of the kernel's entry point.
+    pub(crate) entry_point: VirtualAddress,
+    /// The physical address of the kernel's entry point, if it lies in a
+    /// loaded segment.
+    pub(crate) physical_entry_point: Option<PhysicalAddress>,
+    /// The kernel's ELF sections.
+    pub(crate) elf_sections: &'static [ElfSection],
+    /// Where the kernel's loaded segments were placed.
+    pub(crate) segments: &'static [KernelSegment],
+    /// The CRC-32 of the kernel file, if enabled by the `kernel_crc32` config
+    /// key.
+    pub(crate) crc32: Option<u32>,
+}
 
 impl BootContext {
-    pub(crate) fn load_kernel(&mut self) -> (VirtualAddress, &'static mut [ElfSection]) {
-        let mut root = self
-            .open_file_system_root()
-            .expect("failed to open file system root");
-
-        let file = match root
-            .open(KERNEL_NAME, FileMode::Read, FileAttribute::empty())
-            .expect("failed to open kernel file")
-            .into_type()
-            .expect("kernel file was closed or deleted")
-        {
-            FileType::Regular(file) => file,
-            FileType::Dir(_) => panic!(),
+    /// Loads the kernel, mapping its segments into the kernel's address space.
+    ///
+    /// Returns an error if the memory of a segment couldn't be allocated.
+    pub(crate) fn load_kernel(&mut self) -> Result<LoadedKernel, SegmentAllocationError> {
+        #[cfg(feature = "embedded-kernel")]
+        let file = {
+            info!("loading embedded kernel");
+            Source::Embedded {
+                bytes: EMBEDDED_KERNEL,
+                position: 0,
+            }
+        };
+
+        #[cfg(not(feature = "embedded-kernel"))]
+        let file = {
+            match self
+                .open_path(self.config.kernel)
+                .expect("failed to open kernel file")
+                .into_type()
+                .expect("kernel file was closed or deleted")
+            {
+                FileType::Regular(mut file) => {
+                    let size = fs::file_size(&mut file).expect("failed to get kernel size");
+                    self.check_kernel_size("kernel file", size);
+                    Source::File(file)
+                }
+                FileType::Dir(_) => panic!(),
+            }
         };
 
         Loader {
@@ -39,26 +96,201 @@ impl BootContext {
         }
         .load()
     }
+
+    /// Loads the kernel's symbol map, if present.
+    ///
+    /// The file is passed to the kernel as-is, without being parsed.
+    pub(crate) fn load_symbol_map(&self) -> Option<&'static [u8]> {
+        let mut root = self.open_file_system_root()?;
+        let file = fs::open_path(&mut root, SYMBOL_MAP_PATH)?;
+        let symbol_map = self
+            .read_file(file, MemoryType::LOADER_DATA)
+            .expect("failed to read kernel symbol map");
+        info!("loaded kernel symbol map");
+        Some(symbol_map)
+    }
+
+    /// Loads the kernel's config blob, if present, and maps it read-only into
+    /// the kernel's address space.
+    ///
+    /// The blob is passed to the kernel as-is, without being parsed.
+    pub(crate) fn load_kernel_config(&mut self) -> Option<Bytes> {
+        let mut root = self.open_file_system_root()?;
+        let file = fs::open_path(&mut root, KERNEL_CONFIG_PATH)?;
+        let blob = self
+            .read_file(file, KERNEL_CONFIG_MEMORY)
+            .expect("failed to read kernel config");
+        if blob.is_empty() {
+            return Some(Bytes::from(&[][..]));
+        }
+
+        let virtual_start = self.page_allocator.get_free_address(blob.len());
+        let physical_start = PhysicalAddress::new_canonical(blob.as_ptr() as usize);
+        let pages = PageRange::from_virt_addr(virtual_start, blob.len());
+        let frames = FrameRange::from_phys_addr(physical_start, blob.len());
+        let flags = PteFlags::new().present(true).no_execute(true);
+
+        for (page, frame) in pages.into_iter().zip(frames) {
+            self.mapper.map(
+                page,
+                frame,
+                flags,
+                &mut UefiFrameAllocator {
+                    system_table: &self.system_table,
+                },
+            );
+            self.mapping_statistics.other += PAGE_SIZE;
+        }
+        info!("mapped kernel config at {virtual_start:?}");
+
+        // SAFETY: The blob is mapped at `virtual_start` in the kernel's page table.
+        Some(unsafe { Bytes::from_raw_parts(virtual_start.value() as *const u8, blob.len()) })
+    }
+
+    /// Panics if `size` exceeds the configured maximum kernel size, rather than
+    /// attempting to allocate that much memory for a corrupt kernel.
+    fn check_kernel_size(&self, what: &str, size: usize) {
+        let max = self.config.max_kernel_size;
+        assert!(
+            size <= max,
+            "{what} is {size:#x} bytes, exceeding the maximum kernel size of {max:#x} bytes (see \
+             the `max_kernel_size` config key)"
+        );
+    }
+}
+
+/// Where the kernel image is read from.
+enum Source {
+    #[cfg_attr(feature = "embedded-kernel", allow(dead_code))]
+    File(RegularFile),
+    #[cfg(feature = "embedded-kernel")]
+    Embedded {
+        bytes: &'static [u8],
+        position: usize,
+    },
+}
+
+impl Source {
+    fn set_position(&mut self, position: u64) -> uefi::Result {
+        match self {
+            Self::File(file) => file.set_position(position),
+            #[cfg(feature = "embedded-kernel")]
+            Self::Embedded {
+                position: current, ..
+            } => {
+                *current = usize::try_from(position).unwrap_or(usize::MAX);
+                Ok(())
+            }
+        }
+    }
+
+    fn read(
+        &mut self,
+        context: &BootContext,
+        buffer: &mut [u8],
+    ) -> uefi::Result<usize, Option<usize>> {
+        match self {
+            Self::File(file) => context.read_retrying(file, buffer),
+            #[cfg(feature = "embedded-kernel")]
+            Self::Embedded { bytes, position } => {
+                let remaining = bytes.get(*position..).unwrap_or_default();
+                let len = remaining.len().min(buffer.len());
+                buffer[..len].copy_from_slice(&remaining[..len]);
+                *position += len;
+                Ok(len)
+            }
+        }
+    }
 }
 
+/// Warns about allocated `SHT_NOBITS` sections, e.g. `.bss`, that don't lie
+/// within the zero-filled part of a loaded segment, i.e. past its file size.
+///
+/// The file bytes of such sections are never read, so the kernel would find
+/// the overlapping segment's file contents, or unmapped memory, instead of
+/// zeroes. Thread-local sections are skipped, as they are templates the kernel
+/// instantiates itself.
+fn check_bss_sections(sections: &[ElfSection], segments: &[KernelSegment]) {
+    for section in unzeroed_bss_sections(sections, segments) {
+        warn!(
+            "{} section at {:#x} (length {:#x}) isn't in the zero-filled part of a loaded segment",
+            section.name(),
+            section.start,
+            section.size
+        );
+    }
+}
+
+/// Returns the sections [`check_bss_sections`] warns about.
+fn unzeroed_bss_sections<'a>(
+    sections: &'a [ElfSection],
+    segments: &'a [KernelSegment],
+) -> impl Iterator<Item = &'a ElfSection> {
+    sections.iter().filter(|section| {
+        section.ty == SHT_NOBITS
+            && section.flags & u64::from(SHF_ALLOC) != 0
+            && section.flags & u64::from(SHF_TLS) == 0
+            && section.size != 0
+            && !segments.iter().any(|segment| {
+                let zeroed_start = segment.virtual_start + segment.file_size;
+                let end = segment.virtual_start + segment.size;
+                zeroed_start <= section.start && section.start + section.size <= end
+            })
+    })
+}
+
+/// Loads the kernel by streaming it from its source.
+///
+/// The kernel is never read into memory as a whole. Each ELF structure is read
+/// into a buffer local to the function that parses it, and goblin's
+/// `from_bytes` borrows that buffer in place, so the borrow checker guarantees
+/// the bytes outlive the parsed structure. Segment contents are read straight
+/// from the source into the memory returned by `map_segment`.
 struct Loader<'a> {
-    file: RegularFile,
+    file: Source,
     context: &'a mut BootContext,
 }
 
 impl Loader<'_> {
-    fn load(mut self) -> (VirtualAddress, &'static mut [ElfSection]) {
+    fn load(mut self) -> Result<LoadedKernel, SegmentAllocationError> {
+        let crc32 = self.context.config.kernel_crc32.then(|| self.crc32());
+
         let mut buffer = [0; core::mem::size_of::<Header>()];
         self.file
-            .read(&mut buffer)
+            .read(self.context, &mut buffer)
             .expect("failed to read kernel header");
 
         let kernel_header = Header::from_bytes(&buffer);
 
+        assert!(
+            kernel_header.e_machine == KERNEL_MACHINE,
+            "kernel is built for {}, but the bootloader is built for {}",
+            machine_to_str(kernel_header.e_machine),
+            machine_to_str(KERNEL_MACHINE)
+        );
+
+        if kernel_header.e_type == ET_DYN {
+            self.reject_position_independent(kernel_header);
+        }
+
         let program_header_offset = kernel_header.e_phoff;
         let program_header_count = kernel_header.e_phnum;
 
+        let identity_linked = self.is_identity_linked(kernel_header);
+        if identity_linked {
+            info!("kernel is identity-linked, loading segments at their physical addresses");
+        }
+
+        // This slice is copied into another slice in the bootloader, so this slice can
+        // be overwritten by the kernel.
+        let segments = self
+            .context
+            .allocate_small_slice(program_header_count as usize);
         let mut buffer = [0; SIZEOF_PHDR];
+        let mut loaded_size = 0;
+        let mut loaded_segments = 0;
+        let entry_override = self.context.config.entry;
+        let mut entry_override_executable = false;
 
         for i in 0..program_header_count.into() {
             // Loading segments modifies the file position.
@@ -66,26 +298,208 @@ impl Loader<'_> {
                 .set_position(program_header_offset + (i * SIZEOF_PHDR as u64))
                 .expect("failed to set kernel file position to program header");
             self.file
-                .read(&mut buffer)
+                .read(self.context, &mut buffer)
                 .expect("failed to read kernel program header");
 
             let program_header = ProgramHeader::from_bytes(&buffer)
                 .expect("failed to create program header from bytes");
 
-            // .got section
-            if program_header.p_memsz == 0 {
-                continue;
+            match program_header.p_type {
+                // .got section
+                PT_LOAD if program_header.p_memsz == 0 => {}
+                PT_LOAD => {
+                    loaded_size = loaded_size.saturating_add(program_header.p_memsz as usize);
+                    self.context
+                        .check_kernel_size("kernel image in memory", loaded_size);
+                    self.check_write_xor_execute(program_header);
+                    let segment = self.handle_load_segment(program_header, identity_linked)?;
+                    segments[loaded_segments].write(segment);
+                    loaded_segments += 1;
+                    if let Some(entry) = entry_override {
+                        let start = program_header.p_vaddr;
+                        entry_override_executable |= program_header.p_flags & PF_X != 0
+                            && (start..start + program_header.p_memsz).contains(&(entry as u64));
+                    }
+                }
+                // The stack is always mapped no-execute.
+                PT_GNU_STACK if program_header.p_flags & PF_X != 0 => {
+                    warn!("kernel requests an executable stack, but the stack is no-execute");
+                }
+                // The contents of these segments are part of a PT_LOAD segment, or only
+                // relevant to the kernel itself.
+                PT_NULL | PT_DYNAMIC | PT_NOTE | PT_PHDR | PT_TLS | PT_GNU_EH_FRAME
+                | PT_GNU_STACK | PT_GNU_RELRO | PT_GNU_PROPERTY => {
+                    if self.context.config.map_verbose {
+                        debug!("skipping {} segment", pt_to_str(program_header.p_type));
+                    }
+                }
+                p_type => warn!(
+                    "skipping kernel segment of unsupported type {} ({p_type:#x})",
+                    pt_to_str(p_type)
+                ),
             }
+        }
+
+        // Otherwise nothing would be mapped, and the jump to the entry point would
+        // fault.
+        assert!(
+            loaded_segments > 0,
+            "kernel has no loadable segments (of {program_header_count} program headers)"
+        );
 
-            if program_header.p_type == 1 {
-                self.handle_load_segment(program_header);
+        // SAFETY: We initialised the slice up to `loaded_segments`.
+        let segments = unsafe { MaybeUninit::slice_assume_init_ref(&segments[..loaded_segments]) };
+        let elf_sections = self.elf_sections(kernel_header);
+        check_bss_sections(elf_sections, segments);
+
+        let entry_point = match entry_override {
+            Some(entry) => {
+                assert!(
+                    entry_override_executable,
+                    "entry point override {entry:#x} isn't in an executable segment"
+                );
+                info!(
+                    "overriding kernel entry point {:#x} with {entry:#x}",
+                    kernel_header.e_entry
+                );
+                VirtualAddress::new_canonical(entry)
             }
+            None => VirtualAddress::new_canonical(kernel_header.e_entry as usize),
+        };
+        Ok(LoadedKernel {
+            entry_point,
+            physical_entry_point: self.context.mapper.translate(entry_point),
+            elf_sections,
+            segments,
+            crc32,
+        })
+    }
+
+    /// Returns the CRC-32 of the whole kernel file, leaving the file position
+    /// at the start of the file.
+    fn crc32(&mut self) -> u32 {
+        let mut crc32 = Crc32::new();
+        let mut buffer = [0; 4096];
+
+        self.file
+            .set_position(0)
+            .expect("failed to set kernel file position to start");
+        loop {
+            let len = self
+                .file
+                .read(self.context, &mut buffer)
+                .expect("failed to read kernel for checksum");
+            if len == 0 {
+                break;
+            }
+            crc32.update(&buffer[..len]);
         }
+        self.file
+            .set_position(0)
+            .expect("failed to set kernel file position to start");
 
-        (
-            VirtualAddress::new_canonical(kernel_header.e_entry as usize),
-            self.elf_sections(kernel_header),
-        )
+        let crc32 = crc32.finish();
+        info!("kernel crc32: {crc32:#010x}");
+        crc32
+    }
+
+    /// Panics if `segment` is both writable and executable, unless allowed by
+    /// the `allow_wx` config key, in which case a warning is logged.
+    fn check_write_xor_execute(&self, segment: &ProgramHeader) {
+        if segment.p_flags & (PF_W | PF_X) != PF_W | PF_X {
+            return;
+        }
+        assert!(
+            self.context.config.allow_wx,
+            "kernel segment at {:#x} is writable and executable; set `allow_wx true` to map it \
+             anyway",
+            segment.p_vaddr
+        );
+        warn!(
+            "mapping kernel segment at {:#x} (length {:#x}) writable AND executable, as allowed \
+             by `allow_wx`",
+            segment.p_vaddr, segment.p_memsz
+        );
+    }
+
+    /// Returns whether the virtual and physical address of every non-empty
+    /// loadable segment are the same, i.e. the kernel expects to run identity
+    /// mapped.
+    fn is_identity_linked(&mut self, header: &Header) -> bool {
+        let mut buffer = [0; SIZEOF_PHDR];
+        (0..u64::from(header.e_phnum)).all(|i| {
+            self.file
+                .set_position(header.e_phoff + (i * SIZEOF_PHDR as u64))
+                .expect("failed to set kernel file position to program header");
+            self.file
+                .read(self.context, &mut buffer)
+                .expect("failed to read kernel program header");
+            let program_header = ProgramHeader::from_bytes(&buffer)
+                .expect("failed to create program header from bytes");
+            program_header.p_type != PT_LOAD
+                || program_header.p_memsz == 0
+                || program_header.p_vaddr == program_header.p_paddr
+        })
+    }
+
+    /// Panics with an explanation of why the position-independent kernel can't
+    /// be loaded.
+    ///
+    /// Segments are loaded at their linked addresses and no relocations are
+    /// applied, so jumping to a PIE kernel would fault.
+    fn reject_position_independent(&mut self, header: &Header) -> ! {
+        let mut buffer = [0; SIZEOF_PHDR];
+        let dynamic_segment = (0..u64::from(header.e_phnum)).find_map(|i| {
+            self.file
+                .set_position(header.e_phoff + (i * SIZEOF_PHDR as u64))
+                .expect("failed to set kernel file position to program header");
+            self.file
+                .read(self.context, &mut buffer)
+                .expect("failed to read kernel program header");
+            let program_header = ProgramHeader::from_bytes(&buffer)
+                .expect("failed to create program header from bytes");
+            (program_header.p_type == PT_DYNAMIC).then_some(*program_header)
+        });
+        let dynamic_segment = dynamic_segment.unwrap_or_else(|| {
+            panic!(
+                "kernel appears to be PIE (ET_DYN) but has no PT_DYNAMIC segment, so there are no \
+                 relocations the loader can apply; link it as a static executable"
+            )
+        });
+
+        let mut has_rela = false;
+        let mut rela_size = 0;
+        // Each entry is a tag followed by a value.
+        let mut entry = [0; 16];
+        for offset in (0..dynamic_segment.p_filesz).step_by(entry.len()) {
+            self.file
+                .set_position(dynamic_segment.p_offset + offset)
+                .expect("failed to set kernel file position to dynamic entry");
+            self.file
+                .read(self.context, &mut entry)
+                .expect("failed to read kernel dynamic entry");
+            let tag = u64::from_le_bytes(entry[..8].try_into().expect("invalid dynamic tag"));
+
+            match tag {
+                DT_NULL => break,
+                DT_RELA => has_rela = true,
+                DT_RELASZ => {
+                    rela_size =
+                        u64::from_le_bytes(entry[8..].try_into().expect("invalid dynamic value"));
+                }
+                _ => {}
+            }
+        }
+
+        assert!(
+            has_rela,
+            "kernel appears to be PIE (ET_DYN) but has no DT_RELA relocations the loader can \
+             apply; link it as a static executable"
+        );
+        panic!(
+            "kernel is PIE (ET_DYN) with {rela_size:#x} bytes of relocations, but the loader \
+             doesn't apply relocations; link it as a static executable"
+        );
     }
 
     fn elf_sections(&mut self, header: &Header) -> &'static mut [ElfSection] {
@@ -95,7 +509,7 @@ impl Loader<'_> {
         // be overwritten by the kernel.
         let sections = self
             .context
-            .allocate_slice(program_header_count as usize, MemoryType::LOADER_DATA);
+            .allocate_small_slice(program_header_count as usize);
         let mut buffer = [0; SIZEOF_SHDR];
 
         let shstrtab_header = header.e_shoff + (u64::from(header.e_shstrndx) * SIZEOF_SHDR as u64);
@@ -103,7 +517,7 @@ impl Loader<'_> {
             .set_position(shstrtab_header)
             .expect("failed to set kernel file position to shstrtab header");
         self.file
-            .read(&mut buffer)
+            .read(self.context, &mut buffer)
             .expect("failed to read kernel shstrtab header");
         let shstrtab_section_header =
             SectionHeader::from_bytes(&buffer).expect("failed to create section header from bytes");
@@ -114,7 +528,7 @@ impl Loader<'_> {
                 .set_position(header.e_shoff + (i * SIZEOF_SHDR) as u64)
                 .expect("failed to set kernel file position to section header");
             self.file
-                .read(&mut buffer)
+                .read(self.context, &mut buffer)
                 .expect("failed to read kernel section header");
             let section_header = SectionHeader::from_bytes(&buffer)
                 .expect("failed to create section header from bytes");
@@ -125,7 +539,7 @@ impl Loader<'_> {
                 .set_position(name_position)
                 .expect("failed to set kernel file position to shstrab name position");
             self.file
-                .read(&mut name)
+                .read(self.context, &mut name)
                 .expect("failed to read kernel section name");
 
             uninit_section.write(ElfSection {
@@ -133,6 +547,7 @@ impl Loader<'_> {
                 start: section_header.sh_addr as usize,
                 size: section_header.sh_size as usize,
                 flags: section_header.sh_flags,
+                ty: section_header.sh_type,
             });
         }
 
@@ -140,18 +555,98 @@ impl Loader<'_> {
         unsafe { MaybeUninit::slice_assume_init_mut(sections) }
     }
 
-    fn handle_load_segment(&mut self, segment: &ProgramHeader) {
-        info!("loading segment: {segment:?}");
-        let slice = self.context.map_segment(segment);
-        info!("at paddr: {:x?}", slice.as_ptr());
+    fn handle_load_segment(
+        &mut self,
+        segment: &ProgramHeader,
+        identity_linked: bool,
+    ) -> Result<KernelSegment, SegmentAllocationError> {
+        let slice = self.context.map_segment(segment, identity_linked)?;
+        let kernel_segment = KernelSegment {
+            virtual_start: segment.p_vaddr as usize,
+            physical_start: slice.as_ptr() as usize,
+            size: segment.p_memsz as usize,
+            file_size: segment.p_filesz as usize,
+            flags: segment.p_flags,
+        };
+        if self.context.config.map_verbose {
+            debug!("mapped segment at paddr {:x?}: {segment:?}", slice.as_ptr());
+        }
 
         self.file
             .set_position(segment.p_offset)
             .expect("failed to set kernel file position to segment offset");
         self.file
-            .read(&mut slice[..segment.p_filesz as usize])
+            .read(self.context, &mut slice[..segment.p_filesz as usize])
             .expect("failed to read kernel segment");
 
         // The BSS section was already zeroed by `map_segment`.
+        Ok(kernel_segment)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use goblin::elf64::section_header::SHT_PROGBITS;
+
+    fn section(name: &str, ty: u32, flags: u32, start: usize, size: usize) -> ElfSection {
+        let mut section = ElfSection {
+            name: [0; 64],
+            start,
+            size,
+            flags: flags.into(),
+            ty,
+        };
+        section.name[..name.len()].copy_from_slice(name.as_bytes());
+        section
+    }
+
+    /// A segment at 0x1000 with 0x1000 bytes from the file followed by 0x2000
+    /// zeroed bytes.
+    const SEGMENT: KernelSegment = KernelSegment {
+        virtual_start: 0x1000,
+        physical_start: 0x10_1000,
+        size: 0x3000,
+        file_size: 0x1000,
+        flags: PF_W,
+    };
+
+    fn unzeroed(sections: &[ElfSection]) -> Vec<&str> {
+        unzeroed_bss_sections(sections, &[SEGMENT])
+            .map(ElfSection::name)
+            .collect()
+    }
+
+    #[test]
+    fn bss_in_zeroed_part_is_accepted() {
+        let sections = [
+            section(".bss", SHT_NOBITS, SHF_ALLOC, 0x2000, 0x2000),
+            section(".sbss", SHT_NOBITS, SHF_ALLOC, 0x3000, 0x800),
+        ];
+        assert!(unzeroed(&sections).is_empty());
+    }
+
+    #[test]
+    fn bss_overlapping_file_bytes_is_reported() {
+        let sections = [
+            section(".bss", SHT_NOBITS, SHF_ALLOC, 0x1800, 0x1000),
+            section(".bss.tail", SHT_NOBITS, SHF_ALLOC, 0x3800, 0x1000),
+            section(".bss.unmapped", SHT_NOBITS, SHF_ALLOC, 0x8000, 0x1000),
+        ];
+        assert_eq!(unzeroed(&sections), [".bss", ".bss.tail", ".bss.unmapped"]);
+    }
+
+    #[test]
+    fn other_sections_are_ignored() {
+        let sections = [
+            // With contents in the file.
+            section(".data", SHT_PROGBITS, SHF_ALLOC, 0x1000, 0x1000),
+            // Not loaded.
+            section(".comment", SHT_NOBITS, 0, 0x8000, 0x1000),
+            // A template the kernel instantiates itself.
+            section(".tbss", SHT_NOBITS, SHF_ALLOC | SHF_TLS, 0x8000, 0x1000),
+            section(".bss.empty", SHT_NOBITS, SHF_ALLOC, 0x8000, 0),
+        ];
+        assert!(unzeroed(&sections).is_empty());
     }
 }
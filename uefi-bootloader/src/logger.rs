@@ -1,17 +1,36 @@
+use crate::{boot_log::BOOT_LOG, font::Font, framebuffer::Framebuffer};
 use core::{
     fmt::{self, Write},
-    ptr,
+    sync::atomic::{AtomicBool, Ordering},
 };
-use font_constants::BACKUP_CHAR;
-use noto_sans_mono_bitmap::{
-    get_raster, get_raster_width, FontWeight, RasterHeight, RasterizedChar,
-};
-use spin::{Mutex, Once};
-use uefi_bootloader_api::{FrameBufferInfo, PixelFormat};
+use spin::{Mutex, MutexGuard, Once};
+use uefi_bootloader_api::FrameBufferInfo;
+
+/// The logger registered with the `log` crate.
+pub(crate) static GLOBAL_LOGGER: GlobalLogger = GlobalLogger;
 
-/// The global logger instance used for the `log` crate.
+/// The global framebuffer logger instance.
 pub(crate) static LOGGER: Once<LockedLogger> = Once::new();
 
+/// Whether the framebuffer logger was detached from its framebuffer.
+static DETACHED: AtomicBool = AtomicBool::new(false);
+
+/// Returns the framebuffer logger, unless there is none or it was detached.
+pub(crate) fn get() -> Option<&'static LockedLogger> {
+    LOGGER.get().filter(|_| !DETACHED.load(Ordering::Relaxed))
+}
+
+/// Stops drawing to the framebuffer, e.g. because it won't be passed to the
+/// kernel, until [`LockedLogger::set_frame_buffer`] is called.
+pub(crate) fn detach() {
+    DETACHED.store(true, Ordering::Relaxed);
+}
+
+/// Forwards log records to the framebuffer logger, if there is one, and to the
+/// boot log.
+#[derive(Debug)]
+pub(crate) struct GlobalLogger;
+
 /// A [`Logger`] instance protected by a spinlock.
 pub(crate) struct LockedLogger(Mutex<Logger>);
 
@@ -23,43 +42,68 @@ const LETTER_SPACING: usize = 0;
 /// Padding from the border. Prevent that font is too close to border.
 const BORDER_PADDING: usize = 1;
 
-/// Constants for the usage of the [`noto_sans_mono_bitmap`] crate.
-mod font_constants {
-    use super::{get_raster_width, FontWeight, RasterHeight};
+/// Height of the strip at the bottom of the screen that isn't used for text,
+/// so that it can be drawn to using [`crate::display::Display`].
+pub(crate) const RESERVED_HEIGHT: usize = 8;
 
-    /// Height of each char raster. The font size is ~0.84% of this. Thus, this
-    /// is the line height that enables multiple characters to be
-    /// side-by-side and appear optically in one line in a natural way.
-    pub(crate) const CHAR_RASTER_HEIGHT: RasterHeight = RasterHeight::Size16;
-
-    /// The width of each single symbol of the mono space font.
-    pub(crate) const CHAR_RASTER_WIDTH: usize =
-        get_raster_width(FontWeight::Regular, CHAR_RASTER_HEIGHT);
+impl LockedLogger {
+    /// Create a new instance that logs to the given framebuffer using the
+    /// given font.
+    pub(crate) fn new(
+        framebuffer: &'static mut [u8],
+        info: FrameBufferInfo,
+        font: &'static Font,
+    ) -> Self {
+        LockedLogger(Mutex::new(Logger::new(framebuffer, info, font)))
+    }
 
-    /// Backup character if a desired symbol is not available by the font.
-    /// The '�' character requires the feature "unicode-specials".
-    pub(crate) const BACKUP_CHAR: char = '�';
+    /// Switches to the given framebuffer, clearing it if `clear` is set.
+    ///
+    /// Text starts at the top left corner either way.
+    pub(crate) fn set_frame_buffer(
+        &self,
+        framebuffer: &'static mut [u8],
+        info: FrameBufferInfo,
+        clear: bool,
+    ) {
+        let mut logger = self.0.lock();
+        logger.framebuffer = Framebuffer::new(framebuffer, info);
+        if clear {
+            logger.clear();
+        } else {
+            logger.x_pos = BORDER_PADDING;
+            logger.y_pos = BORDER_PADDING;
+        }
+        DETACHED.store(false, Ordering::Relaxed);
+    }
 
-    pub(crate) const FONT_WEIGHT: FontWeight = FontWeight::Regular;
-}
+    /// Mirrors all output from now on to the given framebuffer, clearing it if
+    /// `clear` is set.
+    ///
+    /// Text is laid out for the primary framebuffer; whatever doesn't fit on
+    /// the mirror is cut off.
+    pub(crate) fn set_mirror(
+        &self,
+        framebuffer: &'static mut [u8],
+        info: FrameBufferInfo,
+        clear: bool,
+    ) {
+        let mut mirror = Framebuffer::new(framebuffer, info);
+        if clear {
+            mirror.clear();
+        }
+        self.0.lock().mirror = Some(mirror);
+    }
 
-/// Returns the raster of the given char or the raster of
-/// [`font_constants::BACKUP_CHAR`].
-fn get_char_raster(c: char) -> RasterizedChar {
-    fn get(c: char) -> Option<RasterizedChar> {
-        get_raster(
-            c,
-            font_constants::FONT_WEIGHT,
-            font_constants::CHAR_RASTER_HEIGHT,
-        )
-    }
-    get(c).unwrap_or_else(|| get(BACKUP_CHAR).expect("Should get raster of backup char."))
-}
+    /// Sets whether only the line moved to is cleared, instead of the whole
+    /// screen once it is full.
+    pub(crate) fn set_clear_lines(&self, clear_lines: bool) {
+        self.0.lock().clear_lines = clear_lines;
+    }
 
-impl LockedLogger {
-    /// Create a new instance that logs to the given framebuffer.
-    pub(crate) fn new(framebuffer: &'static mut [u8], info: FrameBufferInfo) -> Self {
-        LockedLogger(Mutex::new(Logger::new(framebuffer, info)))
+    /// Switches to the given font for all text written from now on.
+    pub(crate) fn set_font(&self, font: &'static Font) {
+        self.0.lock().font = font;
     }
 
     /// Force-unlocks the logger to prevent a deadlock.
@@ -72,6 +116,16 @@ impl LockedLogger {
         // SAFETY: Guaranteed by caller.
         unsafe { self.0.force_unlock() };
     }
+
+    /// Locks the logger, giving direct access to the framebuffer.
+    pub(crate) fn lock(&self) -> MutexGuard<'_, Logger> {
+        self.0.lock()
+    }
+
+    /// Locks the logger, unless it is already locked.
+    pub(crate) fn try_lock(&self) -> Option<MutexGuard<'_, Logger>> {
+        self.0.try_lock()
+    }
 }
 
 impl log::Log for LockedLogger {
@@ -87,30 +141,79 @@ impl log::Log for LockedLogger {
     fn flush(&self) {}
 }
 
+impl log::Log for GlobalLogger {
+    fn enabled(&self, _metadata: &log::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        if let Some(logger) = get() {
+            log::Log::log(logger, record);
+        }
+
+        let mut boot_log = BOOT_LOG.lock();
+        writeln!(boot_log, "{:5}: {}", record.level(), record.args()).unwrap();
+    }
+
+    fn flush(&self) {}
+}
+
 /// Allows logging text to a pixel-based framebuffer.
 pub(crate) struct Logger {
-    framebuffer: &'static mut [u8],
-    info: FrameBufferInfo,
+    framebuffer: Framebuffer,
+    /// A second framebuffer that everything drawn is also drawn to.
+    mirror: Option<Framebuffer>,
+    font: &'static Font,
     x_pos: usize,
     y_pos: usize,
+    /// Whether to clear one line at a time rather than the whole screen.
+    clear_lines: bool,
+    /// Whether to keep text out of the column the spinner is drawn in.
+    spinner_column: bool,
 }
 
 impl Logger {
-    /// Creates a new logger that uses the given framebuffer.
-    pub(crate) fn new(framebuffer: &'static mut [u8], info: FrameBufferInfo) -> Self {
+    /// Creates a new logger that uses the given framebuffer and font.
+    pub(crate) fn new(
+        framebuffer: &'static mut [u8],
+        info: FrameBufferInfo,
+        font: &'static Font,
+    ) -> Self {
         let mut logger = Self {
-            framebuffer,
-            info,
+            framebuffer: Framebuffer::new(framebuffer, info),
+            mirror: None,
+            font,
             x_pos: 0,
             y_pos: 0,
+            clear_lines: false,
+            spinner_column: false,
         };
         logger.clear();
         logger
     }
 
     fn newline(&mut self) {
-        self.y_pos += font_constants::CHAR_RASTER_HEIGHT.val() + LINE_SPACING;
+        self.y_pos += self.font.height() + LINE_SPACING;
         self.carriage_return();
+        if self.clear_lines {
+            if self.y_pos + self.font.height() + BORDER_PADDING >= self.text_height() {
+                self.y_pos = BORDER_PADDING;
+            }
+            self.clear_line();
+        }
+    }
+
+    /// Erases the text on the line at `self.y_pos`, including the spacing
+    /// below it.
+    fn clear_line(&mut self) {
+        let start = self.y_pos;
+        let end = start + self.font.height() + LINE_SPACING;
+        self.framebuffer
+            .clear_rows(start..end.min(self.text_height()));
+        if let Some(mirror) = &mut self.mirror {
+            let mirror_height = mirror.height();
+            mirror.clear_rows(start.min(mirror_height)..end.min(mirror_height));
+        }
     }
 
     fn carriage_return(&mut self) {
@@ -121,15 +224,33 @@ impl Logger {
     pub(crate) fn clear(&mut self) {
         self.x_pos = BORDER_PADDING;
         self.y_pos = BORDER_PADDING;
-        self.framebuffer.fill(0);
+        self.framebuffer.clear();
+        if let Some(mirror) = &mut self.mirror {
+            mirror.clear();
+        }
     }
 
-    fn width(&self) -> usize {
-        self.info.width
+    pub(crate) fn width(&self) -> usize {
+        self.framebuffer.width()
     }
 
-    fn height(&self) -> usize {
-        self.info.height
+    pub(crate) fn height(&self) -> usize {
+        self.framebuffer.height()
+    }
+
+    /// The width of the area text is written to.
+    fn text_width(&self) -> usize {
+        if self.spinner_column {
+            self.width()
+                .saturating_sub(self.font.width() + 2 * BORDER_PADDING)
+        } else {
+            self.width()
+        }
+    }
+
+    /// The height of the area text is written to.
+    fn text_height(&self) -> usize {
+        self.height().saturating_sub(RESERVED_HEIGHT)
     }
 
     /// Writes a single char to the framebuffer. Takes care of special control
@@ -140,43 +261,71 @@ impl Logger {
             '\n' => self.newline(),
             '\r' => self.carriage_return(),
             c => {
-                let new_xpos = self.x_pos + font_constants::CHAR_RASTER_WIDTH;
-                if new_xpos >= self.width() {
+                let new_xpos = self.x_pos + self.font.width();
+                if new_xpos >= self.text_width() {
                     self.newline();
                 }
-                let new_ypos =
-                    self.y_pos + font_constants::CHAR_RASTER_HEIGHT.val() + BORDER_PADDING;
-                if new_ypos >= self.height() {
+                let new_ypos = self.y_pos + self.font.height() + BORDER_PADDING;
+                if new_ypos >= self.text_height() {
                     self.clear();
                 }
-                self.write_rendered_char(&get_char_raster(c));
+                self.write_char_at(self.x_pos, self.y_pos, c);
+                self.x_pos += self.font.width() + LETTER_SPACING;
             }
         }
     }
 
-    /// Prints a rendered char into the framebuffer.
-    /// Updates `self.x_pos`.
-    fn write_rendered_char(&mut self, rendered_char: &RasterizedChar) {
-        for (y, row) in rendered_char.raster().iter().enumerate() {
-            for (x, byte) in row.iter().enumerate() {
-                self.write_pixel(self.x_pos + x, self.y_pos + y, *byte);
+    /// Prints a char with its top left corner at the given position, without
+    /// affecting the text cursor.
+    pub(crate) fn write_char_at(&mut self, x_pos: usize, y_pos: usize, c: char) {
+        let font = self.font;
+        font.draw(c, |x, y, intensity| {
+            self.write_pixel(x_pos + x, y_pos + y, intensity);
+        });
+    }
+
+    /// Sets whether text is kept out of the column at the right edge of the
+    /// screen that the spinner is drawn in.
+    pub(crate) fn set_spinner_column(&mut self, spinner_column: bool) {
+        self.spinner_column = spinner_column;
+    }
+
+    /// Draws a char in the top right corner of the screen, erasing the one
+    /// drawn there before, without affecting the text cursor.
+    pub(crate) fn draw_spinner(&mut self, c: char) {
+        let x = self
+            .width()
+            .saturating_sub(self.font.width() + BORDER_PADDING);
+        let (width, height) = (self.font.width(), self.font.height());
+        self.fill_rect(x, BORDER_PADDING, width, height, 0);
+        self.write_char_at(x, BORDER_PADDING, c);
+    }
+
+    /// Fills the given rectangle, clipped to the screen, with the text colour
+    /// at the given intensity.
+    pub(crate) fn fill_rect(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        intensity: u8,
+    ) {
+        for y in y..(y + height).min(self.height()) {
+            for x in x..(x + width).min(self.width()) {
+                self.write_pixel(x, y, intensity);
             }
         }
-        self.x_pos += rendered_char.width() + LETTER_SPACING;
     }
 
     fn write_pixel(&mut self, x: usize, y: usize, intensity: u8) {
-        let pixel_offset = y * self.info.stride + x;
-        let color = match self.info.pixel_format {
-            PixelFormat::Rgb => [intensity, intensity, intensity / 2, 0],
-            PixelFormat::Bgr => [intensity / 2, intensity, intensity, 0],
-        };
-        let bytes_per_pixel = self.info.bytes_per_pixel;
-        let byte_offset = pixel_offset * bytes_per_pixel;
-        self.framebuffer[byte_offset..(byte_offset + bytes_per_pixel)]
-            .copy_from_slice(&color[..bytes_per_pixel]);
-        // SAFETY: The frame buffer is valid.
-        let _ = unsafe { ptr::read_volatile(&self.framebuffer[byte_offset]) };
+        let color = [intensity, intensity, intensity / 2];
+        self.framebuffer.put_pixel(x, y, color);
+        if let Some(mirror) = &mut self.mirror {
+            if x < mirror.width() && y < mirror.height() {
+                mirror.put_pixel(x, y, color);
+            }
+        }
     }
 }
 
@@ -4,109 +4,350 @@
 
 #![allow(dead_code)]
 #![feature(step_trait, abi_efiapi, maybe_uninit_slice, maybe_uninit_write_slice)]
-#![no_std]
-#![no_main]
+// Unit tests run on the host, with the standard library.
+#![cfg_attr(not(test), no_std, no_main)]
 
+mod acpi;
 mod arch;
 mod boot_info;
+mod boot_log;
+mod chainload;
+mod config;
 mod context;
+mod display;
+mod fdt;
+mod font;
+mod framebuffer;
+mod fs;
+mod initrd;
+mod input;
 mod kernel;
 mod logger;
 mod mappings;
 mod memory;
+mod memory_attributes;
 mod modules;
+mod phase;
+mod spinner;
 mod util;
 
 use crate::arch::{jump_to_kernel, pre_context_switch_actions};
 use crate::memory::{Frame, VirtualAddress};
-use core::{fmt::Write, ptr::NonNull};
-use log::{error, info};
+use core::{ffi::c_void, mem::MaybeUninit, ptr::NonNull};
+use log::{debug, error, info, warn};
 use uefi::{
-    prelude::entry,
-    proto::console::gop::{self, GraphicsOutput},
+    prelude::cstr16,
+    proto::{
+        console::gop::{self, GraphicsOutput},
+        pi::mp::MpServices,
+    },
     table::{
         cfg::{ACPI2_GUID, ACPI_GUID},
+        runtime::VariableVendor,
         Boot, SystemTable,
     },
-    Handle, Status,
+    CStr16, Guid, Handle, Identify, Status,
+};
+use uefi_bootloader_api::{
+    BootInformation, BootServices, ConfigurationTable, Firmware, FrameBuffer, FrameBufferInfo,
+    PixelFormat, RsdpAddresses,
 };
-use uefi_bootloader_api::{BootInformation, FrameBuffer, FrameBufferInfo, PixelFormat};
 
 pub(crate) use context::{BootContext, RuntimeContext};
 
-static mut SYSTEM_TABLE: Option<NonNull<SystemTable<Boot>>> = None;
+/// The GUID of the configuration table entry holding the flattened device
+/// tree.
+const DEVICE_TREE_GUID: Guid =
+    Guid::from_values(0xb1b6_21d5, 0xf19c, 0x41a5, 0x830b, 0xd915_2c69_aae0);
 
-#[entry]
+#[cfg_attr(not(test), uefi::prelude::entry)]
 fn main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
-    let system_table_pointer = NonNull::from(&mut system_table);
-    // SAFETY: We are the sole thread.
-    unsafe { SYSTEM_TABLE = Some(system_table_pointer) };
+    // SAFETY: The system table isn't moved until `enter_boot_services` is called.
+    unsafe { phase::enter_console(NonNull::from(&mut system_table)) };
 
     system_table
         .stdout()
         .clear()
         .expect("failed to clear stdout");
 
-    let frame_buffer = get_frame_buffer(&system_table);
+    log::set_logger(&logger::GLOBAL_LOGGER).expect("logger already set");
+    log::set_max_level(log::LevelFilter::Trace);
+
+    let mut frame_buffer = get_frame_buffer(&system_table, None, 0);
     if let Some(frame_buffer) = frame_buffer {
-        init_logger(&frame_buffer);
+        init_logger(&frame_buffer, true);
         info!("using framebuffer at {:#x}", frame_buffer.start);
     }
 
-    // SAFETY: We are the sole thread.
-    unsafe { SYSTEM_TABLE = None };
+    phase::enter_boot_services();
 
-    let rsdp_address = get_rsdp_address(&system_table);
+    let rsdp_addresses = get_rsdp_addresses(&system_table);
+    let device_tree_address = get_device_tree_address(&system_table);
+    #[cfg(target_arch = "riscv64")]
+    arch::read_firmware_info(&system_table, device_tree_address);
+    let cpu_count = get_cpu_count(&system_table);
+    let firmware = get_firmware(&system_table);
+    info!(
+        "firmware: {} (revision {:#x})",
+        firmware.vendor(),
+        firmware.revision
+    );
+    let secure_boot_enabled = get_secure_boot_enabled(&system_table);
+    info!("secure boot enabled: {secure_boot_enabled}");
 
     let mut context = BootContext::new(handle, system_table);
-    let (entry_point, elf_sections) = context.load_kernel();
+    let configuration_tables = get_configuration_tables(&context);
+    context.load_font();
+    context.load_config();
+    if let Some(logger) = logger::LOGGER.get() {
+        logger.set_clear_lines(context.config.log_clear_lines);
+    }
+    context.enable_pause_on_error();
+    info!("loaded config");
+    context.protect_loader_image();
+
+    let device_path = context.config.gop_device_path;
+    let min_bits_per_pixel = context.config.min_bits_per_pixel;
+    if device_path.is_some() || min_bits_per_pixel != 0 {
+        let selected = get_frame_buffer(context.system_table(), device_path, min_bits_per_pixel);
+        match selected {
+            Some(selected) => {
+                let changed = frame_buffer.map_or(true, |frame_buffer| {
+                    frame_buffer.start != selected.start
+                        || frame_buffer.info.size != selected.info.size
+                        || frame_buffer.info.stride != selected.info.stride
+                        || frame_buffer.info.pitch_bytes != selected.info.pitch_bytes
+                });
+                if changed {
+                    init_logger(&selected, context.config.log_clear_screen);
+                    info!("using framebuffer at {:#x}", selected.start);
+                }
+                frame_buffer = Some(selected);
+            }
+            None if frame_buffer.is_some() && min_bits_per_pixel != 0 => {
+                logger::detach();
+                warn!(
+                    "no GOP mode has at least {min_bits_per_pixel} bits per pixel, continuing \
+                     without a framebuffer"
+                );
+                frame_buffer = None;
+            }
+            None => {}
+        }
+    }
+
+    if let Some(device_path) = context.config.mirror_gop_device_path {
+        init_mirror(
+            context.system_table(),
+            device_path,
+            frame_buffer.as_ref(),
+            context.config.log_clear_screen,
+        );
+    }
+
+    if context.config.dry_run {
+        info!("dry run: the kernel will be loaded but not entered");
+    }
+
+    if let Some(path) = context.config.chainload {
+        return context.chainload(path);
+    }
+    if !cfg!(feature = "embedded-kernel") && context.is_pe_image(context.config.kernel) {
+        return context.chainload(context.config.kernel);
+    }
+
+    context.start_spinner();
+    let kernel = match context.load_kernel() {
+        Ok(kernel) => kernel,
+        Err(error) => {
+            error!("failed to load kernel: {error}");
+            // The notification function mustn't outlive the bootloader image.
+            context.stop_spinner();
+            return Status::OUT_OF_RESOURCES;
+        }
+    };
     info!("loaded kernel");
+    let symbol_map = context.load_symbol_map();
+    let kernel_config = context.load_kernel_config();
+    let command_line = context.command_line();
+    let boot_logo = context.load_boot_logo(rsdp_addresses);
     // This may take a sec.
     info!("loading modules...");
-    let modules = context.load_modules();
+    let (modules, modules_address) = context.load_modules();
     info!("loaded modules");
+    context.stop_spinner();
 
-    let mut context = context.exit_boot_services();
+    let interrupts = context.config.interrupts;
+    let entry_point = if context.config.identity_map_kernel {
+        let physical_entry_point = kernel
+            .physical_entry_point
+            .expect("kernel entry point isn't in a loaded segment");
+        VirtualAddress::new_canonical(physical_entry_point.value())
+    } else {
+        kernel.entry_point
+    };
+    let dry_run = context.config.dry_run;
+    let prejump_delay_ms = context.config.prejump_delay_ms;
+    context.log_boot_services_statistics();
+    context.write_boot_log_file();
+    let mut context = if context.config.exit_boot_services {
+        context.exit_boot_services()
+    } else {
+        info!("not exiting boot services, the kernel must exit them itself");
+        context.keep_boot_services()
+    };
+    if let Some(frame_buffer) = &frame_buffer {
+        context
+            .frame_allocator
+            .reserve_frame_buffer(frame_buffer.start, frame_buffer.info.size);
+    }
 
-    let stack_top = context.set_up_mappings();
+    let stack = context.set_up_mappings();
+    if let Some(frame_buffer) = &mut frame_buffer {
+        context.map_frame_buffer(frame_buffer);
+    }
+    context.log_mapping_statistics();
     info!("created memory mappings");
 
     let page_table_frame = context.page_table();
+    let boot_services = context.boot_services;
     info!(
         "page table located at: {:#x}",
         page_table_frame.start_address()
     );
 
-    let boot_info = context.create_boot_info(frame_buffer, rsdp_address, modules, elf_sections);
+    let boot_info = context.create_boot_info(
+        frame_buffer,
+        rsdp_addresses,
+        device_tree_address,
+        cpu_count,
+        firmware,
+        modules,
+        modules_address,
+        kernel,
+        symbol_map,
+        command_line,
+        kernel_config,
+        stack,
+        boot_logo,
+        configuration_tables,
+        secure_boot_enabled,
+    );
     info!("created boot info: {boot_info:x?}");
 
+    if dry_run {
+        info!(
+            "dry run succeeded: entry point {entry_point:?}, {} elf sections, {} modules, {:#x} \
+             bytes of boot info, halting",
+            boot_info.elf_sections.len(),
+            boot_info.modules.len(),
+            boot_info.size
+        );
+        arch::halt();
+    }
+
     info!("running pre-context switch actions");
-    pre_context_switch_actions();
+    pre_context_switch_actions(interrupts);
 
     let context = KernelContext {
         page_table_frame,
-        stack_top,
+        stack_top: VirtualAddress::new_canonical(stack.top),
         entry_point,
         boot_info,
     };
 
     info!("about to jump to kernel: {context:x?}");
+    if prejump_delay_ms != 0 {
+        info!("waiting {prejump_delay_ms} ms before jumping to kernel");
+        delay(prejump_delay_ms, boot_services);
+    }
     // SAFETY: Everything is correctly mapped.
     unsafe { jump_to_kernel(context) };
 }
 
-fn get_frame_buffer(system_table: &SystemTable<Boot>) -> Option<FrameBuffer> {
-    let handle = system_table
-        .boot_services()
-        .get_handle_for_protocol::<GraphicsOutput<'_>>()
-        .ok()?;
+/// Waits for `ms` milliseconds, using the firmware's `Stall` if boot services
+/// weren't exited.
+fn delay(ms: usize, boot_services: Option<BootServices>) {
+    // SAFETY: The system table is valid as long as boot services weren't exited.
+    let system_table = boot_services.and_then(|boot_services| unsafe {
+        SystemTable::<Boot>::from_ptr(boot_services.system_table as *mut c_void)
+    });
+    match system_table {
+        Some(system_table) => system_table.boot_services().stall(ms * 1000),
+        None => arch::busy_wait(ms),
+    }
+}
+
+/// Returns the framebuffer of the GOP whose device path contains
+/// `device_path`, falling back to the first GOP.
+///
+/// If the current mode has less than `min_bits_per_pixel` bits per pixel, the
+/// first mode that has enough is set, and `None` is returned if there is none.
+fn get_frame_buffer(
+    system_table: &SystemTable<Boot>,
+    device_path: Option<&str>,
+    min_bits_per_pixel: usize,
+) -> Option<FrameBuffer> {
+    let handle = device_path.and_then(|device_path| {
+        let handle = find_gop_handle(system_table, device_path);
+        if handle.is_none() {
+            warn!("no GOP device path contains {device_path:?}, using the first GOP");
+        }
+        handle
+    });
+    let handle = match handle {
+        Some(handle) => handle,
+        None => system_table
+            .boot_services()
+            .get_handle_for_protocol::<GraphicsOutput<'_>>()
+            .ok()?,
+    };
+    open_frame_buffer(system_table, handle, min_bits_per_pixel)
+}
+
+/// Returns the framebuffer of the GOP on `handle`, first switching to a mode
+/// with at least `min_bits_per_pixel` bits per pixel if necessary.
+fn open_frame_buffer(
+    system_table: &SystemTable<Boot>,
+    handle: Handle,
+    min_bits_per_pixel: usize,
+) -> Option<FrameBuffer> {
     let mut gop = system_table
         .boot_services()
         .open_protocol_exclusive::<GraphicsOutput<'_>>(handle)
         .ok()?;
 
+    if min_bits_per_pixel != 0 {
+        log_modes(&gop);
+    }
+
+    let current_bits_per_pixel = bits_per_pixel(&gop.current_mode_info());
+    if current_bits_per_pixel < min_bits_per_pixel {
+        let (width, height) = gop.current_mode_info().resolution();
+        info!(
+            "rejected current GOP mode {width}x{height}: {current_bits_per_pixel} bits per pixel"
+        );
+        let mode = gop.modes().find(|mode| {
+            let bits_per_pixel = bits_per_pixel(mode.info());
+            let (width, height) = mode.info().resolution();
+            if bits_per_pixel < min_bits_per_pixel {
+                info!("rejected GOP mode {width}x{height}: {bits_per_pixel} bits per pixel");
+                false
+            } else {
+                true
+            }
+        })?;
+        gop.set_mode(&mode).ok()?;
+    }
+
     let mode_info = gop.current_mode_info();
     let mut frame_buffer = gop.frame_buffer();
+    // Broken firmware, and BltOnly modes, may have no linear framebuffer.
+    if frame_buffer.as_mut_ptr().is_null() || frame_buffer.size() == 0 {
+        warn!("ignoring GOP without a linear framebuffer");
+        return None;
+    }
+    let bytes_per_pixel = 4;
     let info = FrameBufferInfo {
         size: frame_buffer.size(),
         width: mode_info.resolution().0,
@@ -118,34 +359,217 @@ fn get_frame_buffer(system_table: &SystemTable<Boot>) -> Option<FrameBuffer> {
                 panic!("Bitmask and BltOnly framebuffers are not supported")
             }
         },
-        bytes_per_pixel: 4,
+        bytes_per_pixel,
         stride: mode_info.stride(),
+        pitch_bytes: pitch_bytes(
+            frame_buffer.size(),
+            mode_info.resolution().1,
+            mode_info.stride() * bytes_per_pixel,
+        ),
     };
 
     Some(FrameBuffer {
         start: frame_buffer.as_mut_ptr() as usize,
+        // The framebuffer is mapped into the kernel's address space after exiting boot
+        // services.
+        virtual_start: 0,
         info,
     })
 }
 
-fn init_logger(frame_buffer: &FrameBuffer) {
+/// Returns the number of bytes per row of a framebuffer of `size` bytes and
+/// `height` rows, whose rows are `computed` bytes long according to the GOP.
+///
+/// The GOP only reports the stride in pixels. Some firmware pads rows further,
+/// which shows as a framebuffer size that is an exact multiple of the height,
+/// slightly larger than the computed pitch. Larger sizes are more likely to be
+/// unused memory after the last row, so the computed pitch is kept.
+fn pitch_bytes(size: usize, height: usize, computed: usize) -> usize {
+    if height == 0 || size % height != 0 {
+        return computed;
+    }
+    let derived = size / height;
+    if derived > computed && derived < 2 * computed {
+        info!("framebuffer rows are padded to {derived:#x} bytes instead of {computed:#x}");
+        derived
+    } else {
+        computed
+    }
+}
+
+/// Logs all modes offered by `gop`, one line per mode.
+fn log_modes(gop: &GraphicsOutput<'_>) {
+    for (index, mode) in gop.modes().enumerate() {
+        let info = mode.info();
+        let (width, height) = info.resolution();
+        debug!(
+            "GOP mode {index}: {width}x{height} {:?}, stride {}",
+            info.pixel_format(),
+            info.stride()
+        );
+    }
+}
+
+/// Returns the number of bits per pixel of the given mode, or zero if it has no
+/// linear framebuffer or an unsupported pixel format.
+fn bits_per_pixel(mode_info: &gop::ModeInfo) -> usize {
+    match mode_info.pixel_format() {
+        gop::PixelFormat::Rgb | gop::PixelFormat::Bgr => 32,
+        gop::PixelFormat::Bitmask | gop::PixelFormat::BltOnly => 0,
+    }
+}
+
+/// Returns the first GOP handle whose device path, in its text form, contains
+/// `device_path`.
+fn find_gop_handle(system_table: &SystemTable<Boot>, device_path: &str) -> Option<Handle> {
+    util::find_handle_by_device_path(
+        system_table.boot_services(),
+        &GraphicsOutput::GUID,
+        "GOP",
+        device_path,
+    )
+}
+
+/// Mirrors log output to the framebuffer of the GOP whose device path
+/// contains `device_path`, unless it is the primary framebuffer, clearing it
+/// if `clear` is set.
+fn init_mirror(
+    system_table: &SystemTable<Boot>,
+    device_path: &str,
+    primary: Option<&FrameBuffer>,
+    clear: bool,
+) {
+    let Some(logger) = logger::get() else {
+        warn!("not mirroring log output, as there is no primary framebuffer");
+        return;
+    };
+    let Some(handle) = find_gop_handle(system_table, device_path) else {
+        warn!("no GOP device path contains {device_path:?}, not mirroring log output");
+        return;
+    };
+    let Some(mirror) = open_frame_buffer(system_table, handle, 0) else {
+        return;
+    };
+    if primary.map_or(false, |primary| primary.start == mirror.start) {
+        warn!("mirror GOP {device_path:?} is the primary GOP, not mirroring log output");
+        return;
+    }
+
+    // SAFETY: The hardware initialised the frame buffer.
+    let slice =
+        unsafe { core::slice::from_raw_parts_mut(mirror.start as *mut _, mirror.info.size) };
+    logger.set_mirror(slice, mirror.info, clear);
+    info!("mirroring log output to framebuffer at {:#x}", mirror.start);
+}
+
+/// Starts logging to `frame_buffer`, clearing it if `clear` is set or if it
+/// is the first framebuffer logged to.
+fn init_logger(frame_buffer: &FrameBuffer, clear: bool) {
     // SAFETY: The hardware initialised the frame buffer.
     let slice = unsafe {
         core::slice::from_raw_parts_mut(frame_buffer.start as *mut _, frame_buffer.info.size)
     };
-    let logger =
-        logger::LOGGER.call_once(move || logger::LockedLogger::new(slice, frame_buffer.info));
-    log::set_logger(logger).expect("logger already set");
-    log::set_max_level(log::LevelFilter::Trace);
+    match logger::LOGGER.get() {
+        Some(logger) => logger.set_frame_buffer(slice, frame_buffer.info, clear),
+        None => {
+            logger::LOGGER.call_once(move || {
+                logger::LockedLogger::new(slice, frame_buffer.info, &font::BUILTIN_FONT)
+            });
+        }
+    }
 }
 
-fn get_rsdp_address(system_table: &SystemTable<Boot>) -> Option<usize> {
-    let mut config_entries = system_table.config_table().iter();
-    // look for an ACPI2 RSDP first
-    let acpi2_rsdp = config_entries.find(|entry| matches!(entry.guid, ACPI2_GUID));
-    // if no ACPI2 RSDP is found, look for a ACPI1 RSDP
-    let rsdp = acpi2_rsdp.or_else(|| config_entries.find(|entry| matches!(entry.guid, ACPI_GUID)));
-    rsdp.map(|entry| entry.address as usize)
+fn get_rsdp_addresses(system_table: &SystemTable<Boot>) -> RsdpAddresses {
+    let find = |guid| {
+        system_table
+            .config_table()
+            .iter()
+            .find(|entry| entry.guid == guid)
+            .map(|entry| entry.address as usize)
+    };
+    RsdpAddresses {
+        acpi1: find(ACPI_GUID),
+        acpi2: find(ACPI2_GUID),
+    }
+}
+
+fn get_device_tree_address(system_table: &SystemTable<Boot>) -> Option<usize> {
+    system_table
+        .config_table()
+        .iter()
+        .find(|entry| entry.guid == DEVICE_TREE_GUID)
+        .map(|entry| entry.address as usize)
+}
+
+/// Copies the entries of the configuration table, so that they can be passed to
+/// the kernel.
+fn get_configuration_tables(context: &BootContext) -> &'static [ConfigurationTable] {
+    let entries = context.system_table().config_table();
+    let tables = context.allocate_small_slice(entries.len());
+    for (table, entry) in tables.iter_mut().zip(entries) {
+        table.write(ConfigurationTable {
+            // SAFETY: GUIDs are 16 bytes long, and any bytes are valid.
+            guid: unsafe { core::mem::transmute(entry.guid) },
+            address: entry.address as usize,
+        });
+    }
+    // SAFETY: We initialised all the tables.
+    unsafe { MaybeUninit::slice_assume_init_mut(tables) }
+}
+
+fn get_firmware(system_table: &SystemTable<Boot>) -> Firmware {
+    let mut vendor = [0; 64];
+    let mut len = 0;
+    let chars = char::decode_utf16(
+        system_table
+            .firmware_vendor()
+            .to_u16_slice()
+            .iter()
+            .copied(),
+    )
+    .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER));
+    for c in chars {
+        // The last byte is reserved for the null terminator.
+        if len + c.len_utf8() >= vendor.len() {
+            break;
+        }
+        len += c.encode_utf8(&mut vendor[len..]).len();
+    }
+
+    Firmware {
+        vendor,
+        revision: system_table.firmware_revision().0,
+    }
+}
+
+/// Returns whether Secure Boot is enforced, i.e. the `SecureBoot` variable is
+/// set and the platform isn't in setup mode. Missing variables count as
+/// disabled.
+fn get_secure_boot_enabled(system_table: &SystemTable<Boot>) -> bool {
+    let read = |name: &CStr16| {
+        let mut buffer = [0; 1];
+        system_table
+            .runtime_services()
+            .get_variable(name, &VariableVendor::GLOBAL_VARIABLE, &mut buffer)
+            .ok()
+            .and_then(|(value, _)| value.first().copied())
+    };
+    read(cstr16!("SecureBoot")) == Some(1) && read(cstr16!("SetupMode")) != Some(1)
+}
+
+fn get_cpu_count(system_table: &SystemTable<Boot>) -> Option<usize> {
+    let handle = system_table
+        .boot_services()
+        .get_handle_for_protocol::<MpServices>()
+        .ok()?;
+    let mp_services = system_table
+        .boot_services()
+        .open_protocol_exclusive::<MpServices>(handle)
+        .ok()?;
+    mp_services
+        .get_number_of_processors()
+        .ok()
+        .map(|count| count.enabled)
 }
 
 /// The context necessary to switch to the kernel.
@@ -157,11 +581,16 @@ struct KernelContext {
     boot_info: &'static BootInformation,
 }
 
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo<'_>) -> ! {
+    use core::fmt::Write;
+
     // SAFETY: We are the sole thread.
-    if let Some(mut system_table_pointer) = unsafe { SYSTEM_TABLE } {
-        // SAFETY: We are the sole thread.
+    let phase = unsafe { phase::current_from_panic() };
+    if let phase::Phase::Console(mut system_table_pointer) = phase {
+        // SAFETY: The phase guarantees the pointer is valid, and we are the sole
+        // thread.
         let system_table = unsafe { system_table_pointer.as_mut() };
         let _ = writeln!(system_table.stdout(), "{info}");
     }
@@ -170,7 +599,11 @@ fn panic(info: &core::panic::PanicInfo<'_>) -> ! {
         // SAFETY: We are the sole thread.
         unsafe { logger.force_unlock() };
     }
+    // SAFETY: We are the sole thread.
+    unsafe { boot_log::BOOT_LOG.force_unlock() };
     error!("{info}");
 
+    // SAFETY: We are the sole thread.
+    unsafe { input::pause_from_panic() };
     arch::halt();
 }
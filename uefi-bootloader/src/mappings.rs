@@ -1,52 +1,285 @@
 use crate::{
     jump_to_kernel,
-    memory::{Frame, FrameAllocator, Page, PhysicalAddress, PteFlags, VirtualAddress},
+    memory::{
+        Frame, FrameRange, Page, PageRange, PhysicalAddress, PteFlags, VirtualAddress, PAGE_SIZE,
+    },
+    util::calculate_pages,
     RuntimeContext,
 };
+use core::{mem, slice};
+use log::{debug, info};
+use uefi_bootloader_api::{FrameBuffer, Stack, STACK_GUARD_PATTERN};
+
+/// The number of bytes mapped into the kernel's address space, by purpose.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct MappingStatistics {
+    /// The kernel's segments, including their identity mappings.
+    pub(crate) segments: usize,
+    pub(crate) stack: usize,
+    pub(crate) anonymous_regions: usize,
+    pub(crate) frame_buffer: usize,
+    /// The identity mappings of firmware memory, if boot services weren't
+    /// exited.
+    pub(crate) firmware: usize,
+    /// Everything else, e.g. the kernel config and the context switch function.
+    pub(crate) other: usize,
+}
 
 impl RuntimeContext {
-    pub(crate) fn set_up_mappings(&mut self) -> VirtualAddress {
+    pub(crate) fn set_up_mappings(&mut self) -> Stack {
         // TODO: Enable nxe and write protect bits on x86_64.
 
         // TODO: Depend on kernel_config?
-        const STACK_SIZE: usize = 18 * 4096;
+        const STACK_SIZE: usize = 17 * 4096;
 
-        let stack_start_address = self.page_allocator.get_free_address(STACK_SIZE);
-
-        let stack_start = Page::containing_address(stack_start_address);
-        let stack_end = {
-            let end_address = stack_start_address + STACK_SIZE;
-            Page::containing_address(end_address - 1)
-        };
+        // These are mapped first so that the stack isn't placed on top of them.
+        if self.boot_services.is_some() {
+            self.identity_map_firmware_memory();
+        }
+        self.map_anonymous_regions();
 
-        // The +1 means the guard page isn't mapped to a frame.
-        for page in (stack_start + 1)..=stack_end {
-            let frame = self
-                .frame_allocator
-                .allocate_frame()
-                .expect("failed to allocate stack frame");
-            self.mapper.map(
-                page,
-                frame,
+        // The guard pages below the stack aren't mapped to frames.
+        let guard_size = self.config.stack_guard_pages * PAGE_SIZE;
+        let (stack_start, stack_frames) = self
+            .map_new_region_above_guard(
+                guard_size,
+                STACK_SIZE,
                 PteFlags::new()
                     .present(true)
                     .writable(true)
                     .no_execute(true),
+            )
+            .unwrap_or_else(|| {
+                panic!("out of memory: no {STACK_SIZE:#x} bytes of contiguous frames for the stack")
+            });
+        self.mapping_statistics.stack += STACK_SIZE;
+        if self.config.stack_guard_pattern {
+            // SAFETY: Physical memory is identity-mapped, and the frame was just
+            // allocated for the stack.
+            let words = unsafe {
+                slice::from_raw_parts_mut(
+                    stack_frames.value() as *mut u64,
+                    PAGE_SIZE / mem::size_of::<u64>(),
+                )
+            };
+            words.fill(STACK_GUARD_PATTERN);
+        }
+
+        let stack = Stack {
+            guard_start: stack_start.value() - guard_size,
+            bottom: stack_start.value(),
+            top: stack_start.value() + STACK_SIZE,
+        };
+
+        if self.config.map_verbose {
+            debug!("mapped stack: {stack:x?}");
+        }
+
+        // Identity-map the context switch function so that when it switches to the new
+        // page table, it continues executing. It is already mapped if firmware memory
+        // was identity-mapped.
+        let jump_page =
+            Page::containing_address(VirtualAddress::new_canonical(jump_to_kernel as usize));
+        if self.mapper.translate(jump_page.start_address()).is_none() {
+            self.mapper.map(
+                jump_page,
+                Frame::containing_address(PhysicalAddress::new_canonical(jump_to_kernel as usize)),
+                PteFlags::new().present(true),
                 &mut self.frame_allocator,
             );
+            self.mapping_statistics.other += PAGE_SIZE;
         }
 
-        // Identity-map the context switch function so that when it switches to the new
-        // page table, it continues executing.
-        self.mapper.map(
-            Page::containing_address(VirtualAddress::new_canonical(jump_to_kernel as usize)),
-            Frame::containing_address(PhysicalAddress::new_canonical(jump_to_kernel as usize)),
-            PteFlags::new().present(true),
-            &mut self.frame_allocator,
-        );
+        if self.config.map_verbose {
+            debug!(
+                "identity-mapped context switch function at {:#x}",
+                jump_to_kernel as usize
+            );
+        }
 
         crate::memory::set_up_arch_specific_mappings(self);
 
-        (stack_end + 1).start_address()
+        stack
+    }
+
+    /// Logs how many bytes were mapped into the kernel's address space, and
+    /// how many frames its page table takes.
+    ///
+    /// The boot info is mapped afterwards, so it isn't included.
+    pub(crate) fn log_mapping_statistics(&self) {
+        let MappingStatistics {
+            segments,
+            stack,
+            anonymous_regions,
+            frame_buffer,
+            firmware,
+            other,
+        } = self.mapping_statistics;
+        let total = segments + stack + anonymous_regions + frame_buffer + firmware + other;
+        info!(
+            "mapped {total:#x} bytes: {segments:#x} for segments, {stack:#x} for the stack, \
+             {anonymous_regions:#x} for anonymous regions, {frame_buffer:#x} for the framebuffer, \
+             {firmware:#x} for firmware memory, {other:#x} for the rest; {} page table frames",
+            self.mapper.page_table_frames()
+        );
+    }
+
+    /// Maps the framebuffer to free pages in the kernel's address space, and
+    /// sets its virtual start accordingly.
+    pub(crate) fn map_frame_buffer(&mut self, frame_buffer: &mut FrameBuffer) {
+        let flags = PteFlags::new()
+            .present(true)
+            .writable(true)
+            .no_execute(true);
+
+        let page_offset = frame_buffer.start % PAGE_SIZE;
+        let len = page_offset + frame_buffer.info.size;
+        let virtual_start = self.page_allocator.get_free_address(len);
+        let pages = PageRange::from_virt_addr(virtual_start, len);
+        let frames = FrameRange::from_phys_addr(
+            PhysicalAddress::new_canonical(frame_buffer.start - page_offset),
+            len,
+        );
+        for (page, frame) in pages.into_iter().zip(frames) {
+            self.mapper
+                .map(page, frame, flags, &mut self.frame_allocator);
+            self.mapping_statistics.frame_buffer += PAGE_SIZE;
+        }
+
+        frame_buffer.virtual_start = virtual_start.value() + page_offset;
+        if self.config.map_verbose {
+            debug!(
+                "mapped framebuffer at {:#x} to {:#x}",
+                frame_buffer.virtual_start, frame_buffer.start
+            );
+        }
+    }
+
+    /// Maps `size` bytes, rounded up to whole pages, at free pages to newly
+    /// allocated, zeroed, physically contiguous frames.
+    ///
+    /// Returns the virtual and physical start of the region, or `None` if there
+    /// aren't enough contiguous free frames, in which case nothing is mapped.
+    pub(crate) fn map_new_region(
+        &mut self,
+        size: usize,
+        flags: PteFlags,
+    ) -> Option<(VirtualAddress, PhysicalAddress)> {
+        self.map_new_region_above_guard(0, size, flags)
+    }
+
+    /// Like [`map_new_region`](Self::map_new_region), but leaves the
+    /// `guard_size` bytes below the region unmapped.
+    fn map_new_region_above_guard(
+        &mut self,
+        guard_size: usize,
+        size: usize,
+        flags: PteFlags,
+    ) -> Option<(VirtualAddress, PhysicalAddress)> {
+        let guard_start = self.page_allocator.get_free_address(guard_size + size);
+        let start = guard_start + guard_size;
+        let physical_start = self.map_zeroed(start, size, flags)?;
+        Some((start, physical_start))
+    }
+
+    /// Maps the `size` bytes at the page-aligned `start`, rounded up to whole
+    /// pages, to newly allocated, zeroed, physically contiguous frames.
+    ///
+    /// Returns the physical start, or `None` if there aren't enough contiguous
+    /// free frames, in which case nothing is mapped. The pages must be free;
+    /// marking them as used is up to the caller.
+    fn map_zeroed(
+        &mut self,
+        start: VirtualAddress,
+        size: usize,
+        flags: PteFlags,
+    ) -> Option<PhysicalAddress> {
+        let len = calculate_pages(size) * PAGE_SIZE;
+        let first_frame = self.frame_allocator.allocate_frames(len / PAGE_SIZE)?;
+        let physical_start = first_frame.start_address();
+        // SAFETY: Physical memory is identity-mapped, and the frames were just
+        // allocated.
+        unsafe { core::ptr::write_bytes(physical_start.value() as *mut u8, 0, len) };
+
+        let pages = PageRange::from_virt_addr(start, len);
+        for (page, frame) in pages
+            .into_iter()
+            .zip(FrameRange::from_phys_addr(physical_start, len))
+        {
+            self.mapper
+                .map(page, frame, flags, &mut self.frame_allocator);
+        }
+        Some(physical_start)
+    }
+
+    /// Identity-maps all memory in the memory map, as boot services must be
+    /// called with identity mapping.
+    ///
+    /// Pages that are already mapped, e.g. those of an identity-mapped kernel,
+    /// are left as they are.
+    fn identity_map_firmware_memory(&mut self) {
+        let flags = PteFlags::new().present(true).writable(true);
+
+        for descriptor in self.frame_allocator.descriptors() {
+            let start = VirtualAddress::new_canonical(descriptor.phys_start as usize);
+            let len = descriptor.page_count as usize * PAGE_SIZE;
+            if len == 0 {
+                continue;
+            }
+            self.page_allocator.mark_range_as_used(start, len);
+
+            for page in PageRange::from_virt_addr(start, len) {
+                if self.mapper.translate(page.start_address()).is_some() {
+                    continue;
+                }
+                let frame = Frame::containing_address(PhysicalAddress::new_canonical(
+                    page.start_address().value(),
+                ));
+                self.mapper
+                    .map(page, frame, flags, &mut self.frame_allocator);
+                self.mapping_statistics.firmware += PAGE_SIZE;
+            }
+        }
+
+        if self.config.map_verbose {
+            debug!("identity-mapped firmware memory");
+        }
+    }
+
+    /// Maps the anonymous regions requested in the config to zeroed frames.
+    fn map_anonymous_regions(&mut self) {
+        let flags = PteFlags::new()
+            .present(true)
+            .writable(true)
+            .no_execute(true);
+
+        for (start, size) in self.config.anonymous_regions() {
+            let start = VirtualAddress::new(start)
+                .unwrap_or_else(|| panic!("anonymous region at {start:#x} isn't canonical"));
+            assert!(
+                start.value() % PAGE_SIZE == 0 && size > 0,
+                "anonymous region at {start:?} must be page aligned and non-empty"
+            );
+            self.page_allocator.mark_range_as_used(start, size);
+
+            for page in PageRange::from_virt_addr(start, size) {
+                assert!(
+                    self.mapper.translate(page.start_address()).is_none(),
+                    "anonymous region at {start:?} (length {size:#x}) overlaps an existing \
+                     mapping at {page:?}"
+                );
+            }
+            self.map_zeroed(start, size, flags).unwrap_or_else(|| {
+                panic!(
+                    "out of memory: no {size:#x} bytes of contiguous frames for the anonymous \
+                     region at {start:?}"
+                )
+            });
+            self.mapping_statistics.anonymous_regions += calculate_pages(size) * PAGE_SIZE;
+
+            if self.config.map_verbose {
+                debug!("mapped anonymous region at {start:?} (length {size:#x})");
+            }
+        }
     }
 }
@@ -6,26 +6,44 @@ use core::{
     fmt,
     iter::Step,
     mem::MaybeUninit,
-    ops::{Add, AddAssign, Deref, DerefMut, RangeInclusive, Sub, SubAssign},
+    ops::{Add, AddAssign, Deref, DerefMut, Range, RangeInclusive, Sub, SubAssign},
 };
 use derive_more::{
     Add, AddAssign, Binary, BitAnd, BitAndAssign, BitOr, BitOrAssign, BitXor, BitXorAssign,
     LowerHex, Octal, Sub, SubAssign, UpperHex,
 };
+use log::{info, warn};
 use paste::paste;
 use uefi::table::{
     boot::{AllocateType, MemoryDescriptor, MemoryMapIter, MemoryType},
     Boot, SystemTable,
 };
-use uefi_bootloader_api::{MemoryRegion, MemoryRegionKind};
+use uefi_bootloader_api::{MemoryAttributes, MemoryRegion, MemoryRegionKind};
 use zerocopy::FromBytes;
 
 pub(crate) use imp::{set_up_arch_specific_mappings, Mapper, PageAllocator, PteFlags};
 
 pub(crate) const PAGE_SIZE: usize = 4096;
+/// The size of the unmapped gap the page allocator keeps after every region of
+/// the kernel's address space, so that an access just past a region faults
+/// rather than landing in the next one.
+pub(crate) const GUARD_SIZE: usize = PAGE_SIZE;
 const MAX_PAGE_NUMBER: usize = usize::MAX / PAGE_SIZE;
 
-pub(crate) const KERNEL_MEMORY: MemoryType = MemoryType::custom(0xffff_ffff);
+pub(crate) const PAGE_TABLE_MEMORY: MemoryType =
+    MemoryType::custom(uefi_bootloader_api::PAGE_TABLE_MEMORY_TYPE);
+pub(crate) const KERNEL_IMAGE_MEMORY: MemoryType =
+    MemoryType::custom(uefi_bootloader_api::KERNEL_IMAGE_MEMORY_TYPE);
+pub(crate) const KERNEL_CONFIG_MEMORY: MemoryType =
+    MemoryType::custom(uefi_bootloader_api::KERNEL_CONFIG_MEMORY_TYPE);
+pub(crate) const BOOT_LOGO_MEMORY: MemoryType =
+    MemoryType::custom(uefi_bootloader_api::BOOT_LOGO_MEMORY_TYPE);
+
+// Memory types below 0x8000_0000 are reserved by the UEFI specification.
+const _: () = assert!(KERNEL_IMAGE_MEMORY.0 >= 0x8000_0000);
+const _: () = assert!(KERNEL_CONFIG_MEMORY.0 >= 0x8000_0000);
+const _: () = assert!(BOOT_LOGO_MEMORY.0 >= 0x8000_0000);
+const _: () = assert!(PAGE_TABLE_MEMORY.0 >= 0x8000_0000);
 
 /// A macro for defining `VirtualAddress` and `PhysicalAddress` structs
 /// and implementing their common traits, which are generally identical.
@@ -367,21 +385,96 @@ macro_rules! implement_page_frame_range {
 implement_page_frame_range!(PageRange, "virtual", virt, Page, VirtualAddress);
 implement_page_frame_range!(FrameRange, "physical", phys, Frame, PhysicalAddress);
 
+/// The `EFI_MEMORY_NV` attribute, marking persistent memory.
+const NON_VOLATILE_ATTRIBUTE: u64 = 0x8000;
+/// The `EFI_MEMORY_SP` attribute, marking memory meant for specific uses
+/// (e.g. high-bandwidth memory).
+const SPECIFIC_PURPOSE_ATTRIBUTE: u64 = 0x4_0000;
+/// The memory attributes defined by the UEFI specification (as of 2.10).
+///
+/// Besides the cacheability and protection attributes, these are
+/// `EFI_MEMORY_MORE_RELIABLE`, `EFI_MEMORY_CPU_CRYPTO`,
+/// `EFI_MEMORY_HOT_PLUGGABLE`, `EFI_MEMORY_ISA_VALID` with its
+/// `EFI_MEMORY_ISA_MASK`, and `EFI_MEMORY_RUNTIME`, none of which make memory
+/// unsafe to use.
+const KNOWN_ATTRIBUTES: u64 = 0x8000_0000_0000_0000 | 0x4fff_f000_0000_0000 | 0x1f_f000 | 0x1f;
+
 fn descriptor_kind(memory_descriptor: &MemoryDescriptor) -> MemoryRegionKind {
     match memory_descriptor.ty {
+        MemoryType::PERSISTENT_MEMORY => MemoryRegionKind::Persistent,
+        PAGE_TABLE_MEMORY => MemoryRegionKind::Bootloader,
         MemoryType::CONVENTIONAL
         | MemoryType::LOADER_CODE
         | MemoryType::LOADER_DATA
         | MemoryType::BOOT_SERVICES_CODE
-        | MemoryType::BOOT_SERVICES_DATA => MemoryRegionKind::Usable,
+        | MemoryType::BOOT_SERVICES_DATA => free_memory_kind(memory_descriptor.att.bits()),
         tag => MemoryRegionKind::UnknownUefi(tag.0),
     }
 }
 
+/// Returns the kind of memory the firmware considers free, given its
+/// attributes.
+///
+/// Only attributes changing how the memory may be used demote it from
+/// [`Usable`](MemoryRegionKind::Usable), as well as attributes newer than
+/// [`KNOWN_ATTRIBUTES`], whose meaning can't be known.
+fn free_memory_kind(attributes: u64) -> MemoryRegionKind {
+    if attributes & NON_VOLATILE_ATTRIBUTE != 0 {
+        MemoryRegionKind::Persistent
+    } else if attributes & SPECIFIC_PURPOSE_ATTRIBUTE != 0 {
+        MemoryRegionKind::ReservedForSpecificPurpose
+    } else if attributes & !KNOWN_ATTRIBUTES != 0 {
+        MemoryRegionKind::Reserved
+    } else {
+        MemoryRegionKind::Usable
+    }
+}
+
+/// Returns the kind of memory region a descriptor is reported as while boot
+/// services are still active.
+///
+/// Memory the firmware hands out is still owned by it, and the memory map is
+/// only a snapshot, so none of it is reported as usable.
+fn firmware_descriptor_kind(memory_descriptor: &MemoryDescriptor) -> MemoryRegionKind {
+    match memory_descriptor.ty {
+        ty @ (MemoryType::CONVENTIONAL
+        | MemoryType::LOADER_CODE
+        | MemoryType::LOADER_DATA
+        | MemoryType::BOOT_SERVICES_CODE
+        | MemoryType::BOOT_SERVICES_DATA) => MemoryRegionKind::UnknownUefi(ty.0),
+        _ => descriptor_kind(memory_descriptor),
+    }
+}
+
+/// Allocates frames for the kernel's page table and the memory mapped into it.
+///
+/// [`UefiFrameAllocator`] is used before exiting boot services, and
+/// [`LegacyFrameAllocator`] afterwards. The mappers of all architectures are
+/// generic over this trait, so both go through the same mapping code.
 pub(crate) trait FrameAllocator {
+    /// Returns an unused frame, or `None` if there are no free frames left.
     fn allocate_frame(&mut self) -> Option<Frame>;
 }
 
+/// Counts the frames allocated through it, e.g. by a mapper for page tables.
+pub(crate) struct CountingFrameAllocator<'a, T> {
+    pub(crate) inner: &'a mut T,
+    pub(crate) count: &'a mut usize,
+}
+
+impl<T> FrameAllocator for CountingFrameAllocator<'_, T>
+where
+    T: FrameAllocator,
+{
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        let frame = self.inner.allocate_frame()?;
+        *self.count += 1;
+        Some(frame)
+    }
+}
+
+/// Allocates the frames of the kernel's page table from the firmware before
+/// exiting boot services.
 pub(crate) struct UefiFrameAllocator<'a> {
     pub(crate) system_table: &'a SystemTable<Boot>,
 }
@@ -390,7 +483,7 @@ impl FrameAllocator for UefiFrameAllocator<'_> {
     fn allocate_frame(&mut self) -> Option<Frame> {
         self.system_table
             .boot_services()
-            .allocate_pages(AllocateType::AnyPages, KERNEL_MEMORY, 1)
+            .allocate_pages(AllocateType::AnyPages, PAGE_TABLE_MEMORY, 1)
             .ok()
             .map(|address| {
                 Frame::containing_address(PhysicalAddress::new_canonical(address as usize))
@@ -398,112 +491,412 @@ impl FrameAllocator for UefiFrameAllocator<'_> {
     }
 }
 
+/// The legacy VGA and ISA hole, which may alias MMIO even if the firmware
+/// reports it as usable.
+const LEGACY_HOLE: Range<usize> = 0xa_0000..0x10_0000;
+
 pub(crate) struct LegacyFrameAllocator {
     original: MemoryMapIter<'static>,
     memory_map: MemoryMapIter<'static>,
     current_descriptor: Option<CurrentDescriptor>,
+    /// The page-aligned physical range of the framebuffer, if reserved.
+    frame_buffer: Option<Range<usize>>,
+    /// The sorted, disjoint ranges of low memory that are never allocated and
+    /// are reported as [`Reserved`](MemoryRegionKind::Reserved).
+    low_reserved: [Range<usize>; 2],
+    /// The system table, if boot services weren't exited, in which case each
+    /// frame is claimed from the firmware before it is returned.
+    boot_services: Option<SystemTable<Boot>>,
 }
 
+#[derive(Clone)]
 struct CurrentDescriptor {
     descriptor: &'static MemoryDescriptor,
     next_frame: Frame,
 }
 
 impl LegacyFrameAllocator {
-    pub(crate) fn new(memory_map: MemoryMapIter<'static>) -> Self {
+    /// Creates a frame allocator that allocates from the usable memory in
+    /// `memory_map`, excluding the legacy VGA hole and the first
+    /// `low_reserve` bytes of memory.
+    pub(crate) fn new(memory_map: MemoryMapIter<'static>, low_reserve: usize) -> Self {
+        let low_reserve = low_reserve.next_multiple_of(PAGE_SIZE);
+        let low_reserved = if low_reserve < LEGACY_HOLE.start {
+            [0..low_reserve, LEGACY_HOLE]
+        } else {
+            [0..max(low_reserve, LEGACY_HOLE.end), 0..0]
+        };
+        for range in low_reserved.iter().filter(|range| !range.is_empty()) {
+            info!("reserving low memory: {range:#x?}");
+        }
+        for descriptor in memory_map.clone() {
+            let unknown = descriptor.att.bits() & !KNOWN_ATTRIBUTES;
+            if unknown != 0 && descriptor_kind(descriptor) == MemoryRegionKind::Reserved {
+                warn!(
+                    "reporting memory at {:#x} (length {:#x}) as reserved, as it has unknown \
+                     attributes {unknown:#x}",
+                    descriptor.phys_start,
+                    descriptor.page_count as usize * PAGE_SIZE
+                );
+            }
+        }
+
         Self {
             original: memory_map.clone(),
             memory_map,
             current_descriptor: None,
+            frame_buffer: None,
+            low_reserved,
+            boot_services: None,
         }
     }
 
+    /// Allocates frames from the firmware instead of the memory map, as boot
+    /// services stay active.
+    ///
+    /// The memory map is then only used to report regions, with all memory the
+    /// firmware may still hand out reported as
+    /// [`UnknownUefi`](MemoryRegionKind::UnknownUefi).
+    pub(crate) fn claim_from_firmware(&mut self, system_table: SystemTable<Boot>) {
+        self.boot_services = Some(system_table);
+    }
+
+    /// Returns the descriptors of the memory map the allocator was created
+    /// from.
+    pub(crate) fn descriptors(&self) -> MemoryMapIter<'static> {
+        self.original.clone()
+    }
+
+    /// Returns the number of regions needed to hold the memory map.
+    ///
+    /// The regions are counted with the same pass that constructs the memory
+    /// map, so the count is exact for the current state of the allocator.
+    /// Allocating the frames holding the boot info can move the allocator to
+    /// the next descriptor, which splits at most one more region, and this can
+    /// change how the framebuffer is carved out of the regions. The returned
+    /// length accounts for both.
     pub(crate) fn len(&self) -> usize {
-        // At most, one descriptor can be split.
-        self.original.clone().count() + 2
+        const SLACK: usize = 2;
+
+        let mut count = 0;
+        self.for_each_region(|_| count += 1);
+        count + SLACK
     }
 
-    fn allocate_frame_from_current(&mut self) -> Option<Frame> {
+    /// Excludes the framebuffer from the allocatable frames, and reports it as
+    /// a [`Framebuffer`](MemoryRegionKind::Framebuffer) region.
+    ///
+    /// Some firmware reports the framebuffer as conventional memory. This must
+    /// be called before any frames are allocated.
+    pub(crate) fn reserve_frame_buffer(&mut self, start: usize, size: usize) {
+        assert!(
+            self.current_descriptor.is_none(),
+            "reserved framebuffer after allocating frames"
+        );
+        let end = (start + size).next_multiple_of(PAGE_SIZE);
+        let start = start - (start % PAGE_SIZE);
+        self.frame_buffer = Some(start..end);
+    }
+
+    fn allocate_frames_from_current(&mut self, count: usize) -> Option<Frame> {
         let current_descriptor = self.current_descriptor.as_mut()?;
 
+        // Skip past the excluded ranges overlapping the frames until none do. Each
+        // skip moves forward, so this ends.
+        loop {
+            let start = current_descriptor.next_frame.start_address().value();
+            let end = start + count * PAGE_SIZE;
+            let skip_to = self
+                .low_reserved
+                .iter()
+                .chain(&self.frame_buffer)
+                .filter(|range| range.start < end && start < range.end)
+                .map(|range| range.end)
+                .max();
+            match skip_to {
+                Some(address) => {
+                    current_descriptor.next_frame =
+                        Frame::containing_address(PhysicalAddress::new_canonical(address));
+                }
+                None => break,
+            }
+        }
+
         let start_address =
             PhysicalAddress::new_canonical(current_descriptor.descriptor.phys_start as usize);
         let end_address =
             start_address + (current_descriptor.descriptor.page_count as usize * PAGE_SIZE);
 
-        let end_frame = Frame::containing_address(end_address - 1);
-
-        if current_descriptor.next_frame <= end_frame {
+        let end = current_descriptor.next_frame.start_address().value() + count * PAGE_SIZE;
+        if end <= end_address.value() {
             let frame = current_descriptor.next_frame;
-            current_descriptor.next_frame += 1;
+            current_descriptor.next_frame += count;
             Some(frame)
         } else {
             None
         }
     }
 
-    pub(crate) fn construct_memory_map(
-        self,
-        memory_map: &mut [MaybeUninit<MemoryRegion>],
-    ) -> &mut [MemoryRegion] {
+    /// Calls `f` with each region of the memory map, in the order of the
+    /// firmware's descriptors, followed by the framebuffer.
+    fn for_each_region<F>(&self, mut f: F)
+    where
+        F: FnMut(MemoryRegion),
+    {
+        let frame_buffer = self.frame_buffer.as_ref();
+        if self.boot_services.is_some() {
+            for descriptor in self.original.clone() {
+                carve_region(
+                    MemoryRegion {
+                        start: descriptor.phys_start as usize,
+                        len: descriptor.page_count as usize * PAGE_SIZE,
+                        kind: firmware_descriptor_kind(descriptor),
+                        attributes: MemoryAttributes::from_bits_truncate(descriptor.att.bits()),
+                    },
+                    frame_buffer,
+                    &self.low_reserved,
+                    &mut f,
+                );
+            }
+            if let Some(frame_buffer) = frame_buffer {
+                f(MemoryRegion {
+                    start: frame_buffer.start,
+                    len: frame_buffer.len(),
+                    kind: MemoryRegionKind::Framebuffer,
+                    attributes: MemoryAttributes::empty(),
+                });
+            }
+            return;
+        }
+
         // We definetly allocated at least one frame, right?
         let current_descriptor = self
             .current_descriptor
+            .as_ref()
             .expect("failed to get current descriptor");
-        let mut index = 0;
         let mut iterated_through_used_descriptors = false;
 
-        for descriptor in self.original {
+        for descriptor in self.original.clone() {
+            let attributes = MemoryAttributes::from_bits_truncate(descriptor.att.bits());
             if iterated_through_used_descriptors
                 || descriptor.phys_start < 0x1_0000
                 || descriptor_kind(descriptor) != MemoryRegionKind::Usable
             {
-                memory_map[index].write(MemoryRegion {
-                    start: descriptor.phys_start as usize,
-                    len: descriptor.page_count as usize * PAGE_SIZE,
-                    kind: descriptor_kind(descriptor),
-                });
-                index += 1;
+                carve_region(
+                    MemoryRegion {
+                        start: descriptor.phys_start as usize,
+                        len: descriptor.page_count as usize * PAGE_SIZE,
+                        kind: descriptor_kind(descriptor),
+                        attributes,
+                    },
+                    frame_buffer,
+                    &self.low_reserved,
+                    &mut f,
+                );
             } else if descriptor.phys_start == current_descriptor.descriptor.phys_start {
                 let used_len = current_descriptor.next_frame.start_address().value()
                     - descriptor.phys_start as usize;
-                memory_map[index].write(MemoryRegion {
-                    start: descriptor.phys_start as usize,
-                    len: used_len,
-                    kind: MemoryRegionKind::Bootloader,
-                });
-
-                index += 1;
+                carve_region(
+                    MemoryRegion {
+                        start: descriptor.phys_start as usize,
+                        len: used_len,
+                        kind: MemoryRegionKind::Bootloader,
+                        attributes,
+                    },
+                    frame_buffer,
+                    &self.low_reserved,
+                    &mut f,
+                );
 
                 let remaining_len = (descriptor.page_count as usize * PAGE_SIZE) - used_len;
                 if remaining_len > 0 {
-                    memory_map[index].write(MemoryRegion {
-                        start: descriptor.phys_start as usize + used_len,
-                        len: remaining_len,
-                        kind: MemoryRegionKind::Usable,
-                    });
-                    index += 1;
+                    carve_region(
+                        MemoryRegion {
+                            start: descriptor.phys_start as usize + used_len,
+                            len: remaining_len,
+                            kind: MemoryRegionKind::Usable,
+                            attributes,
+                        },
+                        frame_buffer,
+                        &self.low_reserved,
+                        &mut f,
+                    );
                 }
 
                 iterated_through_used_descriptors = true;
             } else {
-                memory_map[index].write(MemoryRegion {
-                    start: descriptor.phys_start as usize,
-                    len: descriptor.page_count as usize * PAGE_SIZE,
-                    kind: MemoryRegionKind::Bootloader,
-                });
-                index += 1;
+                carve_region(
+                    MemoryRegion {
+                        start: descriptor.phys_start as usize,
+                        len: descriptor.page_count as usize * PAGE_SIZE,
+                        kind: MemoryRegionKind::Bootloader,
+                        attributes,
+                    },
+                    frame_buffer,
+                    &self.low_reserved,
+                    &mut f,
+                );
             }
         }
 
+        if let Some(frame_buffer) = frame_buffer {
+            f(MemoryRegion {
+                start: frame_buffer.start,
+                len: frame_buffer.len(),
+                kind: MemoryRegionKind::Framebuffer,
+                attributes: MemoryAttributes::empty(),
+            });
+        }
+    }
+
+    /// Writes the memory map to `memory_map`, which should be at least
+    /// [`len`](Self::len) regions long, sorted by start address.
+    pub(crate) fn construct_memory_map(
+        self,
+        memory_map: &mut [MaybeUninit<MemoryRegion>],
+    ) -> &mut [MemoryRegion] {
+        let capacity = memory_map.len();
+        let mut index = 0;
+
+        self.for_each_region(|region| {
+            assert!(
+                index < capacity,
+                "memory map doesn't fit in the {capacity} regions allocated for it"
+            );
+            memory_map[index].write(region);
+            index += 1;
+        });
+
         // SAFETY: We initialised all the items up to `index`.
-        unsafe { MaybeUninit::slice_assume_init_mut(&mut memory_map[..index]) }
+        let memory_map = unsafe { MaybeUninit::slice_assume_init_mut(&mut memory_map[..index]) };
+        // The framebuffer comes last, and the firmware's descriptors aren't
+        // guaranteed to be sorted either. The regions are disjoint, so the order
+        // is total.
+        memory_map.sort_unstable_by_key(|region| region.start);
+        memory_map
+    }
+}
+
+/// Calls `f` with the parts of `region` that don't overlap `reserved`, with
+/// the parts overlapping `low_reserved` marked as reserved.
+fn carve_region<F>(
+    region: MemoryRegion,
+    reserved: Option<&Range<usize>>,
+    low_reserved: &[Range<usize>],
+    f: &mut F,
+) where
+    F: FnMut(MemoryRegion),
+{
+    let end = region.start + region.len;
+    let Some(reserved) = reserved else {
+        mark_reserved(region, low_reserved, f);
+        return;
+    };
+
+    for (start, end) in [
+        (region.start, min(end, reserved.start)),
+        (max(region.start, reserved.end), end),
+    ] {
+        if start < end {
+            mark_reserved(
+                MemoryRegion {
+                    start,
+                    len: end - start,
+                    kind: region.kind,
+                    attributes: region.attributes,
+                },
+                low_reserved,
+                f,
+            );
+        }
+    }
+}
+
+/// Calls `f` with `region` split at the boundaries of the sorted, disjoint
+/// `reserved` ranges, with the parts overlapping them marked as reserved if
+/// the region is usable or used by the bootloader.
+fn mark_reserved<F>(region: MemoryRegion, reserved: &[Range<usize>], f: &mut F)
+where
+    F: FnMut(MemoryRegion),
+{
+    if !matches!(
+        region.kind,
+        MemoryRegionKind::Usable | MemoryRegionKind::Bootloader
+    ) {
+        f(region);
+        return;
+    }
+
+    let piece = |start: usize, end: usize, kind| MemoryRegion {
+        start,
+        len: end - start,
+        kind,
+        attributes: region.attributes,
+    };
+    let end = region.start + region.len;
+    let mut start = region.start;
+
+    for range in reserved {
+        let overlap_start = max(start, range.start);
+        let overlap_end = min(end, range.end);
+        if overlap_start >= overlap_end {
+            continue;
+        }
+        if start < overlap_start {
+            f(piece(start, overlap_start, region.kind));
+        }
+        f(piece(
+            overlap_start,
+            overlap_end,
+            MemoryRegionKind::Reserved,
+        ));
+        start = overlap_end;
+    }
+    if start < end {
+        f(piece(start, end, region.kind));
     }
 }
 
 impl FrameAllocator for LegacyFrameAllocator {
     fn allocate_frame(&mut self) -> Option<Frame> {
-        if let Some(frame) = self.allocate_frame_from_current() {
+        self.allocate_frames(1)
+    }
+}
+
+impl LegacyFrameAllocator {
+    /// Returns the first of `count` physically contiguous unused frames, or
+    /// `None` if there is no such range left.
+    pub(crate) fn allocate_frames(&mut self, count: usize) -> Option<Frame> {
+        let Some(system_table) = &self.boot_services else {
+            return self.next_frames(count);
+        };
+        loop {
+            let start = system_table
+                .boot_services()
+                .allocate_pages(AllocateType::AnyPages, PAGE_TABLE_MEMORY, count)
+                .ok()? as usize;
+            let end = start + count * PAGE_SIZE;
+            // Frames the firmware shouldn't have handed out stay allocated, so that
+            // it doesn't return them again.
+            let mut excluded = self.low_reserved.iter().chain(&self.frame_buffer);
+            if !excluded.any(|range| range.start < end && start < range.end) {
+                return Some(Frame::containing_address(PhysicalAddress::new_canonical(
+                    start,
+                )));
+            }
+        }
+    }
+
+    /// Allocates `count` contiguous frames from the memory map.
+    ///
+    /// If they don't fit in the rest of the current descriptor, they are taken
+    /// from the next one that is large enough, and the frames skipped are
+    /// reported as [`Bootloader`](MemoryRegionKind::Bootloader) memory. If no
+    /// descriptor is large enough, the allocator is left as it was, so that
+    /// smaller allocations can still succeed.
+    fn next_frames(&mut self, count: usize) -> Option<Frame> {
+        let saved = (self.memory_map.clone(), self.current_descriptor.clone());
+        if let Some(frame) = self.allocate_frames_from_current(count) {
             return Some(frame);
         }
 
@@ -523,11 +916,141 @@ impl FrameAllocator for LegacyFrameAllocator {
             };
             self.current_descriptor = Some(descriptor);
 
-            if let Some(frame) = self.allocate_frame_from_current() {
+            if let Some(frame) = self.allocate_frames_from_current(count) {
                 return Some(frame);
             }
         }
 
+        (self.memory_map, self.current_descriptor) = saved;
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(start: usize, end: usize, kind: MemoryRegionKind) -> MemoryRegion {
+        MemoryRegion {
+            start,
+            len: end - start,
+            kind,
+            attributes: MemoryAttributes::empty(),
+        }
+    }
+
+    fn carve(
+        region: MemoryRegion,
+        frame_buffer: Option<Range<usize>>,
+        low_reserved: &[Range<usize>],
+    ) -> Vec<MemoryRegion> {
+        let mut regions = Vec::new();
+        carve_region(region, frame_buffer.as_ref(), low_reserved, &mut |region| {
+            regions.push(region)
+        });
+        regions
+    }
+
+    #[test]
+    fn carve_region_splits_around_frame_buffer() {
+        use MemoryRegionKind::Usable;
+        assert_eq!(
+            carve(region(0x1000, 0x9000, Usable), Some(0x3000..0x5000), &[]),
+            [
+                region(0x1000, 0x3000, Usable),
+                region(0x5000, 0x9000, Usable)
+            ]
+        );
+        assert_eq!(
+            carve(region(0x1000, 0x9000, Usable), Some(0..0x4000), &[]),
+            [region(0x4000, 0x9000, Usable)]
+        );
+        assert_eq!(
+            carve(region(0x1000, 0x9000, Usable), Some(0..0x10000), &[]),
+            []
+        );
+        assert_eq!(
+            carve(region(0x1000, 0x9000, Usable), Some(0x9000..0xa000), &[]),
+            [region(0x1000, 0x9000, Usable)]
+        );
+    }
+
+    #[test]
+    fn carve_region_marks_low_reserved() {
+        use MemoryRegionKind::{Bootloader, Reserved, Usable};
+        let low_reserved = [0..0x2000, LEGACY_HOLE];
+        assert_eq!(
+            carve(region(0x1000, 0x20_0000, Usable), None, &low_reserved),
+            [
+                region(0x1000, 0x2000, Reserved),
+                region(0x2000, 0xa_0000, Usable),
+                region(0xa_0000, 0x10_0000, Reserved),
+                region(0x10_0000, 0x20_0000, Usable),
+            ]
+        );
+        assert_eq!(
+            carve(region(0xb_0000, 0xc_0000, Bootloader), None, &low_reserved),
+            [region(0xb_0000, 0xc_0000, Reserved)]
+        );
+    }
+
+    #[test]
+    fn carve_region_keeps_firmware_kinds() {
+        let acpi = MemoryRegionKind::UnknownUefi(MemoryType::ACPI_RECLAIM.0);
+        assert_eq!(
+            carve(region(0, 0x20_0000, acpi), None, &[0..0x1000, LEGACY_HOLE]),
+            [region(0, 0x20_0000, acpi)]
+        );
+    }
+
+    #[test]
+    fn carve_region_combines_frame_buffer_and_low_reserved() {
+        use MemoryRegionKind::{Reserved, Usable};
+        assert_eq!(
+            carve(
+                region(0, 0x10_0000, Usable),
+                Some(0x8_0000..0xc_0000),
+                &[LEGACY_HOLE, 0..0]
+            ),
+            [
+                region(0, 0x8_0000, Usable),
+                region(0xc_0000, 0x10_0000, Reserved),
+            ]
+        );
+    }
+
+    #[test]
+    fn free_memory_kind_only_demotes_for_hazards() {
+        use MemoryRegionKind::{Persistent, Reserved, ReservedForSpecificPurpose, Usable};
+        for (attributes, kind) in [
+            // Write-back, runtime.
+            (0x8000_0000_0000_0008, Usable),
+            // More reliable, CPU crypto and hot-pluggable.
+            (0x1_0000 | 0x8_0000 | 0x10_0000, Usable),
+            // ISA-specific, with an ISA mask.
+            (0x4001_0000_0000_0000, Usable),
+            (NON_VOLATILE_ATTRIBUTE, Persistent),
+            (
+                SPECIFIC_PURPOSE_ATTRIBUTE | 0x10_0000,
+                ReservedForSpecificPurpose,
+            ),
+            // Bits with no meaning yet.
+            (0x20_0000, Reserved),
+            (0x2000_0000_0000_0000, Reserved),
+        ] {
+            assert_eq!(free_memory_kind(attributes), kind, "{attributes:#x}");
+        }
+    }
+
+    #[test]
+    fn mark_reserved_skips_disjoint_and_empty_ranges() {
+        use MemoryRegionKind::Usable;
+        let mut regions = Vec::new();
+        mark_reserved(
+            region(0x20_0000, 0x30_0000, Usable),
+            &[0..0x1000, 0..0, LEGACY_HOLE],
+            &mut |region| regions.push(region),
+        );
+        assert_eq!(regions, [region(0x20_0000, 0x30_0000, Usable)]);
+    }
+}
@@ -0,0 +1,204 @@
+//! Protecting the bootloader's own image through the memory attribute
+//! protocol.
+//!
+//! Firmware implementing `EFI_MEMORY_ATTRIBUTE_PROTOCOL` lets images change
+//! the attributes of their memory in the firmware's page tables. The
+//! bootloader uses it to make its code read-only and its data non-executable
+//! while it runs on those page tables, i.e. until it switches to the kernel's.
+
+use crate::{memory::PAGE_SIZE, util::calculate_pages, BootContext};
+use core::slice;
+use log::{debug, info, warn};
+use uefi::{
+    proto::{loaded_image::LoadedImage, Protocol},
+    Guid, Identify, Status,
+};
+
+/// The GUID of the memory attribute protocol.
+const MEMORY_ATTRIBUTE_GUID: Guid =
+    Guid::from_values(0xf456_0cf6, 0x40ec, 0x4b4a, 0xa192, 0xbf1d_57d0_b189);
+
+/// The memory attribute for memory that can't be executed.
+const EFI_MEMORY_XP: u64 = 0x4000;
+/// The memory attribute for memory that can't be written to.
+const EFI_MEMORY_RO: u64 = 0x2_0000;
+
+/// The offset of the offset of the PE header in the DOS header.
+const PE_HEADER_OFFSET_OFFSET: usize = 0x3c;
+/// The size of the PE signature and COFF header.
+const COFF_HEADER_END: usize = 24;
+/// The size of a section header.
+const SECTION_HEADER_SIZE: usize = 40;
+/// The section characteristic for executable sections.
+const IMAGE_SCN_MEM_EXECUTE: u32 = 0x2000_0000;
+/// The section characteristic for writable sections.
+const IMAGE_SCN_MEM_WRITE: u32 = 0x8000_0000;
+
+#[repr(C)]
+struct MemoryAttribute {
+    get_memory_attributes: unsafe extern "efiapi" fn(
+        this: *const MemoryAttribute,
+        base_address: u64,
+        length: u64,
+        attributes: *mut u64,
+    ) -> Status,
+    set_memory_attributes: unsafe extern "efiapi" fn(
+        this: *const MemoryAttribute,
+        base_address: u64,
+        length: u64,
+        attributes: u64,
+    ) -> Status,
+    clear_memory_attributes: unsafe extern "efiapi" fn(
+        this: *const MemoryAttribute,
+        base_address: u64,
+        length: u64,
+        attributes: u64,
+    ) -> Status,
+}
+
+// SAFETY: The GUID is the one of the protocol the struct describes.
+unsafe impl Identify for MemoryAttribute {
+    const GUID: Guid = MEMORY_ATTRIBUTE_GUID;
+}
+
+impl Protocol for MemoryAttribute {}
+
+/// A section of the bootloader's image.
+struct Section {
+    address: u64,
+    size: u64,
+    characteristics: u32,
+}
+
+impl BootContext {
+    /// Makes the bootloader's code read-only and its data non-executable, if
+    /// the firmware implements the memory attribute protocol.
+    pub(crate) fn protect_loader_image(&self) {
+        let boot_services = self.system_table.boot_services();
+        let protocol = boot_services
+            .get_handle_for_protocol::<MemoryAttribute>()
+            .and_then(|handle| boot_services.open_protocol_exclusive::<MemoryAttribute>(handle));
+        let protocol = match protocol {
+            Ok(protocol) => protocol,
+            Err(_) => {
+                info!("memory attribute protocol not found, not protecting the bootloader image");
+                return;
+            }
+        };
+
+        let loaded_image = boot_services
+            .open_protocol_exclusive::<LoadedImage>(self.image_handle)
+            .expect("failed to open loaded image protocol");
+        let (image_base, image_size) = loaded_image.info();
+        // SAFETY: The firmware loaded the whole image there, and it is never freed.
+        let image = unsafe {
+            slice::from_raw_parts(
+                image_base.cast::<u8>(),
+                usize::try_from(image_size).expect("image size doesn't fit in usize"),
+            )
+        };
+
+        let Some(sections) = sections(image) else {
+            warn!("bootloader image has an unexpected PE layout, not protecting it");
+            return;
+        };
+
+        let this: *const MemoryAttribute = &*protocol;
+        for section in sections {
+            // Sections such as `.reloc` may be empty once loaded.
+            if section.size == 0 {
+                continue;
+            }
+            let address = image_base as u64 + section.address;
+            // Attributes apply to whole pages, and the next section may start in the
+            // page the section ends in.
+            if address % PAGE_SIZE as u64 != 0 {
+                warn!("bootloader section at {address:#x} isn't page-aligned, not protecting it");
+                continue;
+            }
+            let size = usize::try_from(section.size).expect("section size doesn't fit in usize");
+            let length = (calculate_pages(size) * PAGE_SIZE) as u64;
+
+            // Each section gets exactly the permissions it asks for, whatever the
+            // firmware set when loading the image.
+            let (mut set, mut clear) = (0, 0);
+            if section.characteristics & IMAGE_SCN_MEM_EXECUTE == 0 {
+                set |= EFI_MEMORY_XP;
+            } else {
+                clear |= EFI_MEMORY_XP;
+            }
+            if section.characteristics & IMAGE_SCN_MEM_WRITE == 0 {
+                set |= EFI_MEMORY_RO;
+            } else {
+                clear |= EFI_MEMORY_RO;
+            }
+
+            // The protocol rejects empty attribute masks.
+            if clear != 0 {
+                // SAFETY: The range is part of the bootloader's image, and the
+                // attributes match how the image uses it.
+                let status =
+                    unsafe { (protocol.clear_memory_attributes)(this, address, length, clear) };
+                if !status.is_success() {
+                    warn!(
+                        "failed to clear memory attributes {clear:#x} on bootloader section at \
+                         {address:#x}: {status:?}"
+                    );
+                    continue;
+                }
+            }
+            if set != 0 {
+                // SAFETY: Same as above.
+                let status =
+                    unsafe { (protocol.set_memory_attributes)(this, address, length, set) };
+                if !status.is_success() {
+                    warn!(
+                        "failed to set memory attributes {set:#x} on bootloader section at \
+                         {address:#x}: {status:?}"
+                    );
+                    continue;
+                }
+            }
+            debug!(
+                "protected bootloader section at {address:#x}..{:#x}: set {set:#x}, cleared \
+                 {clear:#x}",
+                address + length
+            );
+        }
+        info!("protected the bootloader image");
+    }
+}
+
+/// Returns the sections of a loaded PE/COFF image, or `None` if its headers
+/// aren't where they are expected to be.
+fn sections(image: &[u8]) -> Option<impl Iterator<Item = Section> + '_> {
+    let read_u16 = |offset: usize| {
+        Some(u16::from_le_bytes(
+            image.get(offset..offset + 2)?.try_into().ok()?,
+        ))
+    };
+    let read_u32 = |offset: usize| {
+        Some(u32::from_le_bytes(
+            image.get(offset..offset + 4)?.try_into().ok()?,
+        ))
+    };
+
+    let pe_header = read_u32(PE_HEADER_OFFSET_OFFSET)? as usize;
+    if image.get(pe_header..pe_header + 4)? != b"PE\0\0" {
+        return None;
+    }
+    let section_count = read_u16(pe_header + 6)? as usize;
+    let optional_header_size = read_u16(pe_header + 20)? as usize;
+    let section_headers = pe_header + COFF_HEADER_END + optional_header_size;
+    // Check all headers up front, so that the iterator can't come up short.
+    image.get(section_headers..section_headers + section_count * SECTION_HEADER_SIZE)?;
+
+    Some((0..section_count).filter_map(move |index| {
+        let header = section_headers + index * SECTION_HEADER_SIZE;
+        Some(Section {
+            address: read_u32(header + 12)?.into(),
+            size: read_u32(header + 8)?.into(),
+            characteristics: read_u32(header + 36)?,
+        })
+    }))
+}
@@ -1,8 +1,9 @@
-use crate::{memory::PAGE_SIZE, util::calculate_pages, BootContext};
+use crate::{display::Display, fs, memory::PAGE_SIZE, util::calculate_pages, BootContext};
 use core::mem::MaybeUninit;
+use log::{info, warn};
 use uefi::{
     prelude::cstr16,
-    proto::media::file::{File, FileAttribute, FileMode},
+    proto::media::file::{File, FileAttribute, FileMode, RegularFile},
     table::boot::MemoryType,
 };
 use uefi_bootloader_api::Module;
@@ -10,13 +11,20 @@ use uefi_bootloader_api::Module;
 const MODULES_MEMORY: MemoryType = MemoryType::custom(0x8000_0000);
 
 impl BootContext {
-    pub(crate) fn load_modules(&self) -> &'static mut [Module] {
-        let mut root = self
-            .open_file_system_root()
-            .expect("failed to open file system root");
+    /// Loads the modules, returning them along with the physical address their
+    /// offsets are relative to.
+    pub(crate) fn load_modules(&self) -> (&'static mut [Module], usize) {
+        let Some(mut root) = self.open_file_system_root() else {
+            // An embedded kernel can be booted without a file system.
+            assert!(
+                cfg!(feature = "embedded-kernel"),
+                "failed to open file system root"
+            );
+            info!("no file system found, loading no modules");
+            return (&mut [], 0);
+        };
 
-        let mut dir = root
-            .open(cstr16!("modules"), FileMode::Read, FileAttribute::empty())
+        let mut dir = fs::open(&mut root, cstr16!("modules"))
             // TODO: Don't fail if modules don't exist.
             .expect("failed to open modules directory")
             .into_directory()
@@ -24,7 +32,8 @@ impl BootContext {
 
         let mut num_modules = 0;
         let mut num_pages = 0;
-        let mut buf = [0; 500];
+        let mut max_align = PAGE_SIZE;
+        let mut buf = [0; fs::FILE_INFO_BUFFER_SIZE];
 
         while let Some(info) = dir
             .read_entry(&mut buf)
@@ -38,24 +47,60 @@ impl BootContext {
             }
         }
 
+        for (path, align) in self.config.module_entries() {
+            let mut file = open_config_module(self, path);
+            num_modules += 1;
+            num_pages = num_pages.next_multiple_of(align / PAGE_SIZE);
+            num_pages +=
+                calculate_pages(fs::file_size(&mut file).expect("failed to get module size"));
+            max_align = max_align.max(align);
+        }
+
+        for (path, align) in self.config.optional_module_entries() {
+            match open_optional_module(self, path) {
+                Some((_, len)) => {
+                    num_modules += 1;
+                    num_pages = num_pages.next_multiple_of(align / PAGE_SIZE);
+                    num_pages += calculate_pages(len);
+                    max_align = max_align.max(align);
+                }
+                None => warn!("skipping missing optional module: {path}"),
+            }
+        }
+
+        let max_modules = self.config.max_modules;
+        assert!(
+            num_modules <= max_modules,
+            "found {num_modules} modules, but at most {max_modules} can be loaded; raise \
+             `max_modules` or remove modules"
+        );
+
         // This slice is copied into another slice in the bootloader, so this slice can
         // be overwritten by the kernel.
-        let modules = self.allocate_slice(num_modules, MemoryType::LOADER_DATA);
-        let raw_bytes = self.allocate_byte_slice(num_pages * PAGE_SIZE, MODULES_MEMORY);
+        let modules = self.allocate_small_slice(num_modules);
+        // The offsets are only aligned if the start of the modules is aligned to the
+        // largest alignment.
+        let raw_bytes =
+            self.allocate_aligned_byte_slice(num_pages * PAGE_SIZE, max_align, MODULES_MEMORY);
 
         dir.reset_entry_readout()
             .expect("failed to reset modules directory entry readout");
 
         let mut idx = 0;
         let mut num_pages = 0;
+        let display = Display::get();
+        let show_progress = |done| {
+            if let Some(display) = display {
+                display.draw_progress(done, num_modules);
+            }
+        };
+        show_progress(0);
 
         while let Some(info) = dir
             .read_entry(&mut buf)
             .expect("failed to read modules directory entry")
         {
             if !info.attribute().contains(FileAttribute::DIRECTORY) {
-                let name = info.file_name();
-
                 let len = info.file_size() as usize;
                 let mut file = dir
                     .open(info.file_name(), FileMode::Read, FileAttribute::empty())
@@ -63,30 +108,122 @@ impl BootContext {
                     .into_regular_file()
                     .expect("module file was closed or deleted");
 
-                file.read(&mut raw_bytes[(num_pages * 4096)..])
-                    .expect("failed to read module");
-
-                let mut name_buf = [0; 64];
-                let mut name_idx = 0;
-                for c16 in name.iter() {
-                    let c = char::from(*c16);
-                    let s = c.encode_utf8(&mut name_buf[name_idx..(name_idx + 4)]);
-                    name_idx += s.len();
-                }
+                // The version suffix of ISO9660 names isn't part of the module name.
+                let name = char::decode_utf16(
+                    fs::strip_version(info.file_name().to_u16_slice())
+                        .iter()
+                        .copied(),
+                )
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER));
 
-                modules[idx].write(Module {
-                    name: name_buf,
-                    offset: num_pages * 4096,
-                    len,
-                });
+                modules
+                    .get_mut(idx)
+                    .expect("modules directory changed while loading modules")
+                    .write(
+                        load_module(self, &mut file, name, raw_bytes, num_pages * PAGE_SIZE, len)
+                            .expect("failed to read module"),
+                    );
 
                 idx += 1;
                 num_pages += calculate_pages(len);
+                show_progress(idx);
             }
         }
 
-        assert_eq!(idx, modules.len());
-        // SAFETY: We just initialised the slice and checked that it's the same length.
-        unsafe { MaybeUninit::slice_assume_init_mut(modules) }
+        for (path, align) in self.config.module_entries() {
+            let mut file = open_config_module(self, path);
+            let len = fs::file_size(&mut file).expect("failed to get module size");
+            num_pages = num_pages.next_multiple_of(align / PAGE_SIZE);
+            let name = module_name(path);
+
+            modules[idx].write(
+                load_module(self, &mut file, name, raw_bytes, num_pages * PAGE_SIZE, len)
+                    .expect("failed to read module"),
+            );
+
+            idx += 1;
+            num_pages += calculate_pages(len);
+            show_progress(idx);
+        }
+
+        for (path, align) in self.config.optional_module_entries() {
+            // Missing optional modules were already reported when counting them.
+            let Some((mut file, len)) = open_optional_module(self, path) else {
+                continue;
+            };
+            num_pages = num_pages.next_multiple_of(align / PAGE_SIZE);
+            let Some(slot) = modules.get_mut(idx) else {
+                warn!("skipping optional module that appeared while loading modules: {path}");
+                continue;
+            };
+            let name = module_name(path);
+
+            match load_module(self, &mut file, name, raw_bytes, num_pages * PAGE_SIZE, len) {
+                Some(module) => {
+                    slot.write(module);
+                    idx += 1;
+                    num_pages += calculate_pages(len);
+                    show_progress(idx);
+                }
+                None => warn!("skipping unreadable optional module: {path}"),
+            }
+        }
+
+        // Optional modules that couldn't be read aren't included.
+        let modules = &mut modules[..idx];
+        // SAFETY: We just initialised the slice up to `idx`.
+        let modules = unsafe { MaybeUninit::slice_assume_init_mut(modules) };
+        (modules, raw_bytes.as_ptr() as usize)
     }
 }
+
+fn open_config_module(context: &BootContext, path: &'static str) -> RegularFile {
+    context
+        .open_path(path)
+        .expect("failed to open module")
+        .into_regular_file()
+        .expect("module file was closed or deleted")
+}
+
+/// Opens the optional module at `path`, returning it along with its size.
+fn open_optional_module(context: &BootContext, path: &'static str) -> Option<(RegularFile, usize)> {
+    let mut file = context.open_path(path)?.into_regular_file()?;
+    let len = fs::file_size(&mut file)?;
+    Some((file, len))
+}
+
+/// Returns the name of the module at the configured `path`, i.e. its file
+/// name.
+fn module_name(path: &str) -> core::str::Chars<'_> {
+    let (_, path) = fs::split_volume(path);
+    path.rsplit('\\').next().unwrap_or(path).chars()
+}
+
+/// Reads `file` into `raw_bytes` at `offset`, returning the module describing
+/// it.
+fn load_module<I>(
+    context: &BootContext,
+    file: &mut RegularFile,
+    name: I,
+    raw_bytes: &mut [u8],
+    offset: usize,
+    len: usize,
+) -> Option<Module>
+where
+    I: Iterator<Item = char>,
+{
+    context.read_retrying(file, &mut raw_bytes[offset..]).ok()?;
+
+    let mut name_buf = [0; 64];
+    let mut name_idx = 0;
+    for c in name {
+        let s = c.encode_utf8(&mut name_buf[name_idx..(name_idx + 4)]);
+        name_idx += s.len();
+    }
+
+    Some(Module {
+        name: name_buf,
+        offset,
+        len,
+    })
+}
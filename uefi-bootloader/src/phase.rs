@@ -0,0 +1,84 @@
+//! The boot phases, and the output sinks that are valid in each of them.
+//!
+//! The panic handler can run at any point, so it consults the current phase
+//! rather than assuming the firmware console or the framebuffer are usable.
+//!
+//! 1. [`Phase::Console`]: the firmware console and the framebuffer.
+//! 2. [`Phase::BootServices`]: the framebuffer. The system table has been moved
+//!    into the [`BootContext`](crate::BootContext) and can no longer be reached
+//!    from the panic handler, except to wait for a key press if the bootloader
+//!    is configured to pause on errors.
+//! 3. [`Phase::Exited`]: boot services have been exited. Only log messages are
+//!    written to the framebuffer, and nothing else draws to it.
+//!
+//! The boot log is valid in every phase.
+
+use core::ptr::NonNull;
+use spin::Mutex;
+use uefi::table::{Boot, SystemTable};
+
+static PHASE: Mutex<Phase> = Mutex::new(Phase::BootServices);
+
+/// A boot phase.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Phase {
+    /// The system table is still owned by `main`, so its console can be
+    /// written to through the pointer.
+    Console(NonNull<SystemTable<Boot>>),
+    /// Boot services are available, but the system table isn't reachable.
+    BootServices,
+    /// Boot services have been exited.
+    Exited,
+}
+
+// SAFETY: The bootloader is single-threaded.
+unsafe impl Send for Phase {}
+
+impl Phase {
+    /// Returns whether the framebuffer may be drawn to outside of the logger.
+    pub(crate) fn can_draw(self) -> bool {
+        !matches!(self, Self::Exited)
+    }
+}
+
+/// Returns the current phase.
+pub(crate) fn current() -> Phase {
+    *PHASE.lock()
+}
+
+/// Returns the current phase, or `None` if it is being changed, e.g. because
+/// this was called from an event notification function.
+pub(crate) fn try_current() -> Option<Phase> {
+    PHASE.try_lock().map(|phase| *phase)
+}
+
+/// Returns the current phase from the panic handler.
+///
+/// # Safety
+///
+/// The caller must ensure no other thread could simultaneously access the
+/// phase.
+pub(crate) unsafe fn current_from_panic() -> Phase {
+    // SAFETY: Guaranteed by caller.
+    unsafe { PHASE.force_unlock() };
+    current()
+}
+
+/// Enters the [`Console`](Phase::Console) phase.
+///
+/// # Safety
+///
+/// `system_table` must stay valid until [`enter_boot_services`] is called.
+pub(crate) unsafe fn enter_console(system_table: NonNull<SystemTable<Boot>>) {
+    *PHASE.lock() = Phase::Console(system_table);
+}
+
+/// Enters the [`BootServices`](Phase::BootServices) phase.
+pub(crate) fn enter_boot_services() {
+    *PHASE.lock() = Phase::BootServices;
+}
+
+/// Enters the [`Exited`](Phase::Exited) phase.
+pub(crate) fn enter_exited() {
+    *PHASE.lock() = Phase::Exited;
+}
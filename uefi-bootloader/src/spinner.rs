@@ -0,0 +1,93 @@
+//! An animated spinner in the top right corner of the framebuffer, showing
+//! that the bootloader isn't hung during long operations such as loading
+//! modules from slow media.
+//!
+//! The spinner is advanced by a periodic UEFI timer event, so it keeps turning
+//! while the bootloader waits for the firmware, as long as the firmware doesn't
+//! raise the task priority level above `TPL_CALLBACK` while it works. As the
+//! event can fire while the logger is locked, a tick that can't get the lock
+//! is skipped instead of waiting for it.
+
+use crate::{display::Display, BootContext};
+use core::{
+    ffi::c_void,
+    ptr::NonNull,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use log::warn;
+use uefi::table::boot::{Event, EventType, TimerTrigger, Tpl};
+
+/// The glyphs the spinner cycles through.
+const FRAMES: [char; 4] = ['|', '/', '-', '\\'];
+
+/// The time between two frames, in units of 100ns.
+const FRAME_INTERVAL: u64 = 1_000_000;
+
+/// The index of the next frame in [`FRAMES`].
+static NEXT_FRAME: AtomicUsize = AtomicUsize::new(0);
+
+impl BootContext {
+    /// Starts the spinner, if enabled by the `spinner` config key and there is
+    /// a framebuffer.
+    pub(crate) fn start_spinner(&mut self) {
+        if !self.config.spinner || self.spinner.is_some() {
+            return;
+        }
+        let Some(display) = Display::get() else {
+            return;
+        };
+
+        let boot_services = self.system_table.boot_services();
+        // SAFETY: The notification function only draws to the framebuffer while
+        // boot services haven't been exited, and the event is closed before that.
+        let event = unsafe {
+            boot_services.create_event(
+                EventType::TIMER | EventType::NOTIFY_SIGNAL,
+                Tpl::CALLBACK,
+                Some(tick),
+                None,
+            )
+        };
+        let event = match event {
+            Ok(event) => event,
+            Err(error) => {
+                warn!("failed to create spinner timer: {:?}", error.status());
+                return;
+            }
+        };
+        if let Err(error) = boot_services.set_timer(&event, TimerTrigger::Periodic(FRAME_INTERVAL))
+        {
+            warn!("failed to start spinner timer: {:?}", error.status());
+            let _ = boot_services.close_event(event);
+            return;
+        }
+
+        display.reserve_spinner_column(true);
+        self.spinner = Some(event);
+    }
+
+    /// Stops the spinner, if it is running, and erases it.
+    pub(crate) fn stop_spinner(&mut self) {
+        let Some(event) = self.spinner.take() else {
+            return;
+        };
+        // Cancelling the timer makes sure the event doesn't fire again even if
+        // closing it fails.
+        let boot_services = self.system_table.boot_services();
+        let _ = boot_services.set_timer(&event, TimerTrigger::Cancel);
+        let _ = boot_services.close_event(event);
+
+        if let Some(display) = Display::get() {
+            display.draw_spinner(' ');
+            display.reserve_spinner_column(false);
+        }
+    }
+}
+
+/// Draws the next frame of the spinner, unless the framebuffer is busy.
+unsafe extern "efiapi" fn tick(_event: Event, _context: Option<NonNull<c_void>>) {
+    let frame = NEXT_FRAME.load(Ordering::Relaxed);
+    if Display::try_draw_spinner(FRAMES[frame]) {
+        NEXT_FRAME.store((frame + 1) % FRAMES.len(), Ordering::Relaxed);
+    }
+}
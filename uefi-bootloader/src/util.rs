@@ -1,3 +1,190 @@
+use core::fmt;
+use log::info;
+use uefi::{
+    proto::device_path::{
+        text::{AllowShortcuts, DevicePathToText, DisplayOnly},
+        DevicePath,
+    },
+    table::boot::{BootServices, SearchType},
+    CStr16, Guid, Handle,
+};
+
 pub(crate) fn calculate_pages(bytes: usize) -> usize {
-    ((bytes - 1) / 4096) + 1
+    (bytes + 4095) / 4096
+}
+
+/// Returns the first handle supporting the protocol `guid` whose device path,
+/// in its text form, contains `needle`.
+///
+/// `what` names the protocol in the log message reporting the match.
+pub(crate) fn find_handle_by_device_path(
+    boot_services: &BootServices,
+    guid: &Guid,
+    what: &str,
+    needle: &str,
+) -> Option<Handle> {
+    let to_text_handle = boot_services
+        .get_handle_for_protocol::<DevicePathToText>()
+        .ok()?;
+    let to_text = boot_services
+        .open_protocol_exclusive::<DevicePathToText>(to_text_handle)
+        .ok()?;
+    let handles = boot_services
+        .locate_handle_buffer(SearchType::ByProtocol(guid))
+        .ok()?;
+
+    let mut buf = [0; 256];
+    let needle = CStr16::from_str_with_buf(needle, &mut buf)
+        .unwrap_or_else(|_| panic!("invalid {what} device path in config"))
+        .to_u16_slice();
+
+    handles.handles().iter().copied().find(|handle| {
+        // Handles without a device path, e.g. the console splitter's GOP, can't
+        // match.
+        let Ok(path) = boot_services.open_protocol_exclusive::<DevicePath>(*handle) else {
+            return false;
+        };
+        let Ok(text) = to_text.convert_device_path_to_text(
+            boot_services,
+            &path,
+            DisplayOnly(false),
+            AllowShortcuts(false),
+        ) else {
+            return false;
+        };
+        let text: &CStr16 = &text;
+        let matches = needle.is_empty()
+            || text
+                .to_u16_slice()
+                .windows(needle.len())
+                .any(|window| window == needle);
+        if matches {
+            info!("using {what} at {text}");
+        }
+        matches
+    })
+}
+
+/// The reason an encoded value couldn't be decoded.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum DecodeError {
+    /// The byte at the given offset isn't valid at its position.
+    InvalidCharacter(usize),
+    /// No valid encoding has the length of the input.
+    InvalidLength,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidCharacter(offset) => write!(f, "invalid character at offset {offset}"),
+            Self::InvalidLength => write!(f, "invalid length"),
+        }
+    }
+}
+
+/// Decodes the hexadecimal string `input`, calling `f` with each byte.
+pub(crate) fn decode_hex<F>(input: &str, mut f: F) -> Result<(), DecodeError>
+where
+    F: FnMut(u8),
+{
+    let input = input.as_bytes();
+    if input.len() % 2 != 0 {
+        return Err(DecodeError::InvalidLength);
+    }
+
+    for (i, pair) in input.chunks_exact(2).enumerate() {
+        let digit = |j: usize| {
+            char::from(pair[j])
+                .to_digit(16)
+                .ok_or(DecodeError::InvalidCharacter(2 * i + j))
+        };
+        f((digit(0)? << 4 | digit(1)?) as u8);
+    }
+    Ok(())
+}
+
+/// Decodes the padded, standard base64 string `input`, calling `f` with each
+/// byte.
+pub(crate) fn decode_base64<F>(input: &str, mut f: F) -> Result<(), DecodeError>
+where
+    F: FnMut(u8),
+{
+    let input = input.as_bytes();
+    if input.len() % 4 != 0 {
+        return Err(DecodeError::InvalidLength);
+    }
+
+    let chunk_count = input.len() / 4;
+    for (i, chunk) in input.chunks_exact(4).enumerate() {
+        let mut value = 0;
+        let mut padding = 0;
+        for (j, &c) in chunk.iter().enumerate() {
+            let sextet = match c {
+                b'A'..=b'Z' if padding == 0 => c - b'A',
+                b'a'..=b'z' if padding == 0 => c - b'a' + 26,
+                b'0'..=b'9' if padding == 0 => c - b'0' + 52,
+                b'+' if padding == 0 => 62,
+                b'/' if padding == 0 => 63,
+                // At most the last two characters of the input are padding.
+                b'=' if i + 1 == chunk_count && j >= 2 => {
+                    padding += 1;
+                    0
+                }
+                _ => return Err(DecodeError::InvalidCharacter(4 * i + j)),
+            };
+            value = value << 6 | u32::from(sextet);
+        }
+
+        let [_, bytes @ ..] = value.to_be_bytes();
+        for byte in &bytes[..3 - padding] {
+            f(*byte);
+        }
+    }
+    Ok(())
+}
+
+/// An incremental CRC-32 (IEEE 802.3) checksum.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Crc32(u32);
+
+impl Crc32 {
+    /// The reversed IEEE 802.3 polynomial.
+    const POLYNOMIAL: u32 = 0xedb8_8320;
+    const TABLE: [u32; 256] = Self::table();
+
+    const fn table() -> [u32; 256] {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            #[allow(clippy::cast_possible_truncation)]
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 == 0 {
+                    crc >> 1
+                } else {
+                    (crc >> 1) ^ Self::POLYNOMIAL
+                };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    }
+
+    pub(crate) const fn new() -> Self {
+        Self(u32::MAX)
+    }
+
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = Self::TABLE[((self.0 ^ u32::from(*byte)) & 0xff) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    pub(crate) const fn finish(self) -> u32 {
+        !self.0
+    }
 }